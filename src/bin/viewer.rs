@@ -1,113 +1,221 @@
 use eframe::egui;
-use chrono::{NaiveDateTime, Local, TimeZone};
-use std::path::PathBuf;
+use chrono::{Local, TimeZone};
+use std::path::{Path, PathBuf};
 use walkdir::WalkDir;
+use rodio::buffer::SamplesBuffer;
 use rodio::{Decoder, OutputStream, Sink};
 use std::fs::File;
 use std::io::BufReader;
-use hound;
+use std::sync::mpsc;
+use ranger_recorder_rs::coverage::{read_sessions, Outage, Session};
+use ranger_recorder_rs::levels::{read_levels, LevelSample};
+use ranger_recorder_rs::naming::{earliest_local, ClipFormat, ClipName};
+use ranger_recorder_rs::ogg_opus;
+use ranger_recorder_rs::sidecar::ClipMetadata;
+use ranger_recorder_rs::vocalization::{self, Breakdown, Vocalization};
+use ranger_recorder_rs::{from_dbfs, to_dbfs};
 
 #[derive(Clone)]
 struct Recording {
     timestamp: chrono::DateTime<Local>,
+    // Start of the barking episode this clip is part of. Long episodes are split over several parts.
+    episode: chrono::DateTime<Local>,
+    collision: u32,  // tells apart episodes that started in the same millisecond
+    part: u32,
+    source: String,  // microphone label when several are recorded, empty otherwise
+    short: bool,  // shorter than the recorder's min_event_secs
+    non_dog: bool,  // tagged by the recorder's classifier as not a dog
     path: PathBuf,
     duration: f32,  // duration in seconds
     audio_stats: Option<(f32, f32, f32, f32, f32)>, // min, q1, median, q3, max
-    waveform: Vec<f32>,
+    vocalizations: Breakdown,  // seconds of barking, howling and whining in the clip, empty until analyzed
+    metadata: Option<ClipMetadata>,  // from the recorder's .json sidecar, if there is one
 }
 
 struct BarkViewer {
     recordings: Vec<Recording>,
+    sessions: Vec<Session>,  // when the recorder was running, from its session log
+    levels: Vec<(String, Vec<LevelSample>)>,  // continuous level log per source, oldest first
+    sources: Vec<String>,  // distinct source labels, sorted
+    source_filter: Option<String>,  // show only this source, or all of them merged
     timeline_start: chrono::DateTime<Local>,
     timeline_end: chrono::DateTime<Local>,
     current_playback: Option<Sink>,
-    scroll_delta: f32,  // Add scroll tracking
     hovered_timestamp: Option<chrono::DateTime<Local>>,  // Add this field
+    analyzed: Option<mpsc::Receiver<(PathBuf, Breakdown)>>,  // breakdowns from the analysis thread, until it's done
 }
 
-impl Recording {
-    fn analyze_audio(&self) -> Option<(f32, f32, f32, f32, f32)> { // min, 25%, median, 75%, max
-        if let Ok(reader) = hound::WavReader::open(&self.path) {
-            let samples: Vec<f32> = reader.into_samples()
-                .filter_map(|s| s.ok())
-                .map(|s: i16| s as f32 / i16::MAX as f32)
-                .map(|s| s.abs())
+/// A clip's audio, decoded.
+struct DecodedClip {
+    /// Interleaved, in -1.0..=1.0
+    samples: Vec<f32>,
+    channels: usize,
+    sample_rate: u32,
+}
+
+impl DecodedClip {
+    fn duration(&self) -> f32 {
+        self.samples.len() as f32 / self.channels as f32 / self.sample_rate as f32
+    }
+}
+
+/// Decode every sample of a clip, whatever format the recorder wrote it in.
+fn read_clip(path: &Path, format: ClipFormat) -> Option<DecodedClip> {
+    match format {
+        ClipFormat::Wav => {
+            let reader = hound::WavReader::open(path).ok()?;
+            let spec = reader.spec();
+            Some(DecodedClip {
+                samples: read_wav_samples(reader),
+                channels: spec.channels as usize,
+                sample_rate: spec.sample_rate,
+            })
+        }
+        ClipFormat::Flac => {
+            let mut reader = claxon::FlacReader::open(path).ok()?;
+            let info = reader.streaminfo();
+            let scale = (1i64 << (info.bits_per_sample - 1)) as f32;
+            // Stop at the first bad frame, a clip recovered after a crash can end in half of one
+            let samples: Vec<f32> = reader.samples()
+                .map_while(|s| s.ok())
+                .map(|s| s as f32 / scale)
                 .collect();
-            
-            if !samples.is_empty() {
-                let mut sorted = samples.clone();
-                sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
-                
-                let len = sorted.len();
-                let min = sorted[0];
-                let q1 = sorted[len / 4];
-                let median = sorted[len / 2];
-                let q3 = sorted[3 * len / 4];
-                let max = sorted[len - 1];
-                
-                return Some((min, q1, median, q3, max));
-            }
+            Some(DecodedClip { samples, channels: info.channels as usize, sample_rate: info.sample_rate })
+        }
+        ClipFormat::Opus => {
+            let audio = ogg_opus::read(path).ok()?;
+            Some(DecodedClip { samples: audio.samples, channels: audio.channels as usize, sample_rate: audio.sample_rate })
+        }
+    }
+}
+
+fn read_wav_samples<R: std::io::Read>(reader: hound::WavReader<R>) -> Vec<f32> {
+    let spec = reader.spec();
+    match spec.sample_format {
+        hound::SampleFormat::Float => reader.into_samples::<f32>()
+            .filter_map(|s| s.ok())
+            .collect(),
+        hound::SampleFormat::Int => {
+            let scale = (1i64 << (spec.bits_per_sample - 1)) as f32;
+            reader.into_samples::<i32>()
+                .filter_map(|s| s.ok())
+                .map(|s| s as f32 / scale)
+                .collect()
         }
-        None
+    }
+}
+
+/// Box-plot stats of a clip's absolute amplitude: min, 25%, median, 75%, max
+fn analyze_audio(samples: Vec<f32>) -> Option<(f32, f32, f32, f32, f32)> {
+    let mut sorted: Vec<f32> = samples
+        .into_iter()
+        .map(|s| s.abs())
+        .collect();
+    if sorted.is_empty() {
+        return None;
+    }
+    sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+
+    let len = sorted.len();
+    let min = sorted[0];
+    let q1 = sorted[len / 4];
+    let median = sorted[len / 2];
+    let q3 = sorted[3 * len / 4];
+    let max = sorted[len - 1];
+
+    Some((min, q1, median, q3, max))
+}
+
+/// Colour each kind of vocalization is marked in, on the timeline and in the list.
+fn vocalization_color(kind: Vocalization) -> egui::Color32 {
+    match kind {
+        Vocalization::Bark => egui::Color32::from_rgb(255, 210, 0),  // Yellow
+        Vocalization::Howl => egui::Color32::from_rgb(80, 150, 255),  // Blue
+        Vocalization::Whine => egui::Color32::from_rgb(220, 90, 220),  // Magenta
+        Vocalization::Other => egui::Color32::from_gray(150),
     }
 }
 
 impl BarkViewer {
-    fn new() -> Self {
+    fn new(barks_dir: &str) -> Self {
         let mut recordings = Vec::new();
         
         // Scan the barks directory
-        for entry in WalkDir::new("barks")
+        for entry in WalkDir::new(barks_dir)
             .into_iter()
             .filter_map(|e| e.ok())
-            .filter(|e| e.path().extension().map_or(false, |ext| ext == "wav"))
         {
-            if let Some(filename) = entry.path().file_name().and_then(|f| f.to_str()) {
-                if filename.starts_with("bark_") {
-                    if let Ok(reader) = hound::WavReader::open(entry.path()) {
-                        let spec = reader.spec();
-                        let duration = reader.duration() as f32 / spec.sample_rate as f32;
-                        
-                        // Analyze audio data during loading
-                        let audio_stats = {
-                            let samples: Vec<f32> = reader.into_samples()
-                                .filter_map(|s| s.ok())
-                                .map(|s: i16| s as f32 / i16::MAX as f32)
-                                .map(|s| s.abs())
-                                .collect();
-                            
-                            if !samples.is_empty() {
-                                let mut sorted = samples;
-                                sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
-                                
-                                let len = sorted.len();
-                                let min = sorted[0];
-                                let q1 = sorted[len / 4];
-                                let median = sorted[len / 2];
-                                let q3 = sorted[3 * len / 4];
-                                let max = sorted[len - 1];
-                                
-                                Some((min, q1, median, q3, max))
-                            } else {
-                                None
-                            }
-                        };
-                        
-                        if let Ok(timestamp) = NaiveDateTime::parse_from_str(
-                            filename.strip_prefix("bark_").unwrap().strip_suffix(".wav").unwrap(),
-                            "%Y%m%d_%I_%M_%S_%P"
-                        ) {
-                            recordings.push(Recording {
-                                timestamp: Local.from_local_datetime(&timestamp).unwrap(),
-                                path: entry.path().to_owned(),
-                                duration,
-                                audio_stats,
-                                waveform: Vec::new(),
-                            });
-                        }
-                    }
+            let Some(filename) = entry.path().file_name().and_then(|f| f.to_str()) else {
+                continue;
+            };
+            let Some(name) = ClipName::parse(filename) else {
+                continue;
+            };
+            let Some(clip) = read_clip(entry.path(), name.format) else {
+                continue;
+            };
+
+            // Analyze audio data during loading
+            let duration = clip.duration();
+            let audio_stats = analyze_audio(clip.samples);
+
+            // Clips of each source go to their own subdirectory, use that if the sidecar can't say
+            let subdir = entry.path().strip_prefix(barks_dir).ok()
+                .filter(|relative| relative.components().count() > 1)
+                .and_then(|relative| relative.components().next())
+                .map(|dir| dir.as_os_str().to_string_lossy().into_owned())
+                .unwrap_or_default();
+
+            // The sidecar has the precise times, file names are only to the millisecond, or to the
+            // second for the legacy format
+            let metadata = ClipMetadata::read(entry.path());
+            let (timestamp, episode, part, short) = match &metadata {
+                Some(m) => (
+                    m.start.with_timezone(&Local),
+                    m.episode_start.with_timezone(&Local),
+                    m.part,
+                    m.short,
+                ),
+                None => {
+                    let episode = name.start.with_timezone(&Local);
+                    (episode, episode, name.part, name.short)
                 }
+            };
+            recordings.push(Recording {
+                timestamp,
+                episode,
+                collision: name.collision,
+                part,
+                source: metadata.as_ref()
+                    .map(|m| m.source.clone())
+                    .filter(|source| !source.is_empty())
+                    .unwrap_or(subdir),
+                short,
+                non_dog: metadata.as_ref().is_some_and(|m| m.non_dog),
+                path: entry.path().to_owned(),
+                duration,
+                audio_stats,
+                vocalizations: Breakdown::default(),
+                metadata,
+            });
+        }
+
+        // Without a sidecar, parts of an episode are assumed to follow each other without gaps,
+        // so each one starts where the previous one ended
+        recordings.sort_by_key(|r| (r.episode, r.collision, r.part));
+        let mut offset = 0.0;
+        for i in 0..recordings.len() {
+            if i == 0
+                || (recordings[i].episode, recordings[i].collision)
+                    != (recordings[i - 1].episode, recordings[i - 1].collision)
+            {
+                offset = 0.0;
+            }
+            if recordings[i].metadata.is_none() {
+                recordings[i].timestamp = recordings[i].episode
+                    + chrono::Duration::milliseconds((offset * 1000.0) as i64);
             }
+            offset += recordings[i].duration;
         }
 
         // Sort recordings by timestamp
@@ -115,9 +223,8 @@ impl BarkViewer {
 
         // Set timeline range to start at beginning of current day
         let now = Local::now();
-        let today_start = Local.from_local_datetime(
-            &now.date_naive().and_hms_opt(0, 0, 0).unwrap()
-        ).unwrap();
+        // Midnight can be skipped or repeated by a DST change in some zones
+        let today_start = earliest_local(&now.date_naive().and_hms_opt(0, 0, 0).unwrap()).unwrap_or(now);
         
         // Find first recording of today
         let timeline_start = recordings.iter()
@@ -126,16 +233,83 @@ impl BarkViewer {
             .unwrap_or(today_start);
         let timeline_end = now;
 
+        // Each source keeps its level log next to its clips
+        let mut levels = vec![(String::new(), read_levels(Path::new(barks_dir)))];
+        if let Ok(entries) = std::fs::read_dir(barks_dir) {
+            for entry in entries.filter_map(|e| e.ok()).filter(|e| e.path().is_dir()) {
+                let source_levels = read_levels(&entry.path());
+                if !source_levels.is_empty() {
+                    levels.push((entry.file_name().to_string_lossy().into_owned(), source_levels));
+                }
+            }
+        }
+
+        // Breaking clips down into vocalizations takes a while, so it's done in the background,
+        // newest clips first
+        let (sender, analyzed) = mpsc::channel();
+        let paths: Vec<PathBuf> = recordings.iter().rev().map(|r| r.path.clone()).collect();
+        std::thread::spawn(move || {
+            for path in paths {
+                let Some(name) = path.file_name().and_then(|f| f.to_str()).and_then(ClipName::parse) else {
+                    continue;
+                };
+                let Some(clip) = read_clip(&path, name.format) else {
+                    continue;
+                };
+                let breakdown = vocalization::analyze(&clip.samples, clip.channels, clip.sample_rate);
+                // The viewer was closed
+                if sender.send((path, breakdown)).is_err() {
+                    return;
+                }
+            }
+        });
+
+        let mut sources: Vec<String> = recordings.iter().map(|r| r.source.clone()).collect();
+        sources.sort();
+        sources.dedup();
+
         Self {
             recordings,
+            sessions: read_sessions(Path::new(barks_dir)),
+            levels,
+            sources,
+            source_filter: None,
             timeline_start,
             timeline_end,
             current_playback: None,
-            scroll_delta: 0.0,
             hovered_timestamp: None,  // Initialize new field
+            analyzed: Some(analyzed),
         }
     }
 
+    /// Whether `source` passes the source filter.
+    fn shows(&self, source: &str) -> bool {
+        self.source_filter.as_ref().is_none_or(|filter| filter == source)
+    }
+
+    /// Seconds between `from` and `to` the recorder was running, per the session log.
+    fn covered_secs(&self, from: chrono::DateTime<Local>, to: chrono::DateTime<Local>) -> i64 {
+        let overlap = |start: i64, end: i64| (end.min(to.timestamp()) - start.max(from.timestamp())).max(0);
+        self.sessions.iter()
+            .map(|s| {
+                let lost: i64 = s.outages.iter()
+                    .filter(|o| self.is_gap(o))
+                    .map(|o| overlap(o.start.timestamp(), o.end.timestamp()))
+                    .sum();
+                let disarmed: i64 = s.disarmed.iter()
+                    .map(|(start, end)| overlap(start.timestamp(), end.timestamp()))
+                    .sum();
+                overlap(s.start.timestamp(), s.end.timestamp()) - lost - disarmed
+            })
+            .sum()
+    }
+
+    /// Whether an outage left nothing listening in the current view. With several sources merged,
+    /// the others were still recording.
+    fn is_gap(&self, outage: &Outage) -> bool {
+        self.shows(&outage.source) && (self.source_filter.is_some() || self.sources.len() <= 1)
+    }
+
     fn play_audio(&mut self, path: &PathBuf) {
         // Stop any existing playback
         if let Some(sink) = &self.current_playback {
@@ -143,24 +317,71 @@ impl BarkViewer {
         }
 
         // Set up audio playback
-        if let Ok((stream, stream_handle)) = OutputStream::try_default() {
-            if let Ok(file) = File::open(path) {
-                let buf_reader = BufReader::new(file);
-                if let Ok(source) = Decoder::new(buf_reader) {
-                    let sink = Sink::try_new(&stream_handle).unwrap();
-                    sink.append(source);
-                    self.current_playback = Some(sink);
-                    
-                    // Keep stream alive
-                    std::mem::forget(stream);
+        if let Ok((stream, stream_handle)) = OutputStream::try_default()
+            && let Ok(file) = File::open(path)
+        {
+            let sink = Sink::try_new(&stream_handle).unwrap();
+            if path.extension().is_some_and(|ext| ext == ClipFormat::Opus.extension()) {
+                // rodio can't decode Opus, so play it from the decoded samples
+                let Some(clip) = read_clip(path, ClipFormat::Opus) else {
+                    return;
+                };
+                sink.append(SamplesBuffer::new(clip.channels as u16, clip.sample_rate, clip.samples));
+            } else if let Ok(source) = Decoder::new(BufReader::new(file)) {
+                sink.append(source);
+            } else {
+                return;
+            }
+            self.current_playback = Some(sink);
+
+            // Keep stream alive
+            std::mem::forget(stream);
+        }
+    }
+
+    /// Pick up the vocalization breakdowns the analysis thread has finished so far.
+    fn receive_breakdowns(&mut self, ctx: &egui::Context) {
+        let Some(analyzed) = &self.analyzed else {
+            return;
+        };
+        loop {
+            match analyzed.try_recv() {
+                Ok((path, breakdown)) => {
+                    if let Some(recording) = self.recordings.iter_mut().find(|r| r.path == path) {
+                        recording.vocalizations = breakdown;
+                    }
+                }
+                Err(mpsc::TryRecvError::Empty) => {
+                    // Check back for more while nothing else redraws
+                    ctx.request_repaint_after(std::time::Duration::from_millis(250));
+                    return;
+                }
+                Err(mpsc::TryRecvError::Disconnected) => {
+                    self.analyzed = None;
+                    return;
                 }
             }
         }
     }
+
+    /// Mark a clip to be kept by the recorder's retention, in its sidecar.
+    fn set_keep(&mut self, path: &PathBuf, keep: bool) {
+        let Some(recording) = self.recordings.iter_mut().find(|r| &r.path == path) else {
+            return;
+        };
+        let Some(metadata) = &mut recording.metadata else {
+            return;
+        };
+        metadata.keep = keep;
+        if let Err(e) = metadata.write(path) {
+            eprintln!("Failed to update {}: {}", ClipMetadata::path_for(path).display(), e);
+        }
+    }
 }
 
 impl eframe::App for BarkViewer {
     fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
+        self.receive_breakdowns(ctx);
         egui::CentralPanel::default().show(ctx, |ui| {
             ui.heading("Bark Timeline");
             
@@ -187,6 +408,27 @@ impl eframe::App for BarkViewer {
                     self.timeline_end = now;
                     self.timeline_start = now - chrono::Duration::days(7);
                 }
+
+                if self.sources.len() > 1 {
+                    let selected = self.source_filter.clone().unwrap_or_else(|| "All sources".to_string());
+                    egui::ComboBox::from_label("Source")
+                        .selected_text(selected)
+                        .show_ui(ui, |ui| {
+                            ui.selectable_value(&mut self.source_filter, None, "All sources");
+                            for source in &self.sources {
+                                let name = if source.is_empty() { "(unlabelled)" } else { source.as_str() };
+                                ui.selectable_value(&mut self.source_filter, Some(source.clone()), name);
+                            }
+                        });
+                }
+
+                if self.sessions.is_empty() {
+                    ui.label("No session log, coverage unknown");
+                } else {
+                    let span = (self.timeline_end - self.timeline_start).num_seconds().max(1);
+                    let covered = self.covered_secs(self.timeline_start, self.timeline_end);
+                    ui.label(format!("Recorder running for {:.0}% of this view", covered as f32 / span as f32 * 100.0));
+                }
             });
 
             // Add side-by-side layout for timeline and zoom slider
@@ -213,7 +455,7 @@ impl eframe::App for BarkViewer {
                                 let zoom_factor = if scroll_delta > 0.0 { 1.25 } else { 0.8 };
                                 
                                 let center_time = self.timeline_start + chrono::Duration::seconds(
-                                    (self.timeline_end.timestamp() - self.timeline_start.timestamp()) as i64 * zoom_center as i64 / 100
+                                    (self.timeline_end.timestamp() - self.timeline_start.timestamp()) * zoom_center as i64 / 100
                                 );
                                 
                                 let new_duration = chrono::Duration::seconds(
@@ -244,6 +486,7 @@ impl eframe::App for BarkViewer {
 
                     // Find the maximum value among visible recordings
                     let max_visible_value = self.recordings.iter()
+                        .filter(|r| self.shows(&r.source))
                         .filter(|r| r.timestamp >= self.timeline_start && r.timestamp <= self.timeline_end)
                         .filter_map(|r| r.audio_stats)
                         .map(|(_, _, _, _, max)| max)
@@ -259,6 +502,114 @@ impl eframe::App for BarkViewer {
                     // Draw y-axis with percentage markers
                     let y_axis_width = 40.0;
                     let plot_rect = rect.shrink2(egui::vec2(y_axis_width, 0.0));
+
+                    // Shade when the recorder was listening, so a quiet stretch can be told apart
+                    // from a gap in coverage. Without a session log there's nothing to go on.
+                    if !self.sessions.is_empty() {
+                        let span = (self.timeline_end.timestamp() - self.timeline_start.timestamp()) as f32;
+                        let x_for = |t: i64| {
+                            let progress = (t - self.timeline_start.timestamp()) as f32 / span;
+                            (plot_rect.left() + progress * plot_rect.width()).clamp(plot_rect.left(), plot_rect.right())
+                        };
+                        painter.rect_filled(plot_rect, 0.0, egui::Color32::from_rgb(56, 30, 30));  // not recording
+                        for session in &self.sessions {
+                            let (start_x, end_x) = (x_for(session.start.timestamp()), x_for(session.end.timestamp()));
+                            if end_x > start_x {
+                                painter.rect_filled(
+                                    egui::Rect::from_x_y_ranges(start_x..=end_x, plot_rect.y_range()),
+                                    0.0,
+                                    egui::Color32::from_rgb(30, 46, 34),  // recording
+                                );
+                            }
+                            for (start, end) in &session.disarmed {
+                                let (start_x, end_x) = (x_for(start.timestamp()), x_for(end.timestamp()));
+                                if end_x > start_x {
+                                    painter.rect_filled(
+                                        egui::Rect::from_x_y_ranges(start_x..=end_x, plot_rect.y_range()),
+                                        0.0,
+                                        egui::Color32::from_rgb(30, 34, 48),  // outside the schedule
+                                    );
+                                }
+                            }
+                            for outage in session.outages.iter().filter(|o| self.shows(&o.source)) {
+                                let (start_x, end_x) = (x_for(outage.start.timestamp()), x_for(outage.end.timestamp()));
+                                if end_x > start_x {
+                                    let color = if self.is_gap(outage) {
+                                        egui::Color32::from_rgb(56, 30, 30)  // not recording
+                                    } else {
+                                        egui::Color32::from_rgb(56, 46, 26)  // one of several sources lost
+                                    };
+                                    painter.rect_filled(
+                                        egui::Rect::from_x_y_ranges(start_x..=end_x, plot_rect.y_range()),
+                                        0.0,
+                                        color,
+                                    );
+                                }
+                            }
+                            // Stream errors as short red ticks along the top
+                            for (time, _) in &session.stream_errors {
+                                let x = x_for(time.timestamp());
+                                painter.line_segment(
+                                    [egui::pos2(x, plot_rect.top()), egui::pos2(x, plot_rect.top() + 8.0)],
+                                    egui::Stroke::new(2.0, egui::Color32::from_rgb(220, 60, 60)),
+                                );
+                            }
+                        }
+                    }
+
+                    // The level log as a background envelope, peak and RMS per pixel column, plus the
+                    // threshold, on the same amplitude scale as the box plots. Merged sources show the
+                    // loudest of them.
+                    let visible_levels: Vec<&[LevelSample]> = self.levels.iter()
+                        .filter(|(source, _)| self.shows(source))
+                        .map(|(_, levels)| {
+                            let first = levels.partition_point(|l| l.time < self.timeline_start);
+                            let last = levels.partition_point(|l| l.time <= self.timeline_end);
+                            &levels[first..last]
+                        })
+                        .filter(|levels| !levels.is_empty())
+                        .collect();
+                    if !visible_levels.is_empty() {
+                        let columns = (plot_rect.width() as usize).max(1);
+                        let span_ms = (self.timeline_end - self.timeline_start).num_milliseconds().max(1) as f64;
+                        let mut envelope: Vec<Option<(f32, f32, f32)>> = vec![None; columns];
+                        for level in visible_levels.into_iter().flatten() {
+                            let progress = (level.time - self.timeline_start.fixed_offset()).num_milliseconds() as f64 / span_ms;
+                            let column = ((progress * columns as f64) as usize).min(columns - 1);
+                            let threshold = from_dbfs(level.threshold_dbfs);
+                            let (peak, rms, _) = envelope[column].get_or_insert((0.0, 0.0, threshold));
+                            *peak = peak.max(from_dbfs(level.peak_dbfs));
+                            *rms = rms.max(from_dbfs(level.rms_dbfs));
+                        }
+
+                        let y_base = plot_rect.bottom();
+                        let y_for = |amplitude: f32| y_base - plot_rect.height() * amplitude.min(1.0);
+                        let threshold_stroke = egui::Stroke::new(1.0, egui::Color32::from_rgb(200, 180, 60));
+                        let mut threshold_line = Vec::new();
+                        for (column, values) in envelope.iter().enumerate() {
+                            let Some((peak, rms, threshold)) = *values else {
+                                // Don't join the threshold across gaps in the log
+                                if threshold_line.len() > 1 {
+                                    painter.add(egui::Shape::line(std::mem::take(&mut threshold_line), threshold_stroke));
+                                }
+                                threshold_line.clear();
+                                continue;
+                            };
+                            let x = plot_rect.left() + column as f32 + 0.5;
+                            painter.line_segment(
+                                [egui::pos2(x, y_base), egui::pos2(x, y_for(peak))],
+                                egui::Stroke::new(1.0, egui::Color32::from_gray(72)),
+                            );
+                            painter.line_segment(
+                                [egui::pos2(x, y_base), egui::pos2(x, y_for(rms))],
+                                egui::Stroke::new(1.0, egui::Color32::from_gray(110)),
+                            );
+                            threshold_line.push(egui::pos2(x, y_for(threshold)));
+                        }
+                        if threshold_line.len() > 1 {
+                            painter.add(egui::Shape::line(threshold_line, threshold_stroke));
+                        }
+                    }
                     
                     // Draw y-axis line
                     painter.line_segment(
@@ -345,7 +696,7 @@ impl eframe::App for BarkViewer {
                     }
 
                     // Draw recordings as box plots using cached data
-                    for recording in &self.recordings {
+                    for recording in self.recordings.iter().filter(|r| self.shows(&r.source)) {
                         if recording.timestamp >= self.timeline_start && recording.timestamp <= self.timeline_end {
                             let progress = (recording.timestamp.timestamp() - self.timeline_start.timestamp()) as f32
                                 / (self.timeline_end.timestamp() - self.timeline_start.timestamp()) as f32;
@@ -356,12 +707,28 @@ impl eframe::App for BarkViewer {
                                 let whisker_width = box_width / 2.0;
                                 let y_base = plot_rect.bottom();
                                 
-                                // Choose color based on hover state only
+                                // Choose color based on hover state, dimmed for short events and
+                                // grey for ones that aren't a dog
                                 let color = if Some(recording.timestamp) == self.hovered_timestamp {
                                     egui::Color32::from_rgb(255, 200, 0)  // Brighter orange when hovered
+                                } else if recording.non_dog {
+                                    egui::Color32::from_rgb(120, 120, 120)  // Grey
+                                } else if recording.short {
+                                    egui::Color32::from_rgb(140, 100, 60)  // Dull orange
                                 } else {
                                     egui::Color32::from_rgb(255, 128, 0)  // Normal orange
                                 };
+
+                                // Link later parts back to the start of their episode
+                                if recording.part > 1 {
+                                    let episode_progress = (recording.episode.timestamp() - self.timeline_start.timestamp()) as f32
+                                        / (self.timeline_end.timestamp() - self.timeline_start.timestamp()) as f32;
+                                    let episode_x = (plot_rect.left() + episode_progress * plot_rect.width()).max(plot_rect.left());
+                                    painter.line_segment(
+                                        [egui::pos2(episode_x, y_base - 2.0), egui::pos2(x, y_base - 2.0)],
+                                        egui::Stroke::new(3.0, color),
+                                    );
+                                }
                                 
                                 // Draw vertical whisker lines
                                 painter.line_segment(
@@ -413,6 +780,25 @@ impl eframe::App for BarkViewer {
                                     ],
                                     egui::Stroke::new(2.0, egui::Color32::WHITE),
                                 );
+
+                                // Strip above the box split by seconds of barking, howling and whining
+                                let total: f32 = Vocalization::ALL.iter().map(|&kind| recording.vocalizations.secs(kind)).sum();
+                                if total > 0.0 {
+                                    let strip_bottom = y_base - plot_rect.height() * max - 3.0;
+                                    let mut left = x - box_width / 2.0;
+                                    for kind in Vocalization::ALL {
+                                        let width = box_width * recording.vocalizations.secs(kind) / total;
+                                        painter.rect_filled(
+                                            egui::Rect::from_min_max(
+                                                egui::pos2(left, strip_bottom - 4.0),
+                                                egui::pos2(left + width, strip_bottom),
+                                            ),
+                                            0.0,
+                                            vocalization_color(kind),
+                                        );
+                                        left += width;
+                                    }
+                                }
                             }
                         }
                     }
@@ -440,11 +826,15 @@ impl eframe::App for BarkViewer {
 
             // Show recording list grouped by day
             ui.heading("Recordings");
-            let mut recordings_ui = self.recordings.clone();
+            let mut recordings_ui: Vec<Recording> = self.recordings.iter()
+                .filter(|r| self.shows(&r.source))
+                .cloned()
+                .collect();
             recordings_ui.reverse(); // Reverse the order to show newest first
             
             // Group recordings by day
             let mut current_day: Option<chrono::NaiveDate> = None;
+            let mut kept = None;  // clip whose keep flag was toggled this frame
             for recording in &recordings_ui {
                 let recording_day = recording.timestamp.date_naive();
                 
@@ -457,10 +847,75 @@ impl eframe::App for BarkViewer {
                 let path = recording.path.clone();
                 let timestamp = recording.timestamp;  // Clone timestamp for hover state
                 ui.horizontal(|ui| {
-                    ui.label(format!("{} ({:.1}s)", 
+                    let mut label = format!("{} ({:.1}s)",
                         recording.timestamp.format("%I:%M:%S %p"),
                         recording.duration
-                    ));
+                    );
+                    if recording.part > 1 {
+                        label.push_str(&format!(" part {} of episode at {}", recording.part, recording.episode.format("%I:%M:%S %p")));
+                    }
+                    if recording.short {
+                        label.push_str(" short");
+                    }
+                    if recording.non_dog {
+                        label.push_str(" not a dog");
+                    }
+                    if self.source_filter.is_none() && !recording.source.is_empty() {
+                        label.push_str(&format!(" [{}]", recording.source));
+                    }
+                    let label = ui.label(label);
+                    if let Some(m) = recording.metadata.as_ref().filter(|m| m.recovered) {
+                        label.on_hover_text(format!(
+                            "Peak {:.1} dBFS, RMS {:.1} dBFS\n{} Hz, {} ch\nRecovered after the recorder was cut off, no detection details",
+                            to_dbfs(m.peak),
+                            to_dbfs(m.rms),
+                            m.sample_rate,
+                            m.channels,
+                        ));
+                    } else if let Some(m) = &recording.metadata {
+                        let mut text = format!(
+                            "Peak {:.1} dBFS, RMS {:.1} dBFS\n{} detector, threshold {:.3} (sustain {:.3}), {} resets\n{} ({} Hz, {} ch)\nSession {}",
+                            to_dbfs(m.peak),
+                            to_dbfs(m.rms),
+                            m.detector,
+                            m.threshold,
+                            m.sustain_threshold,
+                            m.trigger_resets,
+                            m.device,
+                            m.sample_rate,
+                            m.channels,
+                            m.session,
+                        );
+                        if let Some(c) = &m.classification {
+                            text.push_str(&format!(
+                                "\nDog score {:.2}{}: centroid {:.0} Hz, flatness {:.2}",
+                                c.dog_score,
+                                if c.dog { "" } else { " (not a dog)" },
+                                c.features.centroid_hz,
+                                c.features.flatness,
+                            ));
+                        }
+                        if let Some(filters) = &m.filters {
+                            let applied = if filters.recorded { "detection and recording" } else { "detection" };
+                            text.push_str(&format!("\nFilters ({}): {}", applied, filters.describe()));
+                        }
+                        label.on_hover_text(text);
+                    }
+                    for kind in Vocalization::ALL {
+                        let secs = recording.vocalizations.secs(kind);
+                        if secs > 0.0 {
+                            ui.colored_label(vocalization_color(kind), format!("{:.1}s {}", secs, kind));
+                        }
+                    }
+                    if let Some(m) = &recording.metadata {
+                        let mut keep = m.keep;
+                        if ui.checkbox(&mut keep, "Keep")
+                            .on_hover_text("Never delete this clip when old clips are cleaned up")
+                            .changed()
+                        {
+                            kept = Some((path.clone(), keep));
+                        }
+                    }
                     let play_button = ui.button("Play");
                     if play_button.hovered() {
                         self.hovered_timestamp = Some(timestamp);
@@ -468,15 +923,19 @@ impl eframe::App for BarkViewer {
                     if play_button.clicked() {
                         self.play_audio(&path);
                     }
-                    if let Some(sink) = &self.current_playback {
-                        if ui.button("Stop").clicked() {
-                            sink.stop();
-                            self.current_playback = None;
-                        }
+                    if let Some(sink) = &self.current_playback
+                        && ui.button("Stop").clicked()
+                    {
+                        sink.stop();
+                        self.current_playback = None;
                     }
                 });
             }
 
+            if let Some((path, keep)) = kept {
+                self.set_keep(&path, keep);
+            }
+
             // Reset hover state on each frame
             if !ctx.input(|i| i.pointer.has_pointer()) {
                 self.hovered_timestamp = None;
@@ -486,6 +945,9 @@ impl eframe::App for BarkViewer {
 }
 
 fn main() -> eframe::Result<()> {
+    // Optional first argument: the recorder's output directory
+    let barks_dir = std::env::args().nth(1).unwrap_or_else(|| "barks".to_string());
+
     let native_options = eframe::NativeOptions {
         viewport: egui::ViewportBuilder::default()
             .with_inner_size([800.0, 600.0]),
@@ -495,6 +957,6 @@ fn main() -> eframe::Result<()> {
     eframe::run_native(
         "Bark Viewer",
         native_options,
-        Box::new(|_cc| Box::new(BarkViewer::new())),
+        Box::new(move |_cc| Box::new(BarkViewer::new(&barks_dir))),
     )
 } 
\ No newline at end of file
@@ -0,0 +1,1028 @@
+use clap::{Args, ValueEnum};
+use serde::{Deserialize, Serialize};
+use chrono::NaiveDateTime;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{self, Component, Path, PathBuf};
+use std::time::Duration;
+
+use ranger_recorder_rs::naming::ClipFormat;
+use ranger_recorder_rs::sidecar::FilterSettings;
+
+use crate::detector::DetectorKind;
+use crate::schedule::{Arming, Profile, Window};
+
+/// Config file picked up from the working directory when `--config` isn't given.
+pub const DEFAULT_CONFIG_FILE: &str = "ranger-recorder.toml";
+
+/// Largest size in MB that still fits in a byte count.
+const MAX_MB: u64 = u64::MAX / (1024 * 1024);
+/// A century, well inside what the retention cut-off date can be computed for.
+const MAX_RETENTION_DAYS: f64 = 36_500.0;
+
+/// Sample format used for the WAV clips.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum WavFormat {
+    Int16,
+    Int24,
+    Int32,
+    Float32,
+    /// Whatever the input delivers: the device's sample format, or the source file's in `detect`
+    Native,
+}
+
+impl WavFormat {
+    /// The format clips are written in: this one, or `native` for `Native`.
+    pub fn resolve(self, native: ResolvedFormat) -> ResolvedFormat {
+        match self {
+            WavFormat::Int16 => ResolvedFormat::Int16,
+            WavFormat::Int24 => ResolvedFormat::Int24,
+            WavFormat::Int32 => ResolvedFormat::Int32,
+            WavFormat::Float32 => ResolvedFormat::Float32,
+            WavFormat::Native => native,
+        }
+    }
+}
+
+/// A `WavFormat` with `Native` resolved to an input's format, which clips can be written in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ResolvedFormat {
+    Int16,
+    Int24,
+    Int32,
+    Float32,
+}
+
+impl ResolvedFormat {
+    /// Closest clip format to a device's native sample format.
+    pub fn for_sample_format(format: cpal::SampleFormat) -> Self {
+        use cpal::SampleFormat;
+        match format {
+            SampleFormat::I8 | SampleFormat::U8 | SampleFormat::I16 | SampleFormat::U16 => ResolvedFormat::Int16,
+            SampleFormat::F32 | SampleFormat::F64 => ResolvedFormat::Float32,
+            _ => ResolvedFormat::Int32,
+        }
+    }
+
+    /// Clip format matching an existing WAV file.
+    pub fn for_wav_spec(spec: hound::WavSpec) -> Self {
+        match (spec.sample_format, spec.bits_per_sample) {
+            (hound::SampleFormat::Float, _) => ResolvedFormat::Float32,
+            (hound::SampleFormat::Int, 0..=16) => ResolvedFormat::Int16,
+            (hound::SampleFormat::Int, 17..=24) => ResolvedFormat::Int24,
+            (hound::SampleFormat::Int, _) => ResolvedFormat::Int32,
+        }
+    }
+
+    pub fn spec(self, channels: u16, sample_rate: u32) -> hound::WavSpec {
+        let (bits_per_sample, sample_format) = match self {
+            ResolvedFormat::Int16 => (16, hound::SampleFormat::Int),
+            ResolvedFormat::Int24 => (24, hound::SampleFormat::Int),
+            ResolvedFormat::Int32 => (32, hound::SampleFormat::Int),
+            ResolvedFormat::Float32 => (32, hound::SampleFormat::Float),
+        };
+        hound::WavSpec {
+            channels,
+            sample_rate,
+            bits_per_sample,
+            sample_format,
+        }
+    }
+
+    /// The sample format FLAC clips get for this one. FLAC only stores integers, and this
+    /// encoder only up to 24 bits.
+    pub fn for_flac(self) -> Self {
+        match self {
+            ResolvedFormat::Int16 => ResolvedFormat::Int16,
+            _ => ResolvedFormat::Int24,
+        }
+    }
+
+    /// Write one normalised (-1.0..=1.0) sample in this format.
+    pub fn write_sample<W>(self, writer: &mut hound::WavWriter<W>, sample: f32) -> hound::Result<()>
+    where
+        W: std::io::Write + std::io::Seek,
+    {
+        match self {
+            ResolvedFormat::Int16 => writer.write_sample(quantize(sample, 16) as i16),
+            ResolvedFormat::Int24 => writer.write_sample(quantize(sample, 24)),
+            ResolvedFormat::Int32 => writer.write_sample(quantize(sample, 32)),
+            ResolvedFormat::Float32 => writer.write_sample(sample.clamp(-1.0, 1.0)),
+        }
+    }
+}
+
+/// A normalised (-1.0..=1.0) sample as a signed integer `bits_per_sample` wide.
+pub fn quantize(sample: f32, bits_per_sample: u32) -> i32 {
+    let full_scale = ((1i64 << (bits_per_sample - 1)) - 1) as f64;
+    (sample.clamp(-1.0, 1.0) as f64 * full_scale) as i32
+}
+
+/// What happens to a clip whose event was shorter than `min_event_secs`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ShortEventAction {
+    /// Delete the clip
+    Discard,
+    /// Keep the clip, with a `_short` suffix on its name
+    Flag,
+}
+
+/// What happens to a clip the classifier doesn't think is a dog.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum NonDogAction {
+    /// Keep the clip as usual, only noting the score in its sidecar
+    Keep,
+    /// Keep the clip, tagged as not a dog in its sidecar
+    Tag,
+    /// Delete the clip
+    Drop,
+}
+
+/// One of several microphones recorded at once. Anything not set here comes from the top level.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SourceConfig {
+    /// Name for the source, stored in each clip's sidecar
+    pub label: String,
+    /// Input device, by name substring or `list-devices` index
+    #[serde(default)]
+    pub device: String,
+    /// Fixed threshold for this source, instead of the top-level one
+    pub threshold: Option<f64>,
+    /// Directory under `output_dir` for this source's clips. Defaults to the label.
+    pub output_subdir: Option<PathBuf>,
+}
+
+/// Parse `LABEL=DEVICE` from the command line.
+fn parse_source(s: &str) -> Result<SourceConfig, String> {
+    let (label, device) = s.split_once('=').ok_or_else(|| format!("expected LABEL=DEVICE, got \"{}\"", s))?;
+    Ok(SourceConfig {
+        label: label.to_string(),
+        device: device.to_string(),
+        threshold: None,
+        output_subdir: None,
+    })
+}
+
+/// Parse `LOW,HIGH` in Hz from the command line.
+fn parse_band(s: &str) -> Result<(f64, f64), String> {
+    let (low, high) = s.split_once(',').ok_or_else(|| format!("expected LOW,HIGH, got \"{}\"", s))?;
+    let hz = |v: &str| v.trim().parse::<f64>().map_err(|e| format!("\"{}\": {}", v, e));
+    Ok((hz(low)?, hz(high)?))
+}
+
+/// Everything the recorder needs to know about a session.
+///
+/// Values are layered: `Config::default()`, then the TOML config file, then command-line flags.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Detector score (0.0 - 1.0) that starts a clip
+    pub threshold: f64,
+    /// Score that keeps an open clip going. Defaults to `threshold`.
+    pub sustain_threshold: Option<f64>,
+    /// How long the detector must stay triggered before a clip starts, in milliseconds
+    pub min_attack_ms: f64,
+    /// Derive the threshold from the ambient noise floor instead of using `threshold`
+    pub auto_threshold: bool,
+    /// With `auto_threshold`, how far above the noise floor (frame RMS) the threshold sits
+    pub auto_margin_db: f64,
+    /// With `auto_threshold`, the sustain threshold's margin above the noise floor. Defaults to
+    /// `auto_margin_db`.
+    pub auto_sustain_margin_db: Option<f64>,
+    /// Percentile of recent frame RMS levels taken as the noise floor
+    pub noise_floor_percentile: f64,
+    /// How much history the noise floor is estimated over, in seconds
+    pub noise_floor_window_secs: f64,
+    /// How often the noise floor and effective threshold are logged, in seconds
+    pub noise_floor_log_secs: f64,
+    /// How buffers are scored against the threshold
+    pub detector: DetectorKind,
+    /// Sliding window for the `rms` and `band` detectors, in milliseconds
+    pub detector_window_ms: f64,
+    /// Pass band of the `band` detector, in Hz
+    pub band_low_hz: f64,
+    pub band_high_hz: f64,
+    /// High-pass in front of the detector against rumble, in Hz. 0 turns it off.
+    pub filter_highpass_hz: f64,
+    /// Mains frequency (50 or 60) to notch out of the detector's input with its harmonics. 0 turns it off.
+    pub filter_notch_hz: f64,
+    /// How many notches: the mains frequency and its multiples
+    pub filter_notch_harmonics: u32,
+    /// Width of each notch, higher is narrower
+    pub filter_notch_q: f64,
+    /// Band-pass in front of the detector, in Hz. Both 0 turns it off.
+    pub filter_bandpass_low_hz: f64,
+    pub filter_bandpass_high_hz: f64,
+    /// Record the filtered audio rather than only detecting on it
+    pub filter_recording: bool,
+    /// Seconds of silence after the last bark before a clip is closed
+    pub silence_timeout_secs: f64,
+    /// Events shorter than this (first trigger to last bark, in seconds) are discarded or flagged
+    pub min_event_secs: f64,
+    pub short_event_action: ShortEventAction,
+    /// Score each clip's barking as dog or not from its spectrum
+    pub classify: bool,
+    /// With `classify`, the dog score (0.0 - 1.0) below which a clip isn't a dog
+    pub dog_threshold: f64,
+    pub non_dog_action: NonDogAction,
+    /// Longest single clip in seconds. Longer episodes continue in `_part<n>` files. 0 never splits.
+    pub max_clip_secs: f64,
+    /// Seconds of audio before the threshold crossing to include at the start of each clip
+    pub pre_roll_secs: f64,
+    /// Where clips are written
+    pub output_dir: PathBuf,
+    /// Interval of the continuous peak / RMS level log, in seconds. 0 turns it off.
+    pub level_log_secs: f64,
+    /// How long to listen for, in minutes. 0 means run until stopped.
+    pub session_minutes: u64,
+    /// Audio host (e.g. ALSA, JACK), as named by `list-devices`. Empty means the default host.
+    pub host: String,
+    /// Substring of the input device name, or its index in `list-devices`. Empty means the
+    /// host's default input.
+    pub device: String,
+    /// Capture sample rate in Hz. 0 uses the device's default.
+    pub sample_rate: u32,
+    /// Capture channel count. 0 uses the device's default.
+    pub channels: u16,
+    pub wav_format: WavFormat,
+    /// Mix the input down to mono before detection and recording
+    pub downmix: bool,
+    /// Relative weight of each input channel in the mono mix. Empty weighs them equally.
+    pub downmix_weights: Vec<f64>,
+    /// Resample the input to this rate in Hz before detection and recording. 0 keeps the input's.
+    pub resample_hz: u32,
+    /// File format of the clips
+    pub clip_format: ClipFormat,
+    /// Bit rate of Opus clips, in kbit/s
+    pub opus_bitrate_kbps: u32,
+    /// Clips older than this many days are removed. 0 keeps them forever.
+    pub retention_max_age_days: f64,
+    /// Oldest clips are removed while all of them together take more than this many MB. 0 means
+    /// no limit.
+    pub retention_max_mb: u64,
+    /// Never remove clips marked to keep in the viewer
+    pub retention_keep_flagged: bool,
+    /// Move removed clips here instead of deleting them
+    pub retention_archive_dir: Option<PathBuf>,
+    /// New clips aren't started while the output directory's disk has less than this many MB free
+    pub min_free_mb: u64,
+    /// Seconds without audio from an input before it's considered lost and reopened
+    pub stall_timeout_secs: f64,
+    /// Microphones to record at once. Empty records just `device`.
+    pub sources: Vec<SourceConfig>,
+    /// When to record. Empty records all the time.
+    pub schedule: Vec<Window>,
+    /// Named detection settings that schedule windows can switch to
+    pub profiles: BTreeMap<String, Profile>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            threshold: 0.05,
+            sustain_threshold: None,
+            min_attack_ms: 0.0,
+            auto_threshold: false,
+            auto_margin_db: 20.0,
+            auto_sustain_margin_db: None,
+            noise_floor_percentile: 20.0,
+            noise_floor_window_secs: 60.0,
+            noise_floor_log_secs: 60.0,
+            detector: DetectorKind::Peak,
+            detector_window_ms: 50.0,
+            band_low_hz: 400.0,
+            band_high_hz: 4000.0,
+            filter_highpass_hz: 0.0,
+            filter_notch_hz: 0.0,
+            filter_notch_harmonics: 3,
+            filter_notch_q: 30.0,
+            filter_bandpass_low_hz: 0.0,
+            filter_bandpass_high_hz: 0.0,
+            filter_recording: false,
+            silence_timeout_secs: 5.0,
+            min_event_secs: 0.0,
+            short_event_action: ShortEventAction::Flag,
+            classify: false,
+            dog_threshold: 0.5,
+            non_dog_action: NonDogAction::Tag,
+            max_clip_secs: 300.0,
+            pre_roll_secs: 2.0,
+            output_dir: PathBuf::from("barks"),
+            level_log_secs: 1.0,
+            session_minutes: 0,
+            host: String::new(),
+            device: String::new(),
+            sample_rate: 0,
+            channels: 0,
+            wav_format: WavFormat::Int16,
+            downmix: false,
+            downmix_weights: Vec::new(),
+            resample_hz: 0,
+            clip_format: ClipFormat::Wav,
+            opus_bitrate_kbps: 24,
+            retention_max_age_days: 0.0,
+            retention_max_mb: 0,
+            retention_keep_flagged: true,
+            retention_archive_dir: None,
+            min_free_mb: 200,
+            stall_timeout_secs: 5.0,
+            sources: Vec::new(),
+            schedule: Vec::new(),
+            profiles: BTreeMap::new(),
+        }
+    }
+}
+
+/// Command-line overrides for `Config`. Anything left unset keeps the config file's value.
+#[derive(Args, Debug, Default)]
+pub struct ConfigArgs {
+    /// TOML config file [default: ranger-recorder.toml, if present]
+    #[arg(short, long, value_name = "FILE", global = true)]
+    pub config: Option<PathBuf>,
+    /// Fixed detector score (0.0 - 1.0) that starts a clip. Overrides `auto_threshold`.
+    #[arg(short, long, global = true)]
+    pub threshold: Option<f64>,
+    /// Score that keeps an open clip going [default: same as threshold]
+    #[arg(long, value_name = "THRESHOLD", global = true)]
+    pub sustain_threshold: Option<f64>,
+    /// How long it must stay loud before a clip starts, in milliseconds
+    #[arg(long, value_name = "MS", global = true)]
+    pub min_attack_ms: Option<f64>,
+    /// Track the noise floor and trigger a margin above it
+    #[arg(short, long, conflicts_with = "threshold", global = true)]
+    pub auto_threshold: bool,
+    /// Margin above the noise floor for --auto-threshold, in dB
+    #[arg(long, value_name = "DB", global = true)]
+    pub margin_db: Option<f64>,
+    /// How buffers are scored against the threshold
+    #[arg(long, value_enum, global = true)]
+    pub detector: Option<DetectorKind>,
+    /// High-pass the detector's input at this frequency
+    #[arg(long, value_name = "HZ", global = true)]
+    pub highpass: Option<f64>,
+    /// Notch mains hum at this frequency (50 or 60) and its harmonics out of the detector's input
+    #[arg(long, value_name = "HZ", global = true)]
+    pub notch: Option<f64>,
+    /// Band-pass the detector's input, e.g. 400,4000
+    #[arg(long, value_name = "LOW,HIGH", value_parser = parse_band, global = true)]
+    pub bandpass: Option<(f64, f64)>,
+    /// Record the filtered audio instead of the raw input
+    #[arg(long, global = true)]
+    pub filter_recording: bool,
+    /// Seconds of silence before a clip is closed
+    #[arg(short, long, value_name = "SECS", global = true)]
+    pub silence_timeout: Option<f64>,
+    /// Events shorter than this many seconds are discarded or flagged
+    #[arg(long, value_name = "SECS", global = true)]
+    pub min_event: Option<f64>,
+    /// What to do with events shorter than --min-event
+    #[arg(long, value_enum, global = true)]
+    pub short_events: Option<ShortEventAction>,
+    /// Score each clip as dog or not from its spectrum
+    #[arg(long, global = true)]
+    pub classify: bool,
+    /// Dog score (0.0 - 1.0) below which a clip isn't a dog
+    #[arg(long, value_name = "SCORE", global = true)]
+    pub dog_threshold: Option<f64>,
+    /// What to do with clips that aren't a dog, with --classify
+    #[arg(long, value_enum, global = true)]
+    pub non_dog: Option<NonDogAction>,
+    /// Split episodes into clips of at most this many seconds (0 never splits)
+    #[arg(long, value_name = "SECS", global = true)]
+    pub max_clip: Option<f64>,
+    /// Seconds of audio kept from before the threshold crossing
+    #[arg(short, long, value_name = "SECS", global = true)]
+    pub pre_roll: Option<f64>,
+    /// Directory clips are written to
+    #[arg(short, long, value_name = "DIR", global = true)]
+    pub output_dir: Option<PathBuf>,
+    /// Seconds per line of the level log (0 turns it off)
+    #[arg(long, value_name = "SECS", global = true)]
+    pub level_log: Option<f64>,
+    /// Session length in minutes (0 runs until stopped)
+    #[arg(short = 'm', long, value_name = "MINUTES", global = true)]
+    pub session_minutes: Option<u64>,
+    /// Run until stopped, ignoring any session length
+    #[arg(long, conflicts_with = "session_minutes", global = true)]
+    pub until_stopped: bool,
+    /// Audio host to use, see list-devices
+    #[arg(long, value_name = "HOST", global = true)]
+    pub host: Option<String>,
+    /// Input device to use, by name substring or list-devices index
+    #[arg(short, long, value_name = "NAME|INDEX", global = true)]
+    pub device: Option<String>,
+    /// Capture sample rate in Hz [default: the device's]
+    #[arg(long, value_name = "HZ", global = true)]
+    pub sample_rate: Option<u32>,
+    /// Capture channel count [default: the device's]
+    #[arg(long, global = true)]
+    pub channels: Option<u16>,
+    /// Sample format of the written clips
+    #[arg(short, long, value_enum, global = true)]
+    pub wav_format: Option<WavFormat>,
+    /// Mix the input down to mono before detection and recording
+    #[arg(long, global = true)]
+    pub downmix: bool,
+    /// Weight of each input channel in the mono mix, e.g. 1,0 for the left channel only
+    #[arg(long, value_name = "W,W,...", value_delimiter = ',', global = true)]
+    pub downmix_weights: Vec<f64>,
+    /// Resample the input to this rate before detection and recording
+    #[arg(long, value_name = "HZ", global = true)]
+    pub resample: Option<u32>,
+    /// File format of the written clips
+    #[arg(long, value_enum, global = true)]
+    pub clip_format: Option<ClipFormat>,
+    /// Bit rate of Opus clips
+    #[arg(long, value_name = "KBPS", global = true)]
+    pub opus_bitrate: Option<u32>,
+    /// Remove clips older than this many days (0 keeps them forever)
+    #[arg(long, value_name = "DAYS", global = true)]
+    pub max_age_days: Option<f64>,
+    /// Remove the oldest clips while they take more than this many MB (0 means no limit)
+    #[arg(long, value_name = "MB", global = true)]
+    pub max_mb: Option<u64>,
+    /// Let retention remove clips marked to keep in the viewer too
+    #[arg(long, global = true)]
+    pub delete_kept: bool,
+    /// Move clips removed by retention here instead of deleting them
+    #[arg(long, value_name = "DIR", global = true)]
+    pub archive_dir: Option<PathBuf>,
+    /// Don't start clips while the output disk has less than this many MB free (0 turns it off)
+    #[arg(long, value_name = "MB", global = true)]
+    pub min_free: Option<u64>,
+    /// Seconds without audio before an input is reopened
+    #[arg(long, value_name = "SECS", global = true)]
+    pub stall_timeout: Option<f64>,
+    /// Record this device as a separate source, repeat for several (replaces the config's sources)
+    #[arg(long = "source", value_name = "LABEL=DEVICE", value_parser = parse_source, global = true)]
+    pub sources: Vec<SourceConfig>,
+    /// Record all the time, ignoring the config's schedule
+    #[arg(long, global = true)]
+    pub ignore_schedule: bool,
+}
+
+impl Config {
+    /// Build the effective config from defaults, the config file and `args`.
+    pub fn load(args: &ConfigArgs) -> Result<Self, String> {
+        let mut config = match &args.config {
+            Some(path) => Self::from_file(path)?,
+            None if Path::new(DEFAULT_CONFIG_FILE).exists() => Self::from_file(Path::new(DEFAULT_CONFIG_FILE))?,
+            None => Self::default(),
+        };
+        config.apply_args(args);
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self, String> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read config {}: {}", path.display(), e))?;
+        toml::from_str(&text).map_err(|e| format!("Invalid config {}: {}", path.display(), e))
+    }
+
+    fn apply_args(&mut self, args: &ConfigArgs) {
+        if let Some(threshold) = args.threshold {
+            self.threshold = threshold;
+            self.auto_threshold = false;
+        }
+        if let Some(sustain) = args.sustain_threshold {
+            self.sustain_threshold = Some(sustain);
+        }
+        if let Some(ms) = args.min_attack_ms {
+            self.min_attack_ms = ms;
+        }
+        if args.auto_threshold {
+            self.auto_threshold = true;
+        }
+        if let Some(db) = args.margin_db {
+            self.auto_margin_db = db;
+        }
+        if let Some(detector) = args.detector {
+            self.detector = detector;
+        }
+        if let Some(hz) = args.highpass {
+            self.filter_highpass_hz = hz;
+        }
+        if let Some(hz) = args.notch {
+            self.filter_notch_hz = hz;
+        }
+        if let Some((low, high)) = args.bandpass {
+            self.filter_bandpass_low_hz = low;
+            self.filter_bandpass_high_hz = high;
+        }
+        if args.filter_recording {
+            self.filter_recording = true;
+        }
+        if let Some(secs) = args.silence_timeout {
+            self.silence_timeout_secs = secs;
+        }
+        if let Some(secs) = args.min_event {
+            self.min_event_secs = secs;
+        }
+        if let Some(action) = args.short_events {
+            self.short_event_action = action;
+        }
+        if args.classify {
+            self.classify = true;
+        }
+        if let Some(score) = args.dog_threshold {
+            self.dog_threshold = score;
+        }
+        if let Some(action) = args.non_dog {
+            self.non_dog_action = action;
+        }
+        if let Some(secs) = args.max_clip {
+            self.max_clip_secs = secs;
+        }
+        if let Some(secs) = args.pre_roll {
+            self.pre_roll_secs = secs;
+        }
+        if let Some(dir) = &args.output_dir {
+            self.output_dir = dir.clone();
+        }
+        if let Some(secs) = args.level_log {
+            self.level_log_secs = secs;
+        }
+        if let Some(minutes) = args.session_minutes {
+            self.session_minutes = minutes;
+        }
+        if args.until_stopped {
+            self.session_minutes = 0;
+        }
+        if let Some(host) = &args.host {
+            self.host = host.clone();
+        }
+        if let Some(device) = &args.device {
+            self.device = device.clone();
+        }
+        if let Some(rate) = args.sample_rate {
+            self.sample_rate = rate;
+        }
+        if let Some(channels) = args.channels {
+            self.channels = channels;
+        }
+        if let Some(format) = args.wav_format {
+            self.wav_format = format;
+        }
+        if args.downmix {
+            self.downmix = true;
+        }
+        if !args.downmix_weights.is_empty() {
+            self.downmix_weights = args.downmix_weights.clone();
+        }
+        if let Some(rate) = args.resample {
+            self.resample_hz = rate;
+        }
+        if let Some(format) = args.clip_format {
+            self.clip_format = format;
+        }
+        if let Some(kbps) = args.opus_bitrate {
+            self.opus_bitrate_kbps = kbps;
+        }
+        if let Some(days) = args.max_age_days {
+            self.retention_max_age_days = days;
+        }
+        if let Some(mb) = args.max_mb {
+            self.retention_max_mb = mb;
+        }
+        if args.delete_kept {
+            self.retention_keep_flagged = false;
+        }
+        if let Some(dir) = &args.archive_dir {
+            self.retention_archive_dir = Some(dir.clone());
+        }
+        if let Some(mb) = args.min_free {
+            self.min_free_mb = mb;
+        }
+        if let Some(secs) = args.stall_timeout {
+            self.stall_timeout_secs = secs;
+        }
+        if !args.sources.is_empty() {
+            self.sources = args.sources.clone();
+        }
+        if args.ignore_schedule {
+            self.schedule.clear();
+        }
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if !(0.0..=1.0).contains(&self.threshold) {
+            return Err(format!("threshold must be between 0.0 and 1.0, got {}", self.threshold));
+        }
+        if !self.silence_timeout_secs.is_finite() || self.silence_timeout_secs < 0.0 {
+            return Err(format!("silence_timeout_secs must be >= 0, got {}", self.silence_timeout_secs));
+        }
+        if let Some(sustain) = self.sustain_threshold
+            && !(0.0..=1.0).contains(&sustain)
+        {
+            return Err(format!("sustain_threshold must be between 0.0 and 1.0, got {}", sustain));
+        }
+        // A sustain above the start threshold would count a clip as quiet as soon as it starts
+        if let Some(sustain) = self.sustain_threshold
+            && sustain > self.threshold
+        {
+            return Err(format!("sustain_threshold must be at most threshold ({}), got {}", self.threshold, sustain));
+        }
+        if !self.min_attack_ms.is_finite() || self.min_attack_ms < 0.0 {
+            return Err(format!("min_attack_ms must be >= 0, got {}", self.min_attack_ms));
+        }
+        if !self.auto_margin_db.is_finite() || self.auto_margin_db <= 0.0 {
+            return Err(format!("auto_margin_db must be > 0, got {}", self.auto_margin_db));
+        }
+        if let Some(db) = self.auto_sustain_margin_db
+            && !(db.is_finite() && db > 0.0 && db <= self.auto_margin_db)
+        {
+            return Err(format!("auto_sustain_margin_db must be > 0 and at most auto_margin_db ({}), got {}", self.auto_margin_db, db));
+        }
+        if !(0.0..=100.0).contains(&self.noise_floor_percentile) {
+            return Err(format!("noise_floor_percentile must be between 0 and 100, got {}", self.noise_floor_percentile));
+        }
+        if !self.noise_floor_window_secs.is_finite() || self.noise_floor_window_secs <= 0.0 {
+            return Err(format!("noise_floor_window_secs must be > 0, got {}", self.noise_floor_window_secs));
+        }
+        if !self.noise_floor_log_secs.is_finite() || self.noise_floor_log_secs < 0.0 {
+            return Err(format!("noise_floor_log_secs must be >= 0, got {}", self.noise_floor_log_secs));
+        }
+        if !self.detector_window_ms.is_finite() || self.detector_window_ms <= 0.0 {
+            return Err(format!("detector_window_ms must be > 0, got {}", self.detector_window_ms));
+        }
+        if !(self.band_low_hz > 0.0 && self.band_low_hz < self.band_high_hz) {
+            return Err(format!(
+                "band_low_hz must be > 0 and below band_high_hz, got {} - {}",
+                self.band_low_hz, self.band_high_hz
+            ));
+        }
+        if !self.min_event_secs.is_finite() || self.min_event_secs < 0.0 {
+            return Err(format!("min_event_secs must be >= 0, got {}", self.min_event_secs));
+        }
+        if !(0.0..=1.0).contains(&self.dog_threshold) {
+            return Err(format!("dog_threshold must be between 0.0 and 1.0, got {}", self.dog_threshold));
+        }
+        if !self.max_clip_secs.is_finite() || self.max_clip_secs < 0.0 {
+            return Err(format!("max_clip_secs must be >= 0, got {}", self.max_clip_secs));
+        }
+        if !self.pre_roll_secs.is_finite() || self.pre_roll_secs < 0.0 {
+            return Err(format!("pre_roll_secs must be >= 0, got {}", self.pre_roll_secs));
+        }
+        for (key, hz) in [
+            ("filter_highpass_hz", self.filter_highpass_hz),
+            ("filter_notch_hz", self.filter_notch_hz),
+            ("filter_bandpass_low_hz", self.filter_bandpass_low_hz),
+            ("filter_bandpass_high_hz", self.filter_bandpass_high_hz),
+        ] {
+            if !hz.is_finite() || hz < 0.0 {
+                return Err(format!("{} must be >= 0, got {}", key, hz));
+            }
+        }
+        if self.filter_notch_hz > 0.0 && (self.filter_notch_harmonics == 0 || self.filter_notch_q <= 0.0) {
+            return Err(format!(
+                "filter_notch_harmonics must be >= 1 and filter_notch_q > 0, got {} and {}",
+                self.filter_notch_harmonics, self.filter_notch_q
+            ));
+        }
+        let bandpass = (self.filter_bandpass_low_hz, self.filter_bandpass_high_hz);
+        if bandpass != (0.0, 0.0) && !(bandpass.0 > 0.0 && bandpass.0 < bandpass.1) {
+            return Err(format!(
+                "filter_bandpass_low_hz must be > 0 and below filter_bandpass_high_hz, got {} - {}",
+                bandpass.0, bandpass.1
+            ));
+        }
+        // Otherwise the rate is the device's, checked once it's open
+        if let Some(rate) = [self.resample_hz, self.sample_rate].into_iter().find(|&rate| rate > 0) {
+            self.check_cutoffs(rate)?;
+        }
+        if self.downmix_weights.iter().any(|w| !w.is_finite() || *w < 0.0)
+            || (!self.downmix_weights.is_empty() && self.downmix_weights.iter().sum::<f64>() <= 0.0)
+        {
+            return Err(format!(
+                "downmix_weights must be >= 0 and not all 0, got {:?}",
+                self.downmix_weights
+            ));
+        }
+        // Checked here rather than by clap, as `downmix` can come from the config file
+        if !self.downmix && !self.downmix_weights.is_empty() {
+            return Err("downmix_weights only applies with downmix = true (--downmix)".to_string());
+        }
+        if self.resample_hz != 0 && !(1000..=384_000).contains(&self.resample_hz) {
+            return Err(format!("resample_hz must be 0 or 1000 - 384000, got {}", self.resample_hz));
+        }
+        if self.clip_format == ClipFormat::Flac && matches!(self.wav_format, WavFormat::Int32 | WavFormat::Float32) {
+            return Err("clip_format = \"flac\" needs wav_format int16, int24 or native, FLAC clips can't hold \
+                 32-bit or float samples".to_string());
+        }
+        if self.clip_format == ClipFormat::Opus && !cfg!(feature = "opus") {
+            return Err("clip_format = \"opus\" needs the recorder built with `--features opus`".to_string());
+        }
+        if !(6..=256).contains(&self.opus_bitrate_kbps) {
+            return Err(format!("opus_bitrate_kbps must be 6 - 256, got {}", self.opus_bitrate_kbps));
+        }
+        if !(0.0..=MAX_RETENTION_DAYS).contains(&self.retention_max_age_days) {
+            return Err(format!(
+                "retention_max_age_days must be 0 - {}, got {}",
+                MAX_RETENTION_DAYS, self.retention_max_age_days
+            ));
+        }
+        if self.retention_max_mb > MAX_MB {
+            return Err(format!("retention_max_mb must be at most {}, got {}", MAX_MB, self.retention_max_mb));
+        }
+        if self.min_free_mb > MAX_MB {
+            return Err(format!("min_free_mb must be at most {}, got {}", MAX_MB, self.min_free_mb));
+        }
+        if let Some(archive) = &self.retention_archive_dir
+            && normalize(archive).starts_with(normalize(&self.output_dir))
+        {
+            return Err(format!(
+                "retention_archive_dir must be outside output_dir, got {}",
+                archive.display()
+            ));
+        }
+        if !self.stall_timeout_secs.is_finite() || self.stall_timeout_secs <= 0.0 {
+            return Err(format!("stall_timeout_secs must be > 0, got {}", self.stall_timeout_secs));
+        }
+        for (i, source) in self.sources.iter().enumerate() {
+            if source.label.is_empty() || source.label.contains(['/', '\\']) {
+                return Err(format!("source labels must be non-empty names without slashes, got \"{}\"", source.label));
+            }
+            if self.sources[..i].iter().any(|other| other.label == source.label) {
+                return Err(format!("source label \"{}\" is used twice", source.label));
+            }
+            if let Some(threshold) = source.threshold
+                && !(0.0..=1.0).contains(&threshold)
+            {
+                return Err(format!("threshold of source \"{}\" must be between 0.0 and 1.0, got {}", source.label, threshold));
+            }
+            if let (Some(threshold), Some(sustain)) = (source.threshold, self.sustain_threshold)
+                && sustain > threshold
+            {
+                return Err(format!(
+                    "sustain_threshold must be at most the threshold of source \"{}\" ({}), got {}",
+                    source.label, threshold, sustain
+                ));
+            }
+        }
+        for window in &self.schedule {
+            if let Some(profile) = &window.profile
+                && !self.profiles.contains_key(profile)
+            {
+                return Err(format!("schedule window {} uses unknown profile \"{}\"", window.describe(), profile));
+            }
+        }
+        for (name, profile) in &self.profiles {
+            let applied = Config { schedule: Vec::new(), profiles: BTreeMap::new(), ..profile.apply(self) };
+            applied.validate().map_err(|e| format!("profile \"{}\": {}", name, e))?;
+        }
+        if !self.level_log_secs.is_finite() || self.level_log_secs < 0.0 {
+            return Err(format!("level_log_secs must be >= 0, got {}", self.level_log_secs));
+        }
+        Ok(())
+    }
+
+    /// Check that the filters' and the band detector's frequencies are below Nyquist for audio
+    /// processed at `sample_rate`.
+    pub fn check_cutoffs(&self, sample_rate: u32) -> Result<(), String> {
+        let nyquist = sample_rate as f64 / 2.0;
+        let band = self.detector == DetectorKind::Band;
+        for (key, hz, applies) in [
+            ("filter_highpass_hz", self.filter_highpass_hz, true),
+            ("filter_notch_hz", self.filter_notch_hz, true),
+            ("filter_bandpass_low_hz", self.filter_bandpass_low_hz, true),
+            ("filter_bandpass_high_hz", self.filter_bandpass_high_hz, true),
+            ("band_low_hz", self.band_low_hz, band),
+            ("band_high_hz", self.band_high_hz, band),
+        ] {
+            if applies && hz >= nyquist {
+                return Err(format!(
+                    "{} must be below {} Hz, half the {} Hz sample rate, got {}",
+                    key, nyquist, sample_rate, hz
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// The config each source records with, by label. Without `sources` that's just this config,
+    /// with an empty label.
+    pub fn per_source(&self) -> Vec<(String, Config)> {
+        if self.sources.is_empty() {
+            return vec![(String::new(), self.clone())];
+        }
+        self.sources
+            .iter()
+            .map(|source| {
+                let mut config = Config { device: source.device.clone(), sources: Vec::new(), ..self.clone() };
+                if let Some(threshold) = source.threshold {
+                    config.threshold = threshold;
+                    config.auto_threshold = false;
+                }
+                let subdir = source.output_subdir.clone().unwrap_or_else(|| PathBuf::from(&source.label));
+                config.output_dir = self.output_dir.join(subdir);
+                (source.label.clone(), config)
+            })
+            .collect()
+    }
+
+    /// The filter chain in front of the detector, `None` if every stage is off.
+    pub fn filter_settings(&self) -> Option<FilterSettings> {
+        let settings = FilterSettings {
+            highpass_hz: self.filter_highpass_hz,
+            notch_hz: self.filter_notch_hz,
+            notch_harmonics: self.filter_notch_harmonics,
+            notch_q: self.filter_notch_q,
+            bandpass_low_hz: self.filter_bandpass_low_hz,
+            bandpass_high_hz: self.filter_bandpass_high_hz,
+            recorded: self.filter_recording,
+        };
+        let any = settings.highpass_hz > 0.0 || settings.notch_hz > 0.0 || settings.bandpass_low_hz > 0.0;
+        any.then_some(settings)
+    }
+
+    /// Whether the schedule has the recorder armed at local time `now`. The first matching
+    /// window wins.
+    pub fn arming_at(&self, now: NaiveDateTime) -> Arming {
+        if self.schedule.is_empty() {
+            return Arming::Always;
+        }
+        match self.schedule.iter().position(|window| window.contains(now)) {
+            Some(index) => Arming::Window(index),
+            None => Arming::Disarmed,
+        }
+    }
+
+    /// The config to record with while armed by `arming`, with the window's profile applied.
+    pub fn armed_with(&self, arming: Arming) -> Config {
+        let profile = match arming {
+            Arming::Window(index) => self.schedule[index].profile.as_ref().map(|name| &self.profiles[name]),
+            _ => None,
+        };
+        match profile {
+            Some(profile) => profile.apply(self),
+            None => self.clone(),
+        }
+    }
+
+    /// `None` when the session should run until stopped.
+    pub fn session_length(&self) -> Option<Duration> {
+        (self.session_minutes > 0).then(|| Duration::from_secs(self.session_minutes * 60))
+    }
+
+    /// The config as it would appear in a config file, for printing at startup.
+    pub fn to_toml(&self) -> String {
+        toml::to_string_pretty(self).expect("Config is always serializable")
+    }
+}
+
+/// `path` made absolute, with `.` and `..` resolved the way they read, so two spellings of a
+/// directory compare equal. Symlinks aren't followed, the directories needn't exist yet.
+fn normalize(path: &Path) -> PathBuf {
+    let mut normal = PathBuf::new();
+    for component in path::absolute(path).unwrap_or_else(|_| path.to_path_buf()).components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normal.pop();
+            }
+            other => normal.push(other),
+        }
+    }
+    normal
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    #[derive(Parser)]
+    struct Cli {
+        #[command(flatten)]
+        args: ConfigArgs,
+    }
+
+    /// Load `toml` as the config file, with `flags` on the command line.
+    fn load(toml: &str, flags: &[&str]) -> Result<Config, String> {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(DEFAULT_CONFIG_FILE);
+        fs::write(&path, toml).unwrap();
+        let config_flag = ["--config", path.to_str().unwrap()];
+        let argv = ["ranger-recorder-rs"].iter().chain(&config_flag).chain(flags);
+        Config::load(&Cli::try_parse_from(argv).unwrap().args)
+    }
+
+    #[test]
+    fn file_overrides_defaults_and_flags_override_the_file() {
+        let config = load("threshold = 0.2\nsilence_timeout_secs = 3.0\nclip_format = \"flac\"\n", &["-s", "7"]).unwrap();
+        assert_eq!(config.threshold, 0.2);
+        assert_eq!(config.silence_timeout_secs, 7.0);
+        assert_eq!(config.clip_format, ClipFormat::Flac);
+        assert_eq!(config.pre_roll_secs, Config::default().pre_roll_secs);
+    }
+
+    #[test]
+    fn threshold_flag_overrides_auto_threshold_from_the_file() {
+        let config = load("auto_threshold = true\n", &[]).unwrap();
+        assert!(config.auto_threshold);
+        let config = load("auto_threshold = true\n", &["--threshold", "0.3"]).unwrap();
+        assert!(!config.auto_threshold);
+        assert_eq!(config.threshold, 0.3);
+    }
+
+    #[test]
+    fn unknown_keys_are_rejected() {
+        let error = load("thresold = 0.2\n", &[]).unwrap_err();
+        assert!(error.contains("unknown field `thresold`"), "{}", error);
+    }
+
+    #[test]
+    fn invalid_values_are_reported() {
+        assert_eq!(load("threshold = 1.5\n", &[]).unwrap_err(), "threshold must be between 0.0 and 1.0, got 1.5");
+        assert_eq!(load("", &["--threshold", "2"]).unwrap_err(), "threshold must be between 0.0 and 1.0, got 2");
+        assert_eq!(
+            load("downmix_weights = [1.0, 0.0]\n", &[]).unwrap_err(),
+            "downmix_weights only applies with downmix = true (--downmix)",
+        );
+    }
+
+    #[test]
+    fn auto_threshold_settings_are_checked() {
+        assert_eq!(load("auto_margin_db = 0.0\n", &[]).unwrap_err(), "auto_margin_db must be > 0, got 0");
+        assert_eq!(load("auto_margin_db = nan\n", &[]).unwrap_err(), "auto_margin_db must be > 0, got NaN");
+        assert_eq!(load("noise_floor_log_secs = -1.0\n", &[]).unwrap_err(), "noise_floor_log_secs must be >= 0, got -1");
+        assert!(load("noise_floor_log_secs = inf\n", &[]).is_err());
+        assert!(load("noise_floor_log_secs = 0.0\n", &[]).is_ok());
+    }
+
+    #[test]
+    fn sustain_must_not_be_above_the_start_threshold() {
+        assert!(load("threshold = 0.2\nsustain_threshold = 0.1\n", &[]).is_ok());
+        assert!(load("threshold = 0.2\nsustain_threshold = 0.2\n", &[]).is_ok());
+        assert_eq!(
+            load("threshold = 0.2\nsustain_threshold = 0.3\n", &[]).unwrap_err(),
+            "sustain_threshold must be at most threshold (0.2), got 0.3",
+        );
+        // Lowering the threshold on the command line below the file's sustain
+        assert!(load("threshold = 0.2\nsustain_threshold = 0.15\n", &["-t", "0.1"]).is_err());
+        assert!(load("sustain_threshold = 0.04\n[[sources]]\nlabel = \"a\"\ndevice = \"x\"\nthreshold = 0.03\n", &[]).is_err());
+
+        assert!(load("auto_margin_db = 20.0\nauto_sustain_margin_db = 14.0\n", &[]).is_ok());
+        assert_eq!(
+            load("auto_margin_db = 20.0\nauto_sustain_margin_db = 24.0\n", &[]).unwrap_err(),
+            "auto_sustain_margin_db must be > 0 and at most auto_margin_db (20), got 24",
+        );
+    }
+
+    #[test]
+    fn retention_and_free_space_limits_are_bounded() {
+        assert!(load("retention_max_age_days = 36500.0\n", &[]).is_ok());
+        assert_eq!(
+            load("retention_max_age_days = 36501.0\n", &[]).unwrap_err(),
+            "retention_max_age_days must be 0 - 36500, got 36501",
+        );
+        assert!(load("retention_max_age_days = 1e300\n", &[]).is_err());
+        assert!(load("retention_max_age_days = -1.0\n", &[]).is_err());
+        assert!(load("retention_max_age_days = nan\n", &[]).is_err());
+        assert!(load("retention_max_mb = 17592186044415\n", &[]).is_ok());
+        assert_eq!(
+            load("retention_max_mb = 17592186044416\n", &[]).unwrap_err(),
+            "retention_max_mb must be at most 17592186044415, got 17592186044416",
+        );
+        assert!(load("", &["--min-free", "18446744073709551615"]).is_err());
+    }
+
+    #[test]
+    fn the_archive_must_be_outside_the_output_dir_however_it_is_spelled() {
+        for (output_dir, archive) in [
+            ("barks", "barks/archive"),
+            ("barks", "./barks/archive"),
+            ("./barks", "barks/old/../archive"),
+            ("barks/", "barks"),
+        ] {
+            let toml = format!("output_dir = \"{}\"\nretention_archive_dir = \"{}\"\n", output_dir, archive);
+            assert!(load(&toml, &[]).is_err(), "{} in {}", archive, output_dir);
+        }
+        assert!(load("output_dir = \"./barks\"\nretention_archive_dir = \"barks-archive\"\n", &[]).is_ok());
+        assert!(load("output_dir = \"barks\"\nretention_archive_dir = \"../barks\"\n", &[]).is_ok());
+    }
+
+    #[test]
+    fn filter_cutoffs_must_be_below_nyquist() {
+        assert_eq!(
+            load("resample_hz = 16000\nfilter_bandpass_low_hz = 400.0\nfilter_bandpass_high_hz = 8000.0\n", &[]).unwrap_err(),
+            "filter_bandpass_high_hz must be below 8000 Hz, half the 16000 Hz sample rate, got 8000",
+        );
+        assert!(load("sample_rate = 8000\nfilter_highpass_hz = 4500.0\n", &[]).is_err());
+        // Resampling decides the rate the filters run at, not the capture rate
+        assert!(load("sample_rate = 48000\nresample_hz = 8000\nfilter_highpass_hz = 5000.0\n", &[]).is_err());
+        assert!(load("sample_rate = 8000\nresample_hz = 48000\nfilter_highpass_hz = 5000.0\n", &[]).is_ok());
+        // The band detector's band only counts when it's the detector in use
+        assert!(load("sample_rate = 8000\n", &[]).is_ok());
+        assert!(load("sample_rate = 8000\ndetector = \"band\"\n", &[]).is_err());
+        // Without a fixed rate, it's checked against the device's once it's open
+        let config = load("filter_notch_hz = 60.0\n", &[]).unwrap();
+        assert!(config.check_cutoffs(8000).is_ok());
+        assert!(config.check_cutoffs(100).is_err());
+    }
+}
@@ -0,0 +1,239 @@
+use chrono::{DateTime, FixedOffset};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+/// Session records are appended here by the recorder, one JSON object per line.
+pub const SESSION_LOG_FILE: &str = "sessions.jsonl";
+
+/// A stretch of time the recorder was listening, rebuilt from the session log.
+#[derive(Clone, Debug)]
+pub struct Session {
+    pub id: String,
+    pub start: DateTime<FixedOffset>,
+    /// The end record, or the last record of any kind if the recorder never wrote one
+    pub end: DateTime<FixedOffset>,
+    /// Why the session ended, `None` if it was killed or is still running
+    pub end_reason: Option<String>,
+    /// Input devices, prefixed with their source label when there are several
+    pub devices: Vec<String>,
+    pub stream_errors: Vec<(DateTime<FixedOffset>, String)>,
+    /// Times an input was lost during the session
+    pub outages: Vec<Outage>,
+    /// Stretches outside the schedule, when nothing was recording on purpose
+    pub disarmed: Vec<(DateTime<FixedOffset>, DateTime<FixedOffset>)>,
+}
+
+/// A stretch of a session during which one of its inputs wasn't recording.
+#[derive(Clone, Debug)]
+pub struct Outage {
+    /// Label of the input, empty when only one is recorded
+    pub source: String,
+    pub start: DateTime<FixedOffset>,
+    /// When it was restored, or the end of the session if it never was
+    pub end: DateTime<FixedOffset>,
+    pub reason: String,
+}
+
+/// The fields of a session record the viewer cares about, the rest are ignored.
+#[derive(Deserialize)]
+struct Record {
+    session: String,
+    time: DateTime<FixedOffset>,
+    event: String,
+    source: Option<String>,
+    device: Option<String>,
+    reason: Option<String>,
+    message: Option<String>,
+}
+
+/// Read the session log in `dir`, oldest session first. Missing logs and unreadable lines are
+/// skipped, so an empty list means no coverage is known.
+pub fn read_sessions(dir: &Path) -> Vec<Session> {
+    let Ok(log) = fs::read_to_string(dir.join(SESSION_LOG_FILE)) else {
+        return Vec::new();
+    };
+    let mut sessions: BTreeMap<String, Session> = BTreeMap::new();
+    // Index of the outage still waiting for its restored record, per session and source
+    let mut open_outages: BTreeMap<(String, String), usize> = BTreeMap::new();
+    // When each session was last disarmed, until it's armed again
+    let mut disarmed_since: BTreeMap<String, DateTime<FixedOffset>> = BTreeMap::new();
+    for record in log.lines().filter_map(|line| serde_json::from_str::<Record>(line).ok()) {
+        let session = sessions.entry(record.session.clone()).or_insert_with(|| Session {
+            id: record.session,
+            start: record.time,
+            end: record.time,
+            end_reason: None,
+            devices: Vec::new(),
+            stream_errors: Vec::new(),
+            outages: Vec::new(),
+            disarmed: Vec::new(),
+        });
+        session.start = session.start.min(record.time);
+        session.end = session.end.max(record.time);
+        let labelled = |text: String| match record.source.as_deref() {
+            Some(source) if !source.is_empty() => format!("{}: {}", source, text),
+            _ => text,
+        };
+        match record.event.as_str() {
+            "start" => {
+                // Reopened inputs start again, usually on the device they were already listed with
+                if let Some(device) = record.device.map(labelled)
+                    && !session.devices.contains(&device)
+                {
+                    session.devices.push(device);
+                }
+            }
+            "end" => session.end_reason = record.reason,
+            "stream_error" => session.stream_errors.push((record.time, labelled(record.message.unwrap_or_default()))),
+            "outage" => {
+                open_outages.insert((session.id.clone(), record.source.clone().unwrap_or_default()), session.outages.len());
+                session.outages.push(Outage {
+                    source: record.source.unwrap_or_default(),
+                    start: record.time,
+                    end: record.time,
+                    reason: record.reason.unwrap_or_default(),
+                });
+            }
+            "disarmed" => {
+                disarmed_since.entry(session.id.clone()).or_insert(record.time);
+            }
+            "armed" => {
+                if let Some(since) = disarmed_since.remove(&session.id) {
+                    session.disarmed.push((since, record.time));
+                }
+            }
+            "restored" => {
+                if let Some(index) = open_outages.remove(&(session.id.clone(), record.source.unwrap_or_default())) {
+                    session.outages[index].end = record.time;
+                }
+            }
+            _ => {}
+        }
+    }
+    // Inputs that never came back were down until the session ended, and the same goes for
+    // sessions that ended disarmed
+    for ((id, _), index) in open_outages {
+        let session = sessions.get_mut(&id).expect("outages belong to a known session");
+        session.outages[index].end = session.end;
+    }
+    for (id, since) in disarmed_since {
+        let session = sessions.get_mut(&id).expect("disarmed records belong to a known session");
+        session.disarmed.push((since, session.end));
+    }
+    let mut sessions: Vec<Session> = sessions.into_values().collect();
+    sessions.sort_by_key(|s| s.start);
+    sessions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time(s: &str) -> DateTime<FixedOffset> {
+        DateTime::parse_from_rfc3339(s).unwrap()
+    }
+
+    fn read(log: &str) -> Vec<Session> {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join(SESSION_LOG_FILE), log).unwrap();
+        read_sessions(dir.path())
+    }
+
+    #[test]
+    fn sessions_are_rebuilt_from_their_records() {
+        let sessions = read(concat!(
+            r#"{"session":"b","time":"2024-05-01T20:00:00+02:00","event":"start","source":"","device":"usb","config":{}}"#,
+            "\n",
+            r#"{"session":"a","time":"2024-05-01T18:00:00+02:00","event":"start","source":"yard","device":"mic"}"#,
+            "\n",
+            r#"{"session":"a","time":"2024-05-01T18:00:00+02:00","event":"start","source":"porch","device":"usb"}"#,
+            "\n",
+            r#"{"session":"a","time":"2024-05-01T18:10:00+02:00","event":"stream_error","source":"yard","message":"xrun"}"#,
+            "\n",
+            r#"{"session":"a","time":"2024-05-01T18:20:00+02:00","event":"outage","source":"yard","reason":"stalled"}"#,
+            "\n",
+            "not json\n",
+            r#"{"session":"a","time":"2024-05-01T18:21:00+02:00","event":"restored","source":"yard","device":"mic","outage_secs":60.0}"#,
+            "\n",
+            r#"{"session":"a","time":"2024-05-01T18:30:00+02:00","event":"disarmed"}"#,
+            "\n",
+            r#"{"session":"a","time":"2024-05-01T18:40:00+02:00","event":"armed","window":"18:40-23:00"}"#,
+            "\n",
+            r#"{"session":"a","time":"2024-05-01T19:00:00+02:00","event":"end","reason":"stopped"}"#,
+            "\n",
+            // Killed: heartbeats but no end record, and an outage that never closed
+            r#"{"session":"b","time":"2024-05-01T20:01:00+02:00","event":"heartbeat"}"#,
+            "\n",
+            r#"{"session":"b","time":"2024-05-01T20:02:00+02:00","event":"outage","source":"","reason":"device lost"}"#,
+            "\n",
+            r#"{"session":"b","time":"2024-05-01T20:05:00+02:00","event":"heartbeat"}"#,
+            "\n",
+        ));
+        assert_eq!(sessions.len(), 2);
+
+        let a = &sessions[0];
+        assert_eq!(a.id, "a");
+        assert_eq!((a.start, a.end), (time("2024-05-01T18:00:00+02:00"), time("2024-05-01T19:00:00+02:00")));
+        assert_eq!(a.end_reason.as_deref(), Some("stopped"));
+        assert_eq!(a.devices, ["yard: mic", "porch: usb"]);
+        assert_eq!(a.stream_errors, [(time("2024-05-01T18:10:00+02:00"), "yard: xrun".to_string())]);
+        assert_eq!(a.outages.len(), 1);
+        let outage = &a.outages[0];
+        assert_eq!((outage.source.as_str(), outage.reason.as_str()), ("yard", "stalled"));
+        assert_eq!((outage.start, outage.end), (time("2024-05-01T18:20:00+02:00"), time("2024-05-01T18:21:00+02:00")));
+        assert_eq!(a.disarmed, [(time("2024-05-01T18:30:00+02:00"), time("2024-05-01T18:40:00+02:00"))]);
+
+        let b = &sessions[1];
+        assert_eq!(b.id, "b");
+        assert_eq!(b.end, time("2024-05-01T20:05:00+02:00"));
+        assert_eq!(b.end_reason, None);
+        assert_eq!(b.devices, ["usb"]);
+        assert_eq!(b.outages.len(), 1);
+        assert_eq!((b.outages[0].start, b.outages[0].end), (time("2024-05-01T20:02:00+02:00"), b.end));
+    }
+
+    #[test]
+    fn reopened_inputs_list_their_device_once() {
+        let sessions = read(concat!(
+            r#"{"session":"a","time":"2024-05-01T18:00:00+02:00","event":"start","source":"yard","device":"mic"}"#,
+            "\n",
+            // Couldn't be opened at first, so its start comes with the restore
+            r#"{"session":"a","time":"2024-05-01T18:00:00+02:00","event":"outage","source":"porch","reason":"no device"}"#,
+            "\n",
+            r#"{"session":"a","time":"2024-05-01T18:10:00+02:00","event":"outage","source":"yard","reason":"stalled"}"#,
+            "\n",
+            r#"{"session":"a","time":"2024-05-01T18:11:00+02:00","event":"restored","source":"yard","device":"mic","outage_secs":60.0}"#,
+            "\n",
+            r#"{"session":"a","time":"2024-05-01T18:11:00+02:00","event":"start","source":"yard","device":"mic","sample_rate":44100}"#,
+            "\n",
+            r#"{"session":"a","time":"2024-05-01T18:12:00+02:00","event":"restored","source":"porch","device":"usb","outage_secs":720.0}"#,
+            "\n",
+            r#"{"session":"a","time":"2024-05-01T18:12:00+02:00","event":"start","source":"porch","device":"usb","sample_rate":48000}"#,
+            "\n",
+        ));
+        let a = &sessions[0];
+        assert_eq!(a.devices, ["yard: mic", "porch: usb"]);
+        assert_eq!(a.outages.len(), 2);
+        assert!(a.outages.iter().all(|outage| outage.end > outage.start));
+    }
+
+    #[test]
+    fn a_session_that_ends_disarmed_is_disarmed_until_its_end() {
+        let sessions = read(concat!(
+            r#"{"session":"a","time":"2024-05-01T18:00:00+02:00","event":"start","device":"mic"}"#,
+            "\n",
+            r#"{"session":"a","time":"2024-05-01T23:00:00+02:00","event":"disarmed"}"#,
+            "\n",
+            r#"{"session":"a","time":"2024-05-02T01:00:00+02:00","event":"end","reason":"stopped"}"#,
+            "\n",
+        ));
+        assert_eq!(sessions[0].disarmed, [(time("2024-05-01T23:00:00+02:00"), time("2024-05-02T01:00:00+02:00"))]);
+    }
+
+    #[test]
+    fn a_missing_log_has_no_sessions() {
+        assert!(read_sessions(tempfile::tempdir().unwrap().path()).is_empty());
+    }
+}
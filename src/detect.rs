@@ -0,0 +1,191 @@
+use chrono::{DateTime, Local, NaiveDateTime};
+use clap::Args;
+use std::path::PathBuf;
+
+use ranger_recorder_rs::naming::earliest_local;
+
+use crate::config::{Config, ResolvedFormat};
+use crate::preprocess::Preprocessor;
+use crate::recorder::{Input, Recorder};
+
+/// Frames fed to the recorder per step, roughly what an audio callback would deliver.
+const FRAMES_PER_BUFFER: usize = 1024;
+
+/// Segment an existing recording into bark clips, as if it had been heard live.
+#[derive(Args, Debug)]
+pub struct DetectArgs {
+    /// WAV file to scan
+    #[arg(short, long, value_name = "FILE")]
+    pub input: PathBuf,
+    /// Local time the recording started, e.g. "2024-05-01 18:30:00" or RFC 3339
+    #[arg(long, value_name = "TIME", value_parser = parse_start_time)]
+    pub start: DateTime<Local>,
+}
+
+fn parse_start_time(s: &str) -> Result<DateTime<Local>, String> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(s) {
+        return Ok(time.with_timezone(&Local));
+    }
+    let naive = NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S")
+        .or_else(|_| NaiveDateTime::parse_from_str(s, "%Y-%m-%dT%H:%M:%S"))
+        .map_err(|_| format!("expected \"YYYY-MM-DD HH:MM:SS\" or RFC 3339, got \"{}\"", s))?;
+    earliest_local(&naive)
+        .ok_or_else(|| format!("{} doesn't exist in the local time zone", s))
+}
+
+pub fn run(config: &Config, args: &DetectArgs) -> Result<(), String> {
+    let reader = hound::WavReader::open(&args.input)
+        .map_err(|e| format!("Failed to open {}: {}", args.input.display(), e))?;
+    let spec = reader.spec();
+    let channels = spec.channels as usize;
+    println!(
+        "Scanning {} ({} Hz, {} channels, {:.1}s) from {}",
+        args.input.display(),
+        spec.sample_rate,
+        channels,
+        reader.duration() as f64 / spec.sample_rate as f64,
+        args.start.format("%Y-%m-%d %H:%M:%S"),
+    );
+
+    let mut preprocessor = Preprocessor::new(config, channels, spec.sample_rate)?;
+    if let Some(processing) = preprocessor.describe() {
+        println!("Processing input: {}", processing);
+    }
+    let mut recorder = Recorder::new(config, Input {
+        source: String::new(),
+        device: args.input.display().to_string(),
+        session: format!("detect-{}", Local::now().format("%Y%m%dT%H%M%S%.3f")),
+        channels: preprocessor.channels(),
+        sample_rate: preprocessor.sample_rate(),
+        native_format: ResolvedFormat::for_wav_spec(spec),
+    });
+    let mut buffer = Vec::with_capacity(FRAMES_PER_BUFFER * channels);
+    let mut frames_read: u64 = 0;
+    let mut feed = |buffer: &mut Vec<f32>, recorder: &mut Recorder| {
+        let offset = chrono::Duration::microseconds(
+            (frames_read as f64 * 1_000_000.0 / spec.sample_rate as f64) as i64
+        );
+        let processed = preprocessor.process(buffer);
+        if !processed.is_empty() {
+            recorder.process(processed, args.start + offset);
+        }
+        frames_read += (buffer.len() / channels) as u64;
+        buffer.clear();
+    };
+
+    let samples: Box<dyn Iterator<Item = hound::Result<f32>>> = match spec.sample_format {
+        hound::SampleFormat::Float => Box::new(reader.into_samples::<f32>()),
+        hound::SampleFormat::Int => {
+            let scale = (1i64 << (spec.bits_per_sample - 1)) as f32;
+            Box::new(reader.into_samples::<i32>().map(move |s| s.map(|s| s as f32 / scale)))
+        }
+    };
+    for sample in samples {
+        buffer.push(sample.map_err(|e| format!("Failed to read {}: {}", args.input.display(), e))?);
+        if buffer.len() == FRAMES_PER_BUFFER * channels {
+            feed(&mut buffer, &mut recorder);
+        }
+    }
+    if !buffer.is_empty() {
+        feed(&mut buffer, &mut recorder);
+    }
+    recorder.finish();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use crate::config::WavFormat;
+    use ranger_recorder_rs::naming::ClipName;
+    use ranger_recorder_rs::sidecar::ClipMetadata;
+    use std::path::Path;
+
+    const RATE: u32 = 8000;
+
+    /// 2 s of quiet, a 0.2 s 500 Hz tone, then 3 s of quiet.
+    fn burst(frame: usize) -> f32 {
+        let t = frame as f32 / RATE as f32;
+        if (2.0..2.2).contains(&t) {
+            0.8 * (2.0 * std::f32::consts::PI * 500.0 * t).sin()
+        } else {
+            0.01 * (2.0 * std::f32::consts::PI * 50.0 * t).sin()
+        }
+    }
+
+    fn write_input(path: &Path, spec: hound::WavSpec) {
+        let mut writer = hound::WavWriter::create(path, spec).unwrap();
+        for frame in 0..(5.2 * RATE as f32) as usize {
+            for _ in 0..spec.channels {
+                match spec.sample_format {
+                    hound::SampleFormat::Float => writer.write_sample(burst(frame)).unwrap(),
+                    hound::SampleFormat::Int => writer.write_sample((burst(frame) * i16::MAX as f32) as i16).unwrap(),
+                }
+            }
+        }
+        writer.finalize().unwrap();
+    }
+
+    fn detect(spec: hound::WavSpec) -> (tempfile::TempDir, Vec<std::path::PathBuf>) {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("input.wav");
+        write_input(&input, spec);
+        let config = Config {
+            threshold: 0.5,
+            silence_timeout_secs: 1.0,
+            pre_roll_secs: 0.5,
+            level_log_secs: 0.0,
+            min_free_mb: 0,
+            wav_format: WavFormat::Native,
+            output_dir: dir.path().join("barks"),
+            ..Config::default()
+        };
+        let start = Local.with_ymd_and_hms(2024, 5, 1, 18, 30, 0).unwrap();
+        run(&config, &DetectArgs { input, start }).unwrap();
+        let clips = std::fs::read_dir(dir.path().join("barks"))
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "wav"))
+            .collect();
+        (dir, clips)
+    }
+
+    #[test]
+    fn finds_the_burst_in_a_wav_file() {
+        let (_dir, clips) = detect(hound::WavSpec {
+            channels: 1,
+            sample_rate: RATE,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        });
+        assert_eq!(clips.len(), 1);
+        let name = ClipName::parse(clips[0].file_name().unwrap().to_str().unwrap()).unwrap();
+        // The tone starts in the buffer at frame 15360 (1.92 s), with 0.5 s of pre-roll
+        let start = Local.with_ymd_and_hms(2024, 5, 1, 18, 30, 1).unwrap() + chrono::Duration::milliseconds(420);
+        assert_eq!(name.start, start.fixed_offset());
+
+        let metadata = ClipMetadata::read(&clips[0]).unwrap();
+        assert_eq!(metadata.start, name.start);
+        assert_eq!(metadata.sample_rate, RATE);
+        let reader = hound::WavReader::open(&clips[0]).unwrap();
+        assert_eq!(reader.spec().bits_per_sample, 16);
+        assert_eq!(reader.duration() as u64, (metadata.duration_secs() * RATE as f64).round() as u64);
+    }
+
+    #[test]
+    fn reads_float_stereo_files_in_their_own_format() {
+        let (_dir, clips) = detect(hound::WavSpec {
+            channels: 2,
+            sample_rate: RATE,
+            bits_per_sample: 32,
+            sample_format: hound::SampleFormat::Float,
+        });
+        assert_eq!(clips.len(), 1);
+        let reader = hound::WavReader::open(&clips[0]).unwrap();
+        assert_eq!(reader.spec().channels, 2);
+        assert_eq!(reader.spec().sample_format, hound::SampleFormat::Float);
+        let peak = reader.into_samples::<f32>().map(|s| s.unwrap().abs()).fold(0.0, f32::max);
+        assert!((peak - 0.8).abs() < 0.01, "peak {}", peak);
+    }
+}
@@ -0,0 +1,251 @@
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+
+use crate::config::Config;
+use crate::filter::Biquad;
+
+/// Which `BarkDetector` the recorder uses.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum DetectorKind {
+    /// Loudest single sample in the buffer
+    Peak,
+    /// RMS over a sliding window
+    Rms,
+    /// RMS over a sliding window, after band-limiting to where barks sit
+    Band,
+}
+
+impl DetectorKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            DetectorKind::Peak => "peak",
+            DetectorKind::Rms => "rms",
+            DetectorKind::Band => "band",
+        }
+    }
+}
+
+/// Outcome of feeding one buffer to a detector.
+#[derive(Clone, Copy, Debug)]
+pub struct Detection {
+    pub triggered: bool,
+    /// Level the detector compared against its threshold, in linear amplitude
+    pub score: f32,
+}
+
+/// Decides whether a buffer of audio sounds like a bark.
+///
+/// Detectors are fed every buffer in order, so they can keep state across buffer boundaries.
+pub trait BarkDetector: Send {
+    /// Inspect one buffer of interleaved samples.
+    fn process(&mut self, data: &[f32]) -> Detection;
+
+    /// Change the score that counts as a trigger, e.g. as the noise floor moves.
+    fn set_threshold(&mut self, threshold: f32);
+
+    fn threshold(&self) -> f32;
+}
+
+/// Build the detector selected in `config`.
+pub fn from_config(config: &Config, channels: usize, sample_rate: u32) -> Box<dyn BarkDetector> {
+    let threshold = config.threshold as f32;
+    let window = ((sample_rate as f64 * config.detector_window_ms / 1000.0) as usize).max(1);
+    match config.detector {
+        DetectorKind::Peak => Box::new(PeakDetector { threshold }),
+        DetectorKind::Rms => Box::new(RmsDetector {
+            threshold,
+            rms: MovingRms::new(window * channels),
+        }),
+        DetectorKind::Band => Box::new(BandEnergyDetector::new(
+            threshold,
+            channels,
+            sample_rate,
+            window,
+            config.band_low_hz,
+            config.band_high_hz,
+        )),
+    }
+}
+
+/// Triggers when any sample's absolute value crosses the threshold.
+pub struct PeakDetector {
+    threshold: f32,
+}
+
+impl BarkDetector for PeakDetector {
+    fn process(&mut self, data: &[f32]) -> Detection {
+        let score = data.iter().map(|x| x.abs()).fold(0.0, f32::max);
+        Detection { triggered: score > self.threshold, score }
+    }
+
+    fn set_threshold(&mut self, threshold: f32) {
+        self.threshold = threshold;
+    }
+
+    fn threshold(&self) -> f32 {
+        self.threshold
+    }
+}
+
+/// Root mean square over the last `len` samples, updated one sample at a time.
+struct MovingRms {
+    squares: VecDeque<f32>,
+    len: usize,
+    sum: f64,
+}
+
+impl MovingRms {
+    fn new(len: usize) -> Self {
+        Self {
+            squares: VecDeque::with_capacity(len),
+            len,
+            sum: 0.0,
+        }
+    }
+
+    fn push(&mut self, sample: f32) -> f32 {
+        let square = sample * sample;
+        if self.squares.len() == self.len {
+            self.sum -= self.squares.pop_front().unwrap_or(0.0) as f64;
+        }
+        self.squares.push_back(square);
+        self.sum = (self.sum + square as f64).max(0.0);
+        (self.sum / self.len as f64).sqrt() as f32
+    }
+}
+
+/// Triggers when the windowed RMS level crosses the threshold. Ignores isolated clicks that the
+/// peak detector would fire on.
+pub struct RmsDetector {
+    threshold: f32,
+    rms: MovingRms,
+}
+
+impl BarkDetector for RmsDetector {
+    fn process(&mut self, data: &[f32]) -> Detection {
+        let score = data.iter().map(|&x| self.rms.push(x)).fold(0.0, f32::max);
+        Detection { triggered: score > self.threshold, score }
+    }
+
+    fn set_threshold(&mut self, threshold: f32) {
+        self.threshold = threshold;
+    }
+
+    fn threshold(&self) -> f32 {
+        self.threshold
+    }
+}
+
+/// Windowed RMS of the signal after a high-pass and low-pass, so rumble and hiss outside the
+/// band don't count toward the level.
+pub struct BandEnergyDetector {
+    threshold: f32,
+    channels: usize,
+    // One high-pass/low-pass pair per channel
+    filters: Vec<(Biquad, Biquad)>,
+    rms: MovingRms,
+}
+
+impl BandEnergyDetector {
+    fn new(threshold: f32, channels: usize, sample_rate: u32, window: usize, low_hz: f64, high_hz: f64) -> Self {
+        let q = std::f64::consts::FRAC_1_SQRT_2;
+        let filters = (0..channels)
+            .map(|_| (Biquad::high_pass(sample_rate, low_hz, q), Biquad::low_pass(sample_rate, high_hz, q)))
+            .collect();
+        Self {
+            threshold,
+            channels,
+            filters,
+            rms: MovingRms::new(window * channels),
+        }
+    }
+}
+
+impl BarkDetector for BandEnergyDetector {
+    fn process(&mut self, data: &[f32]) -> Detection {
+        let mut score = 0.0f32;
+        for frame in data.chunks(self.channels) {
+            for (sample, (high_pass, low_pass)) in frame.iter().zip(self.filters.iter_mut()) {
+                let filtered = low_pass.process(high_pass.process(*sample));
+                score = score.max(self.rms.push(filtered));
+            }
+        }
+        Detection { triggered: score > self.threshold, score }
+    }
+
+    fn set_threshold(&mut self, threshold: f32) {
+        self.threshold = threshold;
+    }
+
+    fn threshold(&self) -> f32 {
+        self.threshold
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_signal::sine;
+
+    const RATE: u32 = 16_000;
+
+    fn detector(kind: DetectorKind, threshold: f64) -> Box<dyn BarkDetector> {
+        let config = Config { detector: kind, threshold, detector_window_ms: 50.0, ..Config::default() };
+        from_config(&config, 1, RATE)
+    }
+
+    /// Feed a second of a half-scale sine in 0.1 s buffers, returning the last buffer's detection.
+    fn feed_tone(detector: &mut dyn BarkDetector, freq: f32) -> Detection {
+        let tone = sine(freq, RATE, RATE as usize);
+        tone.chunks(RATE as usize / 10).map(|buffer| detector.process(buffer)).last().unwrap()
+    }
+
+    #[test]
+    fn peak_scores_the_loudest_sample() {
+        let detection = feed_tone(detector(DetectorKind::Peak, 0.3).as_mut(), 1000.0);
+        assert!(detection.triggered);
+        assert!((detection.score - 0.5).abs() < 1e-3, "{}", detection.score);
+        assert!(!feed_tone(detector(DetectorKind::Peak, 0.6).as_mut(), 1000.0).triggered);
+        assert!(detector(DetectorKind::Peak, 0.3).process(&[0.1, -0.35, 0.2]).triggered);
+    }
+
+    #[test]
+    fn rms_scores_the_windowed_level() {
+        let detection = feed_tone(detector(DetectorKind::Rms, 0.3).as_mut(), 1000.0);
+        assert!(detection.triggered);
+        assert!((detection.score - 0.5 / 2f32.sqrt()).abs() < 0.01, "{}", detection.score);
+        // Above the RMS but below the peak
+        assert!(!feed_tone(detector(DetectorKind::Rms, 0.4).as_mut(), 1000.0).triggered);
+        // A single click in a quiet buffer barely moves it
+        let mut click = vec![0.0; 800];
+        click[400] = 0.9;
+        assert!(!detector(DetectorKind::Rms, 0.3).process(&click).triggered);
+    }
+
+    #[test]
+    fn moving_rms_window_carries_across_buffers() {
+        let mut rms = RmsDetector { threshold: 0.9, rms: MovingRms::new(100) };
+        // Half a window of full scale fills it halfway
+        assert!((rms.process(&[1.0; 50]).score - 0.5f32.sqrt()).abs() < 1e-6);
+        let full = rms.process(&[1.0; 50]);
+        assert!(full.triggered && (full.score - 1.0).abs() < 1e-6);
+        // The loud samples of the earlier buffers are still in the window as silence comes in
+        let fading = rms.process(&[0.0; 50]);
+        assert!(fading.triggered && fading.score > 0.99, "{}", fading.score);
+        assert!(!rms.process(&[0.0; 100]).triggered);
+    }
+
+    #[test]
+    fn band_ignores_energy_outside_the_band() {
+        // Band is 400 - 4000 Hz by default
+        let in_band = feed_tone(detector(DetectorKind::Band, 0.2).as_mut(), 1000.0);
+        assert!(in_band.triggered);
+        assert!((in_band.score - 0.5 / 2f32.sqrt()).abs() < 0.03, "{}", in_band.score);
+        for freq in [50.0, 7000.0] {
+            let out_of_band = feed_tone(detector(DetectorKind::Band, 0.2).as_mut(), freq);
+            assert!(!out_of_band.triggered, "{} Hz scored {}", freq, out_of_band.score);
+        }
+    }
+}
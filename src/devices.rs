@@ -0,0 +1,120 @@
+use cpal::traits::{DeviceTrait, HostTrait};
+use cpal::{SampleRate, SupportedStreamConfig};
+
+use crate::config::Config;
+
+/// The host, input device and stream config picked from the config.
+pub struct Selection {
+    pub host: cpal::HostId,
+    pub device: cpal::Device,
+    pub stream_config: SupportedStreamConfig,
+}
+
+/// Print every audio host, its input devices and the configs they support, numbered the way
+/// `device` indexes them.
+pub fn list() {
+    let default_host = cpal::default_host().id();
+    for host_id in cpal::available_hosts() {
+        let default = if host_id == default_host { " (default)" } else { "" };
+        println!("Host: {}{}", host_id.name(), default);
+        let host = match cpal::host_from_id(host_id) {
+            Ok(host) => host,
+            Err(e) => {
+                println!("  unavailable: {}", e);
+                continue;
+            }
+        };
+        let default_device = host.default_input_device().and_then(|d| d.name().ok());
+        let devices = match host.input_devices() {
+            Ok(devices) => devices,
+            Err(e) => {
+                println!("  failed to list input devices: {}", e);
+                continue;
+            }
+        };
+        for (index, device) in devices.enumerate() {
+            let name = device.name().unwrap_or_else(|_| "<unnamed>".to_string());
+            let default = if default_device.as_ref() == Some(&name) { " (default)" } else { "" };
+            println!("  [{}] {}{}", index, name, default);
+            if let Ok(config) = device.default_input_config() {
+                println!(
+                    "      default: {} ch, {} Hz, {}",
+                    config.channels(),
+                    config.sample_rate().0,
+                    config.sample_format()
+                );
+            }
+            match device.supported_input_configs() {
+                Ok(configs) => {
+                    for config in configs {
+                        println!(
+                            "      {} ch, {} - {} Hz, {}",
+                            config.channels(),
+                            config.min_sample_rate().0,
+                            config.max_sample_rate().0,
+                            config.sample_format()
+                        );
+                    }
+                }
+                Err(e) => println!("      failed to list configs: {}", e),
+            }
+        }
+    }
+}
+
+/// Find the host and input device named in `config` and a stream config with the requested
+/// sample rate and channel count, falling back to the device's defaults for whatever isn't set.
+pub fn select(config: &Config) -> Result<Selection, String> {
+    let host = if config.host.is_empty() {
+        cpal::default_host()
+    } else {
+        let id = cpal::available_hosts()
+            .into_iter()
+            .find(|id| id.name().eq_ignore_ascii_case(&config.host))
+            .ok_or_else(|| format!("No audio host named \"{}\", see list-devices", config.host))?;
+        cpal::host_from_id(id).map_err(|e| format!("Audio host {} is unavailable: {}", id.name(), e))?
+    };
+
+    let device = if config.device.is_empty() {
+        host.default_input_device()
+            .ok_or_else(|| format!("{} has no default input device", host.id().name()))?
+    } else {
+        let mut devices = host.input_devices().map_err(|e| format!("Failed to list input devices: {}", e))?;
+        match config.device.parse::<usize>() {
+            Ok(index) => devices.nth(index)
+                .ok_or_else(|| format!("No input device [{}], see list-devices", index))?,
+            Err(_) => devices.find(|d| d.name().is_ok_and(|name| name.contains(&config.device)))
+                .ok_or_else(|| format!("No input device matching \"{}\", see list-devices", config.device))?,
+        }
+    };
+
+    let default = device.default_input_config()
+        .map_err(|e| format!("Failed to get default input config: {}", e))?;
+    if config.sample_rate == 0 && config.channels == 0 {
+        return Ok(Selection { host: host.id(), device, stream_config: default });
+    }
+
+    let channels = if config.channels > 0 { config.channels } else { default.channels() };
+    let sample_rate = if config.sample_rate > 0 { config.sample_rate } else { default.sample_rate().0 };
+    let mut candidates: Vec<_> = device.supported_input_configs()
+        .map_err(|e| format!("Failed to list input configs: {}", e))?
+        .filter(|c| {
+            c.channels() == channels
+                && c.min_sample_rate().0 <= sample_rate
+                && sample_rate <= c.max_sample_rate().0
+        })
+        .collect();
+    // Keep the device's own sample format if it's on offer
+    candidates.sort_by_key(|c| c.sample_format() != default.sample_format());
+    let stream_config = candidates
+        .into_iter()
+        .next()
+        .ok_or_else(|| format!(
+            "{} doesn't support {} channels at {} Hz, see list-devices",
+            device.name().unwrap_or_default(),
+            channels,
+            sample_rate
+        ))?
+        .with_sample_rate(SampleRate(sample_rate));
+    Ok(Selection { host: host.id(), device, stream_config })
+}
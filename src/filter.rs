@@ -0,0 +1,217 @@
+use std::f64::consts::{FRAC_1_SQRT_2, PI};
+
+use ranger_recorder_rs::sidecar::FilterSettings;
+
+/// How many time constants a stage's start-up transient is given to decay, about 60 dB.
+const SETTLE_TIME_CONSTANTS: f64 = 6.9;
+
+/// Second-order IIR filter section, with coefficients from the RBJ audio EQ cookbook.
+#[derive(Clone, Debug)]
+pub struct Biquad {
+    b0: f64,
+    b1: f64,
+    b2: f64,
+    a1: f64,
+    a2: f64,
+    x1: f64,
+    x2: f64,
+    y1: f64,
+    y2: f64,
+}
+
+impl Biquad {
+    fn from_coefficients(b0: f64, b1: f64, b2: f64, a0: f64, a1: f64, a2: f64) -> Self {
+        Self {
+            b0: b0 / a0,
+            b1: b1 / a0,
+            b2: b2 / a0,
+            a1: a1 / a0,
+            a2: a2 / a0,
+            x1: 0.0,
+            x2: 0.0,
+            y1: 0.0,
+            y2: 0.0,
+        }
+    }
+
+    /// `(cos(w0), alpha)` for a filter at `freq` Hz, which must be below Nyquist, see
+    /// `Config::check_cutoffs`.
+    fn prewarp(sample_rate: u32, freq: f64, q: f64) -> (f64, f64) {
+        let w0 = 2.0 * PI * freq / sample_rate as f64;
+        (w0.cos(), w0.sin() / (2.0 * q))
+    }
+
+    pub fn low_pass(sample_rate: u32, freq: f64, q: f64) -> Self {
+        let (cos, alpha) = Self::prewarp(sample_rate, freq, q);
+        Self::from_coefficients(
+            (1.0 - cos) / 2.0, 1.0 - cos, (1.0 - cos) / 2.0,
+            1.0 + alpha, -2.0 * cos, 1.0 - alpha,
+        )
+    }
+
+    pub fn high_pass(sample_rate: u32, freq: f64, q: f64) -> Self {
+        let (cos, alpha) = Self::prewarp(sample_rate, freq, q);
+        Self::from_coefficients(
+            (1.0 + cos) / 2.0, -(1.0 + cos), (1.0 + cos) / 2.0,
+            1.0 + alpha, -2.0 * cos, 1.0 - alpha,
+        )
+    }
+
+    /// Removes a narrow band around `freq`, narrower the higher `q` is.
+    pub fn notch(sample_rate: u32, freq: f64, q: f64) -> Self {
+        let (cos, alpha) = Self::prewarp(sample_rate, freq, q);
+        Self::from_coefficients(
+            1.0, -2.0 * cos, 1.0,
+            1.0 + alpha, -2.0 * cos, 1.0 - alpha,
+        )
+    }
+
+    pub fn process(&mut self, x: f32) -> f32 {
+        let x = x as f64;
+        let y = self.b0 * x + self.b1 * self.x1 + self.b2 * self.x2 - self.a1 * self.y1 - self.a2 * self.y2;
+        self.x2 = self.x1;
+        self.x1 = x;
+        self.y2 = self.y1;
+        self.y1 = y;
+        y as f32
+    }
+}
+
+/// The configured high-pass, mains notches and band-pass, run in that order on every channel.
+pub struct FilterChain {
+    channels: usize,
+    /// Every stage, once per channel
+    stages: Vec<Vec<Biquad>>,
+    settle_frames: u64,
+}
+
+impl FilterChain {
+    /// The chain `settings` describe, or `None` if every stage is off.
+    pub fn new(settings: &FilterSettings, channels: usize, sample_rate: u32) -> Option<Self> {
+        let mut stages = Vec::new();
+        // Each stage's ringing decays with a time constant of Q / (pi * freq)
+        let mut settle_secs = 0.0;
+        let mut add = |stage: Biquad, freq: f64, q: f64| {
+            stages.push(stage);
+            settle_secs += SETTLE_TIME_CONSTANTS * q / (PI * freq);
+        };
+        if settings.highpass_hz > 0.0 {
+            let freq = settings.highpass_hz;
+            add(Biquad::high_pass(sample_rate, freq, FRAC_1_SQRT_2), freq, FRAC_1_SQRT_2);
+        }
+        if settings.notch_hz > 0.0 {
+            // Harmonics close to Nyquist can't be notched cleanly, and there's no hum up there
+            let harmonics = (1..=settings.notch_harmonics)
+                .map(|n| settings.notch_hz * n as f64)
+                .take_while(|&freq| freq < sample_rate as f64 * 0.45);
+            for freq in harmonics {
+                add(Biquad::notch(sample_rate, freq, settings.notch_q), freq, settings.notch_q);
+            }
+        }
+        if settings.bandpass_low_hz > 0.0 {
+            let (low, high) = (settings.bandpass_low_hz, settings.bandpass_high_hz);
+            add(Biquad::high_pass(sample_rate, low, FRAC_1_SQRT_2), low, FRAC_1_SQRT_2);
+            add(Biquad::low_pass(sample_rate, high, FRAC_1_SQRT_2), high, FRAC_1_SQRT_2);
+        }
+        if stages.is_empty() {
+            return None;
+        }
+        Some(Self {
+            channels,
+            stages: vec![stages; channels],
+            settle_frames: (settle_secs * sample_rate as f64).ceil() as u64,
+        })
+    }
+
+    /// Frames until the chain's start-up transient has died away. A steady hum that's there from
+    /// the first sample makes a narrow notch ring for up to a second before it cancels.
+    pub fn settle_frames(&self) -> u64 {
+        self.settle_frames
+    }
+
+    /// Filter interleaved `data` into `out`, which is cleared first.
+    pub fn process(&mut self, data: &[f32], out: &mut Vec<f32>) {
+        out.clear();
+        for frame in data.chunks_exact(self.channels) {
+            for (&sample, stages) in frame.iter().zip(&mut self.stages) {
+                out.push(stages.iter_mut().fold(sample, |x, stage| stage.process(x)));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_signal::sine;
+
+    const RATE: u32 = 16_000;
+
+    fn settings() -> FilterSettings {
+        FilterSettings { notch_harmonics: 3, notch_q: 30.0, ..FilterSettings::default() }
+    }
+
+    fn rms(samples: &[f32]) -> f32 {
+        (samples.iter().map(|s| s * s).sum::<f32>() / samples.len() as f32).sqrt()
+    }
+
+    /// Gain of the chain at `freq` once it has settled, from the last half second of 3 s of tone.
+    fn gain(settings: &FilterSettings, freq: f32) -> f32 {
+        let mut chain = FilterChain::new(settings, 1, RATE).unwrap();
+        let input = sine(freq, RATE, 3 * RATE as usize);
+        let mut output = Vec::new();
+        chain.process(&input, &mut output);
+        let tail = RATE as usize / 2;
+        rms(&output[output.len() - tail..]) / rms(&input[input.len() - tail..])
+    }
+
+    #[test]
+    fn high_pass_removes_rumble() {
+        let settings = FilterSettings { highpass_hz: 150.0, ..settings() };
+        assert!(gain(&settings, 60.0) < 0.2, "{}", gain(&settings, 60.0));
+        assert!(gain(&settings, 1000.0) > 0.95, "{}", gain(&settings, 1000.0));
+    }
+
+    #[test]
+    fn notches_remove_the_mains_hum_and_its_harmonics() {
+        let settings = FilterSettings { notch_hz: 50.0, ..settings() };
+        for hum in [50.0, 100.0, 150.0] {
+            assert!(gain(&settings, hum) < 0.01, "{} Hz: {}", hum, gain(&settings, hum));
+        }
+        for other in [75.0, 200.0, 1000.0] {
+            assert!(gain(&settings, other) > 0.9, "{} Hz: {}", other, gain(&settings, other));
+        }
+    }
+
+    #[test]
+    fn band_pass_keeps_the_bark_band() {
+        let settings = FilterSettings { bandpass_low_hz: 500.0, bandpass_high_hz: 2000.0, ..settings() };
+        assert!(gain(&settings, 1000.0) > 0.9, "{}", gain(&settings, 1000.0));
+        assert!(gain(&settings, 100.0) < 0.05, "{}", gain(&settings, 100.0));
+        assert!(gain(&settings, 7000.0) < 0.1, "{}", gain(&settings, 7000.0));
+    }
+
+    #[test]
+    fn no_stages_is_no_chain() {
+        assert!(FilterChain::new(&settings(), 1, RATE).is_none());
+    }
+
+    #[test]
+    fn settle_frames_covers_the_notch_transient() {
+        let settings = FilterSettings { notch_hz: 60.0, ..settings() };
+        let mut chain = FilterChain::new(&settings, 2, RATE).unwrap();
+        let settle = chain.settle_frames() as usize;
+        // A hum that's there from the first sample, on both channels
+        let input: Vec<f32> = sine(60.0, RATE, settle + RATE as usize).iter().flat_map(|&s| [s, s]).collect();
+        let mut output = Vec::new();
+        for piece in input.chunks(2 * 512) {
+            let mut filtered = Vec::new();
+            chain.process(piece, &mut filtered);
+            output.extend(filtered);
+        }
+        let peak = |samples: &[f32]| samples.iter().fold(0.0f32, |peak, s| peak.max(s.abs()));
+        // It rings at first, and is down about 60 dB from the hum's 0.5 once settled
+        assert!(peak(&output[..2 * settle / 4]) > 0.05);
+        assert!(peak(&output[2 * settle..]) < 1e-3, "{}", peak(&output[2 * settle..]));
+    }
+}
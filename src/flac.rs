@@ -0,0 +1,460 @@
+use std::io::{self, Seek, SeekFrom, Write};
+
+/// Frames per FLAC block. 4096 is what the reference encoder uses at its default levels.
+const BLOCK_SIZE: usize = 4096;
+/// Highest order of the fixed predictors the format defines.
+const MAX_FIXED_ORDER: usize = 4;
+/// Highest residual partition order tried. Each extra order halves the partitions' length.
+const MAX_PARTITION_ORDER: u32 = 6;
+/// Highest Rice parameter of the 4-bit residual coding method, 15 means escaped.
+const MAX_RICE_PARAMETER: u32 = 14;
+/// Where STREAMINFO starts, after the `fLaC` marker and its metadata block header.
+const STREAMINFO_OFFSET: u64 = 8;
+
+/// A minimal FLAC encoder: fixed-blocksize frames of independent channels, each subframe stored
+/// as constant, verbatim or with the best of the fixed polynomial predictors and Rice-coded
+/// residuals. No LPC or stereo decorrelation, so files come out somewhat bigger than the
+/// reference encoder's, but it needs nothing beyond std.
+///
+/// STREAMINFO is written up front with the length unknown and filled in by `finalize`, so a
+/// file cut off by a crash still decodes up to its last complete frame.
+pub struct FlacWriter<W: Write + Seek> {
+    writer: W,
+    channels: usize,
+    sample_rate: u32,
+    bits_per_sample: u32,
+    /// Interleaved samples of the block being filled
+    block: Vec<i32>,
+    frames_written: u64,
+    frame_number: u64,
+    min_frame_bytes: u32,
+    max_frame_bytes: u32,
+}
+
+impl<W: Write + Seek> FlacWriter<W> {
+    pub fn new(mut writer: W, channels: u16, sample_rate: u32, bits_per_sample: u32) -> io::Result<Self> {
+        if !(1..=8).contains(&channels) || !(4..=24).contains(&bits_per_sample) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("FLAC clips can't have {} channels of {}-bit samples", channels, bits_per_sample),
+            ));
+        }
+        writer.write_all(b"fLaC")?;
+        // Last metadata block, type 0 (STREAMINFO), 34 bytes long
+        writer.write_all(&[0x80, 0, 0, 34])?;
+        let mut flac = Self {
+            writer,
+            channels: channels as usize,
+            sample_rate,
+            bits_per_sample,
+            block: Vec::with_capacity(BLOCK_SIZE * channels as usize),
+            frames_written: 0,
+            frame_number: 0,
+            min_frame_bytes: 0,
+            max_frame_bytes: 0,
+        };
+        flac.write_streaminfo()?;
+        Ok(flac)
+    }
+
+    pub fn channels(&self) -> u16 {
+        self.channels as u16
+    }
+
+    pub fn bits_per_sample(&self) -> u32 {
+        self.bits_per_sample
+    }
+
+    /// Append one sample, already in the stream's bit depth. Channels are interleaved.
+    pub fn write_sample(&mut self, sample: i32) -> io::Result<()> {
+        self.block.push(sample);
+        if self.block.len() == BLOCK_SIZE * self.channels {
+            self.write_frame()?;
+        }
+        Ok(())
+    }
+
+    /// Encode what's left of the last block and fill in STREAMINFO.
+    pub fn finalize(mut self) -> io::Result<()> {
+        // An unfinished frame can't be encoded, drop its stray samples
+        self.block.truncate(self.block.len() - self.block.len() % self.channels);
+        if !self.block.is_empty() {
+            self.write_frame()?;
+        }
+        self.writer.seek(SeekFrom::Start(STREAMINFO_OFFSET))?;
+        self.write_streaminfo()?;
+        self.writer.seek(SeekFrom::End(0))?;
+        self.writer.flush()
+    }
+
+    fn write_streaminfo(&mut self) -> io::Result<()> {
+        let mut bits = BitWriter::new();
+        bits.write(BLOCK_SIZE as u64, 16); // minimum block size, the last block doesn't count
+        bits.write(BLOCK_SIZE as u64, 16);
+        bits.write(self.min_frame_bytes as u64, 24); // 0 while unknown
+        bits.write(self.max_frame_bytes as u64, 24);
+        bits.write(self.sample_rate as u64, 20);
+        bits.write(self.channels as u64 - 1, 3);
+        bits.write(self.bits_per_sample as u64 - 1, 5);
+        bits.write(self.frames_written, 36); // 0 while unknown
+        bits.write(0, 64); // no MD5 signature
+        bits.write(0, 64);
+        self.writer.write_all(&bits.into_bytes())
+    }
+
+    fn write_frame(&mut self) -> io::Result<()> {
+        let frames = self.block.len() / self.channels;
+        let mut bits = BitWriter::new();
+        // Sync code, reserved bit, fixed-blocksize stream
+        bits.write(0b1111_1111_1111_1000, 16);
+        // Block size as a 16-bit value at the end of the header, sample rate from STREAMINFO
+        bits.write(0b0111, 4);
+        bits.write(0b0000, 4);
+        // Independent channels
+        bits.write(self.channels as u64 - 1, 4);
+        bits.write(sample_size_code(self.bits_per_sample), 3);
+        bits.write(0, 1);
+        write_utf8_number(&mut bits, self.frame_number);
+        bits.write(frames as u64 - 1, 16);
+        let crc = crc8(bits.bytes());
+        bits.write(crc as u64, 8);
+
+        let mut channel = Vec::with_capacity(frames);
+        for c in 0..self.channels {
+            channel.clear();
+            channel.extend(self.block.iter().skip(c).step_by(self.channels).map(|&s| s as i64));
+            write_subframe(&mut bits, &channel, self.bits_per_sample);
+        }
+        bits.align();
+        let crc = crc16(bits.bytes());
+        bits.write(crc as u64, 16);
+
+        let frame = bits.into_bytes();
+        self.writer.write_all(&frame)?;
+        let frame_bytes = frame.len() as u32;
+        self.min_frame_bytes = if self.frame_number == 0 { frame_bytes } else { self.min_frame_bytes.min(frame_bytes) };
+        self.max_frame_bytes = self.max_frame_bytes.max(frame_bytes);
+        self.frames_written += frames as u64;
+        self.frame_number += 1;
+        self.block.clear();
+        Ok(())
+    }
+}
+
+/// Encode one channel of a block in whichever of the subframe types comes out smallest.
+fn write_subframe(bits: &mut BitWriter, samples: &[i64], bits_per_sample: u32) {
+    if samples.iter().all(|&s| s == samples[0]) {
+        bits.write(0b0000_0000, 8);
+        bits.write_signed(samples[0], bits_per_sample);
+        return;
+    }
+
+    let verbatim_bits = samples.len() as u64 * bits_per_sample as u64;
+    let best = (0..=MAX_FIXED_ORDER.min(samples.len() - 1))
+        .map(|order| {
+            let residual = fixed_residual(samples, order);
+            let coding = RiceCoding::best(&residual, order, samples.len());
+            let size = order as u64 * bits_per_sample as u64 + coding.bits;
+            (size, order, residual, coding)
+        })
+        .min_by_key(|(size, ..)| *size);
+    match best {
+        Some((size, order, residual, coding)) if size < verbatim_bits => {
+            bits.write(0b0001_0000 | (order as u64) << 1, 8);
+            for &warmup in &samples[..order] {
+                bits.write_signed(warmup, bits_per_sample);
+            }
+            coding.write(bits, &residual, order, samples.len());
+        }
+        _ => {
+            bits.write(0b0000_0010, 8);
+            for &sample in samples {
+                bits.write_signed(sample, bits_per_sample);
+            }
+        }
+    }
+}
+
+/// What's left of `samples` after the fixed predictor of `order`, from sample `order` on.
+fn fixed_residual(samples: &[i64], order: usize) -> Vec<i64> {
+    (order..samples.len())
+        .map(|i| {
+            let s = |back: usize| samples[i - back];
+            match order {
+                0 => s(0),
+                1 => s(0) - s(1),
+                2 => s(0) - 2 * s(1) + s(2),
+                3 => s(0) - 3 * s(1) + 3 * s(2) - s(3),
+                _ => s(0) - 4 * s(1) + 6 * s(2) - 4 * s(3) + s(4),
+            }
+        })
+        .collect()
+}
+
+/// Residuals split into `2^partition_order` partitions, each with its own Rice parameter.
+struct RiceCoding {
+    partition_order: u32,
+    parameters: Vec<u32>,
+    /// Size of the coded residual, header included
+    bits: u64,
+}
+
+impl RiceCoding {
+    /// The partitioning and parameters that code `residual` in the fewest bits.
+    fn best(residual: &[i64], order: usize, block_len: usize) -> Self {
+        let folded: Vec<u64> = residual.iter().map(|&r| fold(r)).collect();
+        (0..=MAX_PARTITION_ORDER)
+            // Partitions must divide the block evenly, and the first has to outlast the warm-up
+            .take_while(|&p| block_len.is_multiple_of(1 << p) && block_len >> p > order)
+            .map(|partition_order| {
+                let mut parameters = Vec::new();
+                let mut bits = 2 + 4; // coding method and partition order
+                for partition in partitions(&folded, partition_order, order, block_len) {
+                    let (parameter, size) = (0..=MAX_RICE_PARAMETER)
+                        .map(|k| (k, rice_bits(partition, k)))
+                        .min_by_key(|&(_, size)| size)
+                        .expect("there's always a parameter");
+                    parameters.push(parameter);
+                    bits += 4 + size;
+                }
+                Self { partition_order, parameters, bits }
+            })
+            .min_by_key(|coding| coding.bits)
+            .expect("partition order 0 always fits")
+    }
+
+    fn write(&self, bits: &mut BitWriter, residual: &[i64], order: usize, block_len: usize) {
+        bits.write(0b00, 2);
+        bits.write(self.partition_order as u64, 4);
+        for (partition, &k) in partitions(residual, self.partition_order, order, block_len).zip(&self.parameters) {
+            bits.write(k as u64, 4);
+            for &r in partition {
+                let folded = fold(r);
+                bits.write_unary(folded >> k);
+                bits.write(folded & ((1 << k) - 1), k);
+            }
+        }
+    }
+}
+
+/// The residual's partitions. The first is short by the predictor order, whose samples are
+/// stored as warm-up instead.
+fn partitions<T>(residual: &[T], partition_order: u32, order: usize, block_len: usize) -> impl Iterator<Item = &[T]> {
+    let len = block_len >> partition_order;
+    let first = len - order;
+    std::iter::once(&residual[..first]).chain(residual[first..].chunks(len))
+}
+
+/// Zig-zag a signed residual onto the unsigned values Rice coding takes.
+fn fold(r: i64) -> u64 {
+    ((r << 1) ^ (r >> 63)) as u64
+}
+
+fn rice_bits(folded: &[u64], k: u32) -> u64 {
+    folded.iter().map(|&u| (u >> k) + 1 + k as u64).sum()
+}
+
+fn sample_size_code(bits_per_sample: u32) -> u64 {
+    match bits_per_sample {
+        8 => 0b001,
+        12 => 0b010,
+        16 => 0b100,
+        20 => 0b101,
+        24 => 0b110,
+        // Anything else is read from STREAMINFO
+        _ => 0b000,
+    }
+}
+
+/// Frame numbers are coded like UTF-8 characters, extended to 36 bits.
+fn write_utf8_number(bits: &mut BitWriter, n: u64) {
+    if n < 0x80 {
+        bits.write(n, 8);
+        return;
+    }
+    let continuation_bytes = match n {
+        0..0x800 => 1,
+        0x800..0x1_0000 => 2,
+        0x1_0000..0x20_0000 => 3,
+        0x20_0000..0x400_0000 => 4,
+        0x400_0000..0x8000_0000 => 5,
+        _ => 6,
+    };
+    let lead_marker = (0xFF00u64 >> (continuation_bytes + 1)) & 0xFF;
+    bits.write(lead_marker | (n >> (6 * continuation_bytes)), 8);
+    for i in (0..continuation_bytes).rev() {
+        bits.write(0x80 | ((n >> (6 * i)) & 0x3F), 8);
+    }
+}
+
+fn crc8(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |mut crc, &byte| {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 { (crc << 1) ^ 0x07 } else { crc << 1 };
+        }
+        crc
+    })
+}
+
+fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0u16, |mut crc, &byte| {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x8005 } else { crc << 1 };
+        }
+        crc
+    })
+}
+
+/// Packs values MSB first, as FLAC wants them.
+struct BitWriter {
+    bytes: Vec<u8>,
+    /// Bits not yet making up a whole byte, in the low `pending_bits` bits
+    pending: u64,
+    pending_bits: u32,
+}
+
+impl BitWriter {
+    fn new() -> Self {
+        Self { bytes: Vec::new(), pending: 0, pending_bits: 0 }
+    }
+
+    /// Write the low `count` bits of `value`, up to 64.
+    fn write(&mut self, value: u64, count: u32) {
+        if count > 32 {
+            self.write(value >> 32, count - 32);
+            self.write(value & 0xFFFF_FFFF, 32);
+            return;
+        }
+        if count == 0 {
+            return;
+        }
+        self.pending = (self.pending << count) | (value & ((1 << count) - 1));
+        self.pending_bits += count;
+        while self.pending_bits >= 8 {
+            self.pending_bits -= 8;
+            self.bytes.push((self.pending >> self.pending_bits) as u8);
+        }
+        self.pending &= (1 << self.pending_bits) - 1;
+    }
+
+    /// Two's complement in `count` bits.
+    fn write_signed(&mut self, value: i64, count: u32) {
+        self.write(value as u64, count);
+    }
+
+    /// `n` zeros and a one.
+    fn write_unary(&mut self, mut n: u64) {
+        while n >= 32 {
+            self.write(0, 32);
+            n -= 32;
+        }
+        self.write(1, n as u32 + 1);
+    }
+
+    /// Pad with zeros to a byte boundary.
+    fn align(&mut self) {
+        if self.pending_bits > 0 {
+            self.write(0, 8 - self.pending_bits);
+        }
+    }
+
+    /// The whole bytes written so far.
+    fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    fn into_bytes(mut self) -> Vec<u8> {
+        self.align();
+        self.bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_signal::{self, sine};
+    use std::io::Cursor;
+
+    const RATE: u32 = 8000;
+
+    /// Encode interleaved samples and decode them again with claxon, checking STREAMINFO on
+    /// the way. Returns the decoded samples.
+    fn round_trip(samples: &[i32], channels: u16, bits_per_sample: u32) -> Vec<i32> {
+        let mut file = Cursor::new(Vec::new());
+        let mut writer = FlacWriter::new(&mut file, channels, RATE, bits_per_sample).unwrap();
+        for &sample in samples {
+            writer.write_sample(sample).unwrap();
+        }
+        writer.finalize().unwrap();
+
+        let mut reader = claxon::FlacReader::new(Cursor::new(file.into_inner())).unwrap();
+        let info = reader.streaminfo();
+        let frames = samples.len() as u64 / channels as u64;
+        assert_eq!(info.sample_rate, RATE);
+        assert_eq!(info.channels, channels as u32);
+        assert_eq!(info.bits_per_sample, bits_per_sample);
+        assert_eq!(info.samples, Some(frames));
+        assert_eq!((info.min_block_size, info.max_block_size), (BLOCK_SIZE as u16, BLOCK_SIZE as u16));
+        let (min_frame, max_frame) = (info.min_frame_size.unwrap(), info.max_frame_size.unwrap());
+        assert!(0 < min_frame && min_frame <= max_frame);
+        reader.samples().map(|s| s.unwrap()).collect()
+    }
+
+    /// Full-range noise.
+    fn noise(len: usize, bits_per_sample: u32) -> Vec<i32> {
+        let scale = (1 << (bits_per_sample - 1)) as f32;
+        test_signal::noise(len).into_iter().map(|s| (s * scale) as i32).collect()
+    }
+
+    #[test]
+    fn sine_round_trips_exactly() {
+        let samples: Vec<i32> = sine(440.0, RATE, 3 * BLOCK_SIZE + 100).into_iter().map(|s| (s * 60_000.0) as i32).collect();
+        assert_eq!(round_trip(&samples, 1, 16), samples);
+    }
+
+    #[test]
+    fn silence_round_trips_exactly() {
+        let samples = vec![0; 2 * BLOCK_SIZE];
+        assert_eq!(round_trip(&samples, 1, 16), samples);
+    }
+
+    #[test]
+    fn odd_length_final_block_round_trips_exactly() {
+        // The last block holds a single frame
+        let samples = noise(2 * (BLOCK_SIZE + 1), 16);
+        assert_eq!(round_trip(&samples, 2, 16), samples);
+    }
+
+    #[test]
+    fn stray_samples_of_an_unfinished_frame_are_dropped() {
+        let samples = noise(2 * 1000 + 1, 16);
+        assert_eq!(round_trip(&samples, 2, 16), samples[..2000]);
+    }
+
+    #[test]
+    fn multi_channel_16_bit_round_trips_exactly() {
+        let mut samples = noise(3 * (BLOCK_SIZE + 777), 16);
+        samples[..6].copy_from_slice(&[i16::MIN as i32, i16::MAX as i32, 0, i16::MAX as i32, i16::MIN as i32, -1]);
+        assert_eq!(round_trip(&samples, 3, 16), samples);
+    }
+
+    #[test]
+    fn multi_channel_24_bit_round_trips_exactly() {
+        let (min, max) = (-(1 << 23), (1 << 23) - 1);
+        let mut samples = noise(6 * (BLOCK_SIZE + 333), 24);
+        samples[..6].copy_from_slice(&[min, max, 0, max, min, -1]);
+        // A constant channel next to noisy ones
+        for frame in samples.chunks_exact_mut(6).skip(1) {
+            frame[2] = 12_345;
+        }
+        assert_eq!(round_trip(&samples, 6, 24), samples);
+    }
+
+    #[test]
+    fn unsupported_layouts_are_refused() {
+        assert!(FlacWriter::new(Cursor::new(Vec::new()), 9, RATE, 16).is_err());
+        assert!(FlacWriter::new(Cursor::new(Vec::new()), 2, RATE, 32).is_err());
+    }
+}
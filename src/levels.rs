@@ -0,0 +1,237 @@
+use chrono::{DateTime, FixedOffset, Local, NaiveDate};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use crate::to_dbfs;
+
+const HEADER: &str = "time,peak_dbfs,rms_dbfs,threshold_dbfs";
+
+/// One line of the level log: the input's levels over one interval.
+#[derive(Clone, Copy, Debug)]
+pub struct LevelSample {
+    /// Start of the interval
+    pub time: DateTime<FixedOffset>,
+    pub peak_dbfs: f32,
+    pub rms_dbfs: f32,
+    /// Detector threshold in effect at the end of the interval
+    pub threshold_dbfs: f32,
+}
+
+/// `levels_<date>.csv` in `dir`, one file per local day so old ones are easy to prune.
+fn path_for(dir: &Path, date: NaiveDate) -> PathBuf {
+    dir.join(format!("levels_{}.csv", date.format("%Y-%m-%d")))
+}
+
+/// The day a level log file covers, or `None` if `file_name` isn't one.
+pub fn log_date(file_name: &str) -> Option<NaiveDate> {
+    let date = file_name.strip_prefix("levels_")?.strip_suffix(".csv")?;
+    NaiveDate::parse_from_str(date, "%Y-%m-%d").ok()
+}
+
+/// Appends the peak and RMS level of every `interval` of input to a CSV file, whether or not a
+/// clip is being recorded, so there's a record of how loud it was between clips.
+pub struct LevelLog {
+    dir: PathBuf,
+    interval_frames: u64,
+    sample_rate: u32,
+    channels: usize,
+    file: Option<(NaiveDate, File)>,
+    /// When the first frame of the current interval was heard
+    interval_start: Option<DateTime<Local>>,
+    frames: u64,
+    peak: f32,
+    sum_squares: f64,
+    /// The last write failed, so the next failure isn't reported again
+    failing: bool,
+}
+
+impl LevelLog {
+    pub fn new(dir: &Path, channels: usize, sample_rate: u32, interval_secs: f64) -> Self {
+        Self {
+            dir: dir.to_path_buf(),
+            interval_frames: ((sample_rate as f64 * interval_secs) as u64).max(1),
+            sample_rate,
+            channels,
+            file: None,
+            interval_start: None,
+            frames: 0,
+            peak: 0.0,
+            sum_squares: 0.0,
+            failing: false,
+        }
+    }
+
+    /// Feed interleaved samples whose first frame was captured at `captured_at`.
+    pub fn push(&mut self, data: &[f32], captured_at: DateTime<Local>, threshold: f32) {
+        for (i, frame) in data.chunks_exact(self.channels).enumerate() {
+            if self.interval_start.is_none() {
+                self.interval_start = Some(captured_at + chrono::Duration::microseconds(
+                    (i as f64 * 1_000_000.0 / self.sample_rate as f64) as i64
+                ));
+            }
+            for &sample in frame {
+                self.peak = self.peak.max(sample.abs());
+                self.sum_squares += (sample * sample) as f64;
+            }
+            self.frames += 1;
+            if self.frames == self.interval_frames {
+                let rms = (self.sum_squares / (self.frames * self.channels as u64) as f64).sqrt() as f32;
+                let start = self.interval_start.take().expect("set on the interval's first frame");
+                match self.append(start, self.peak, rms, threshold) {
+                    Ok(()) if self.failing => {
+                        println!("Level log in {} is being written again", self.dir.display());
+                        self.failing = false;
+                    }
+                    Ok(()) => {}
+                    Err(e) => {
+                        // Reopened for the next interval, in case the file or its directory went away
+                        self.file = None;
+                        if !self.failing {
+                            eprintln!("Failed to write level log in {}, retrying: {}", self.dir.display(), e);
+                            self.failing = true;
+                        }
+                    }
+                }
+                self.frames = 0;
+                self.peak = 0.0;
+                self.sum_squares = 0.0;
+            }
+        }
+    }
+
+    fn append(&mut self, time: DateTime<Local>, peak: f32, rms: f32, threshold: f32) -> io::Result<()> {
+        let date = time.date_naive();
+        if self.file.as_ref().is_none_or(|(open, _)| *open != date) {
+            fs::create_dir_all(&self.dir)?;
+            let mut file = OpenOptions::new().create(true).append(true).open(path_for(&self.dir, date))?;
+            if file.metadata()?.len() == 0 {
+                writeln!(file, "{}", HEADER)?;
+            }
+            self.file = Some((date, file));
+        }
+        let (_, file) = self.file.as_mut().expect("opened above");
+        let line = format!(
+            "{},{:.1},{:.1},{:.1}\n",
+            time.to_rfc3339_opts(chrono::SecondsFormat::Millis, false),
+            to_dbfs(peak),
+            to_dbfs(rms),
+            to_dbfs(threshold),
+        );
+        file.write_all(line.as_bytes())
+    }
+}
+
+/// Read every level log in `dir`, oldest first. Unreadable files and lines are skipped.
+pub fn read_levels(dir: &Path) -> Vec<LevelSample> {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    let mut samples = Vec::new();
+    for entry in entries.filter_map(|e| e.ok()) {
+        let name = entry.file_name();
+        let Some(name) = name.to_str() else {
+            continue;
+        };
+        if log_date(name).is_none() {
+            continue;
+        }
+        let Ok(text) = fs::read_to_string(entry.path()) else {
+            continue;
+        };
+        samples.extend(text.lines().skip(1).filter_map(parse_line));
+    }
+    samples.sort_by_key(|s| s.time);
+    samples
+}
+
+fn parse_line(line: &str) -> Option<LevelSample> {
+    let mut fields = line.split(',');
+    let time = DateTime::parse_from_rfc3339(fields.next()?).ok()?;
+    let mut next = || fields.next()?.parse().ok();
+    Some(LevelSample {
+        time,
+        peak_dbfs: next()?,
+        rms_dbfs: next()?,
+        threshold_dbfs: next()?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn at(h: u32, m: u32, s: u32) -> DateTime<Local> {
+        Local.with_ymd_and_hms(2024, 5, 1, h, m, s).unwrap()
+    }
+
+    #[test]
+    fn logs_every_interval_and_reads_it_back() {
+        let dir = tempfile::tempdir().unwrap();
+        // 2 channels at 100 Hz, so 50 frames an interval
+        let mut log = LevelLog::new(dir.path(), 2, 100, 0.5);
+        log.push(&[0.5; 120], at(12, 0, 0), 0.1);
+        // The second interval started 50 frames into the first push
+        log.push(&[-0.25; 80], at(12, 0, 0) + chrono::Duration::milliseconds(600), 0.1);
+        // Not a full interval yet, so not logged
+        log.push(&[1.0; 40], at(12, 0, 1), 0.1);
+
+        let samples = read_levels(dir.path());
+        assert_eq!(samples.len(), 2, "{:?}", samples);
+        assert_eq!(samples[0].time, at(12, 0, 0).fixed_offset());
+        assert_eq!((samples[0].peak_dbfs, samples[0].rms_dbfs, samples[0].threshold_dbfs), (-6.0, -6.0, -20.0));
+        assert_eq!(samples[1].time, (at(12, 0, 0) + chrono::Duration::milliseconds(500)).fixed_offset());
+        // 10 frames at 0.5 and 40 at 0.25
+        let rms = ((10.0 * 0.25 + 40.0 * 0.0625) / 50.0f32).sqrt();
+        assert_eq!((samples[1].peak_dbfs, samples[1].rms_dbfs), (-6.0, (to_dbfs(rms) * 10.0).round() / 10.0));
+    }
+
+    #[test]
+    fn each_day_gets_its_own_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut log = LevelLog::new(dir.path(), 1, 10, 1.0);
+        log.push(&[0.5; 10], at(23, 59, 59), 0.1);
+        log.push(&[0.5; 10], at(23, 59, 59) + chrono::Duration::seconds(1), 0.1);
+
+        let mut dates: Vec<_> = fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| log_date(entry.unwrap().file_name().to_str().unwrap()).unwrap())
+            .collect();
+        dates.sort();
+        assert_eq!(dates, [at(0, 0, 0).date_naive(), at(0, 0, 0).date_naive().succ_opt().unwrap()]);
+        assert_eq!(read_levels(dir.path()).len(), 2);
+    }
+
+    #[test]
+    fn a_failed_write_is_retried_on_the_next_interval() {
+        let parent = tempfile::tempdir().unwrap();
+        let dir = parent.path().join("levels");
+        // A file where the directory should be
+        fs::write(&dir, b"").unwrap();
+        let mut log = LevelLog::new(&dir, 1, 10, 1.0);
+        log.push(&[0.5; 10], at(12, 0, 0), 0.1);
+        assert!(log.failing);
+
+        fs::remove_file(&dir).unwrap();
+        log.push(&[0.5; 10], at(12, 0, 1), 0.1);
+        assert!(!log.failing);
+        let samples = read_levels(&dir);
+        assert_eq!(samples.len(), 1);
+        assert_eq!(samples[0].time, at(12, 0, 1).fixed_offset());
+    }
+
+    #[test]
+    fn unreadable_lines_and_other_files_are_skipped() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(
+            dir.path().join("levels_2024-05-01.csv"),
+            format!("{}\n2024-05-01T12:00:00.000+02:00,-6.0,-9.5,-20.0\nnot,a,line\n", HEADER),
+        )
+        .unwrap();
+        fs::write(dir.path().join("levels.csv"), "2024-05-01T12:00:01.000+02:00,-6.0,-9.5,-20.0\n").unwrap();
+        let samples = read_levels(dir.path());
+        assert_eq!(samples.len(), 1);
+        assert_eq!(samples[0].rms_dbfs, -9.5);
+    }
+}
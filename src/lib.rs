@@ -0,0 +1,22 @@
+//! Pieces shared by the recorder and the viewer.
+
+pub mod coverage;
+pub mod levels;
+pub mod naming;
+pub mod ogg_opus;
+pub mod sidecar;
+pub mod spectral;
+pub mod vocalization;
+
+#[cfg(test)]
+mod test_signal;
+
+/// Linear amplitude to dBFS.
+pub fn to_dbfs(amplitude: f32) -> f32 {
+    20.0 * amplitude.max(1e-10).log10()
+}
+
+/// dBFS to linear amplitude.
+pub fn from_dbfs(dbfs: f32) -> f32 {
+    10f32.powf(dbfs / 20.0)
+}
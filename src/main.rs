@@ -1,77 +1,242 @@
-use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
-use hound;
-use std::sync::{Arc, Mutex};
-use std::time::{Duration, Instant};
-use std::fs; // Add this import for directory creation
+mod config;
+mod detect;
+mod detector;
+mod devices;
+mod filter;
+mod flac;
+mod migrate;
+mod noise_floor;
+#[cfg(feature = "opus")]
+mod opus;
+mod preprocess;
+mod preroll;
+mod recorder;
+mod repair;
+mod retention;
+mod schedule;
+mod session;
+mod source;
+#[cfg(test)]
+mod test_signal;
+mod writer;
+
+use clap::{Parser, Subcommand};
 use chrono::Local;
+use config::{Config, ConfigArgs};
+use schedule::{Arming, Profile};
+use session::{SessionEvent, SessionLog, HEARTBEAT_INTERVAL};
+use source::{Source, Supervised};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::time::{Duration, Instant};
+
+/// Record clips of your dog barking.
+#[derive(Parser, Debug)]
+#[command(version, about)]
+struct Cli {
+    #[command(flatten)]
+    config: ConfigArgs,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
 
-const THRESHOLD: f32 = 0.05; // Adjust sensitivity for bark detection
-const MIN_BARK_DURATION: Duration = Duration::from_secs(5); // This is now the silence duration before stopping
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Segment an existing WAV recording into bark clips
+    Detect(detect::DetectArgs),
+    /// List audio hosts, input devices and the configs they support
+    ListDevices,
+    /// Rename clips in the output directory from the old 12-hour names to the current format
+    Migrate(migrate::MigrateArgs),
+    /// Fix clips left incomplete by a recorder that was killed mid-clip
+    Repair(repair::RepairArgs),
+    /// Delete or archive clips past the retention limits
+    Prune(retention::PruneArgs),
+}
 
 fn main() {
-    let host = cpal::default_host();
-    let device = host.default_input_device().expect("Failed to find input device");
-    let config = device.default_input_config().expect("Failed to get default input config");
-
-    let sample_rate = config.sample_rate().0;
-    let channels = config.channels() as usize;
-    let samples_per_chunk = (sample_rate as f32 * MIN_BARK_DURATION.as_secs_f32()) as usize;
-    
-    let recording = Arc::new(Mutex::new(false));
-    let last_bark_time = Arc::new(Mutex::new(None));
-    let mut writer: Option<hound::WavWriter<_>> = None;
-    
-    let stream = device.build_input_stream(
-        &config.into(),
-        move |data: &[f32], _: &cpal::InputCallbackInfo| {
-            let max_amplitude = data.iter().map(|x| x.abs()).fold(0.0, f32::max);
-            let mut is_recording = recording.lock().unwrap();
-            let mut last_bark = last_bark_time.lock().unwrap();
-            let now = Instant::now();
-
-            if max_amplitude > THRESHOLD {
-                if !*is_recording {
-                    *is_recording = true;
-                    *last_bark = Some(now);
-                    // Create barks directory if it doesn't exist
-                    fs::create_dir_all("barks").expect("Failed to create barks directory");
-                    
-                    let timestamp = Local::now().format("%Y%m%d_%I_%M_%S_%P");
-                    let filename = format!("barks/bark_{}.wav", timestamp);
-                    println!("Started recording: {}", filename);
-                    let spec = hound::WavSpec {
-                        channels: channels as u16,
-                        sample_rate: sample_rate,
-                        bits_per_sample: 16,
-                        sample_format: hound::SampleFormat::Int,
-                    };
-                    writer = Some(hound::WavWriter::create(filename, spec).unwrap());
-                } else {
-                    // Reset the timer when we hear another bark
-                    *last_bark = Some(now);
-                }
+    let cli = Cli::parse();
+    let config = Config::load(&cli.config).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(2);
+    });
+    println!("Effective configuration:\n{}", config.to_toml());
+
+    match cli.command {
+        None => record(&config),
+        Some(Command::Detect(args)) => {
+            if let Err(e) = detect::run(&config, &args) {
+                eprintln!("{}", e);
+                std::process::exit(1);
             }
-            
-            if *is_recording {
-                if let Some(ref mut w) = writer {
-                    for &sample in data.iter().take(samples_per_chunk) {
-                        let scaled_sample = (sample * i16::MAX as f32) as i16;
-                        w.write_sample(scaled_sample).unwrap();
+        }
+        Some(Command::ListDevices) => devices::list(),
+        Some(Command::Migrate(args)) => {
+            if let Err(e) = migrate::run(&config.output_dir, &args) {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+        Some(Command::Repair(args)) => {
+            if let Err(e) = repair::run(&config.output_dir, &args) {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+        Some(Command::Prune(args)) => {
+            if let Err(e) = retention::run(&config, &args) {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
+/// How often the main thread checks for stalled inputs and retries lost ones.
+const WATCHDOG_INTERVAL: Duration = Duration::from_secs(1);
+
+/// What the main thread waits for while the streams run.
+enum Wake {
+    /// SIGINT or SIGTERM
+    Stop,
+    /// `fatal` when the device is gone and the stream won't recover by itself. `generation` is
+    /// the stream's, see `Supervised::is_current`.
+    StreamError { source: String, generation: u64, message: String, fatal: bool },
+}
+
+/// Listen on every configured input and record clips for the length of the session.
+fn record(config: &Config) {
+    let mut session_log = SessionLog::open(&config.output_dir).expect("Failed to open session log");
+    let (wake, woken) = mpsc::channel();
+    handle_stop_signals(wake.clone());
+    repair::on_startup(&config.output_dir);
+    let mut sweeper = retention::Sweeper::new(config);
+    sweeper.start();
+
+    let mut arming = config.arming_at(Local::now().naive_local());
+    let mut sources = match arming {
+        Arming::Disarmed => {
+            println!("Outside the schedule, waiting for the next window");
+            session_log.record(SessionEvent::Disarmed);
+            Vec::new()
+        }
+        // A device that can't be opened at startup is most likely misconfigured, so give up
+        armed => arm(config, armed, &mut session_log, &wake, true),
+    };
+    println!("Listening for barks... (session {}, Ctrl-C to stop)", session_log.id());
+    let deadline = config.session_length().map(|length| Instant::now() + length);
+    let mut next_heartbeat = Instant::now() + HEARTBEAT_INTERVAL;
+    let mut next_sweep = Instant::now() + retention::SWEEP_INTERVAL;
+    let reason = loop {
+        match woken.recv_timeout(WATCHDOG_INTERVAL) {
+            Ok(Wake::Stop) | Err(RecvTimeoutError::Disconnected) => break "stopped by signal",
+            Ok(Wake::StreamError { source, generation, message, fatal }) => {
+                // Errors a stream reports while it's being closed, or after it's been reopened,
+                // are about one that's already gone
+                if let Some(current) = sources.iter_mut().find(|s| s.label() == source && s.is_current(generation)) {
+                    eprintln!("Stream error{}: {}", source::describe(&source), message);
+                    session_log.record(SessionEvent::StreamError { source: source.clone(), message: message.clone() });
+                    if fatal {
+                        current.fail(&message, &mut session_log);
                     }
                 }
-                // Only stop recording if we haven't heard a bark for MIN_BARK_DURATION
-                if last_bark.unwrap().elapsed() > MIN_BARK_DURATION {
-                    *is_recording = false;
-                    writer = None;
-                    println!("Finished recording");
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+        }
+
+        let now = Instant::now();
+        if deadline.is_some_and(|deadline| now >= deadline) {
+            break "session length reached";
+        }
+
+        let wanted = config.arming_at(Local::now().naive_local());
+        if wanted != arming {
+            if wanted == Arming::Disarmed {
+                sources.drain(..).for_each(Supervised::stop);
+                println!("Schedule window closed, disarmed");
+                session_log.record(SessionEvent::Disarmed);
+            } else if arming == Arming::Disarmed {
+                sources = arm(config, wanted, &mut session_log, &wake, false);
+            } else {
+                // From one window straight into the next. Profiles only change detection
+                // settings, so the inputs keep running and their recorders take up the new ones.
+                announce(config, wanted, &mut session_log);
+                let settings = config.armed_with(wanted);
+                if Profile::of(&settings) != Profile::of(&config.armed_with(arming)) {
+                    for (source, (_, source_config)) in sources.iter_mut().zip(settings.per_source()) {
+                        source.retune(source_config);
+                    }
                 }
             }
-        },
-        |err| eprintln!("Error: {}", err),
-        None,
-    ).expect("Failed to create stream");
-
-    stream.play().expect("Failed to start stream");
-    println!("Listening for barks...");
-    std::thread::sleep(Duration::from_secs(120 * 60)); // Run for an hour
+            arming = wanted;
+        }
+
+        for source in &mut sources {
+            source.check(&mut session_log, &wake);
+        }
+        if now >= next_heartbeat {
+            session_log.record(SessionEvent::Heartbeat);
+            next_heartbeat = now + HEARTBEAT_INTERVAL;
+        }
+        if now >= next_sweep {
+            sweeper.start();
+            next_sweep = now + retention::SWEEP_INTERVAL;
+        }
+    };
+
+    println!("Stopping: {}", reason);
+    sources.into_iter().for_each(Supervised::stop);
+    sweeper.finish();
+    session_log.record(SessionEvent::End { reason: reason.to_string() });
+    println!("Session finished");
+}
+
+/// Open and start every input with the settings for `arming`. Inputs that fail are retried in
+/// the background, or end the process if `exit_on_failure`.
+fn arm(
+    config: &Config,
+    arming: Arming,
+    session_log: &mut SessionLog,
+    wake: &mpsc::Sender<Wake>,
+    exit_on_failure: bool,
+) -> Vec<Supervised> {
+    announce(config, arming, session_log);
+    let mut sources = Vec::new();
+    for (label, source_config) in config.armed_with(arming).per_source() {
+        match Source::start(&label, &source_config, session_log.id(), wake) {
+            Ok((source, start)) => {
+                session_log.record(start);
+                sources.push(Supervised::new(label, source_config, source));
+            }
+            Err(e) if exit_on_failure => {
+                eprintln!("Failed to open input{}: {}", source::describe(&label), e);
+                sources.into_iter().for_each(Supervised::stop);
+                std::process::exit(1);
+            }
+            Err(e) => sources.push(Supervised::lost(label, source_config, &e, session_log)),
+        }
+    }
+    sources
+}
+
+/// Report the schedule window `arming` opens, if any.
+fn announce(config: &Config, arming: Arming, session_log: &mut SessionLog) {
+    if let Arming::Window(index) = arming {
+        let window = config.schedule[index].describe();
+        println!("Schedule window {} opened, armed", window);
+        session_log.record(SessionEvent::Armed { window });
+    }
+}
+
+/// Send `Wake::Stop` once SIGINT or SIGTERM arrives. A second signal exits immediately, in case
+/// shutting down gets stuck.
+fn handle_stop_signals(wake: mpsc::Sender<Wake>) {
+    let signalled = AtomicBool::new(false);
+    ctrlc::set_handler(move || {
+        if signalled.swap(true, Ordering::SeqCst) {
+            eprintln!("Second signal, exiting without finishing the clip");
+            std::process::exit(130);
+        }
+        let _ = wake.send(Wake::Stop);
+    }).expect("Failed to install signal handler");
 }
@@ -0,0 +1,253 @@
+use chrono::{DateTime, FixedOffset, Local, LocalResult, TimeZone};
+use clap::Args;
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+use ranger_recorder_rs::naming::{ClipFormat, ClipName};
+use ranger_recorder_rs::sidecar::ClipMetadata;
+
+/// Legacy clips of one episode, keyed by directory and legacy stamp.
+type Episodes = BTreeMap<(PathBuf, DateTime<FixedOffset>), Vec<(PathBuf, ClipName)>>;
+
+/// Rename clips from the legacy 12-hour file names to the current format.
+#[derive(Args, Debug)]
+pub struct MigrateArgs {
+    /// Print what would be renamed without touching anything
+    #[arg(long)]
+    pub dry_run: bool,
+}
+
+/// Rename every legacy clip under the output directory, along with its sidecar. All parts of an
+/// episode move together so they keep sharing a name.
+pub fn run(output_dir: &Path, args: &MigrateArgs) -> Result<(), String> {
+    migrate(output_dir, args, &Local)
+}
+
+/// `run`, with the legacy names' local time read in `tz`.
+fn migrate<Tz: TimeZone>(output_dir: &Path, args: &MigrateArgs, tz: &Tz) -> Result<(), String>
+where
+    Tz::Offset: fmt::Display,
+{
+    // Group by directory and legacy stamp, which is what tied the parts of an episode together
+    let mut episodes = Episodes::new();
+    let mut unreadable = 0;
+    for entry in WalkDir::new(output_dir).into_iter().filter_map(|e| e.ok()) {
+        let Some(file_name) = entry.file_name().to_str() else {
+            continue;
+        };
+        let Some(name) = ClipName::parse_in(file_name, tz) else {
+            if is_clip(file_name) {
+                eprintln!("Can't read the time stamp of {}, left as is", entry.path().display());
+                unreadable += 1;
+            }
+            continue;
+        };
+        if name.legacy {
+            let dir = entry.path().parent().unwrap_or(output_dir).to_path_buf();
+            episodes.entry((dir, name.start)).or_default().push((entry.into_path(), name));
+        }
+    }
+    if unreadable > 0 {
+        eprintln!("Clips with unreadable names, rename them by hand: {}", unreadable);
+    }
+    if episodes.is_empty() {
+        println!("No legacy clip names under {}", output_dir.display());
+        return Ok(());
+    }
+
+    let mut planned = HashSet::new();
+    let mut renamed = 0;
+    let mut failed = 0;
+    for ((dir, legacy_start), clips) in episodes {
+        // A sidecar has the exact episode start, the legacy name only has local time to the second
+        let sidecar_start = clips
+            .iter()
+            .find_map(|(path, _)| ClipMetadata::read(path))
+            .map(|m| m.episode_start);
+        let start = sidecar_start.unwrap_or_else(|| {
+            if let LocalResult::Ambiguous(..) = tz.from_local_datetime(&legacy_start.naive_local()) {
+                eprintln!(
+                    "{} falls in a repeated DST hour and has no sidecar, assuming the first {}",
+                    clips[0].0.display(),
+                    legacy_start.format("%H:%M:%S"),
+                );
+            }
+            legacy_start
+        });
+
+        let mut episode = ClipName::new(start.with_timezone(tz));
+        let target = |episode: &ClipName, name: &ClipName| {
+            dir.join(ClipName { part: name.part, short: name.short, format: name.format, ..episode.clone() }.file_name())
+        };
+        // Like the recorder, flagged and unflagged clips, or WAV and FLAC ones, with the same stamp
+        // count as a clash
+        while clips.iter().any(|(_, name)| {
+            ClipName { part: name.part, ..episode.clone() }.clashes().any(|clash| {
+                let path = dir.join(clash.file_name());
+                path.exists() || planned.contains(&path)
+            })
+        }) {
+            episode.collision += 1;
+        }
+
+        for (path, name) in &clips {
+            let new_path = target(&episode, name);
+            println!("{} -> {}", path.display(), new_path.display());
+            planned.insert(new_path.clone());
+            if args.dry_run {
+                continue;
+            }
+            if let Err(e) = fs::rename(path, &new_path) {
+                eprintln!("Failed to rename {}: {}", path.display(), e);
+                failed += 1;
+                continue;
+            }
+            let sidecar = ClipMetadata::path_for(path);
+            if sidecar.exists()
+                && let Err(e) = fs::rename(&sidecar, ClipMetadata::path_for(&new_path))
+            {
+                eprintln!("Failed to rename {}: {}", sidecar.display(), e);
+                failed += 1;
+            }
+            renamed += 1;
+        }
+    }
+
+    if args.dry_run {
+        println!("Dry run, nothing was renamed");
+        Ok(())
+    } else if failed > 0 {
+        Err(format!("Renamed {} clips, {} renames failed", renamed, failed))
+    } else {
+        println!("Renamed {} clips", renamed);
+        Ok(())
+    }
+}
+
+/// Named like a clip, whether or not its stamp can be read.
+fn is_clip(file_name: &str) -> bool {
+    file_name.starts_with("bark_")
+        && ClipFormat::ALL.iter().any(|format| file_name.ends_with(&format!(".{}", format.extension())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono_tz::Europe::Berlin;
+    use tempfile::TempDir;
+
+    fn migrate_files(files: &[&str]) -> (TempDir, Vec<String>) {
+        let dir = tempfile::tempdir().unwrap();
+        for file in files {
+            fs::write(dir.path().join(file), b"").unwrap();
+        }
+        migrate(dir.path(), &MigrateArgs { dry_run: false }, &Berlin).unwrap();
+        let names = file_names(dir.path());
+        (dir, names)
+    }
+
+    fn file_names(dir: &Path) -> Vec<String> {
+        let mut names: Vec<_> = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        names
+    }
+
+    fn sidecar(episode_start: &str) -> ClipMetadata {
+        let start = DateTime::parse_from_rfc3339(episode_start).unwrap();
+        ClipMetadata {
+            start,
+            end: start + chrono::Duration::seconds(3),
+            episode_start: start,
+            part: 1,
+            ..ClipMetadata::default()
+        }
+    }
+
+    #[test]
+    fn renames_legacy_names_to_the_current_format() {
+        let (_dir, names) = migrate_files(&[
+            "bark_20240501_06_30_07_pm.wav",
+            "bark_20240501_06_30_07_pm_part2_short.flac",
+            "bark_20240501_12_05_00_am.wav",
+            "bark_20240501_12_05_00_pm.wav",
+            "bark_20240101_09_00_00_am.wav",
+            "bark_20240501T070000.000+0200.wav",
+        ]);
+        assert_eq!(
+            names,
+            [
+                "bark_20240101T090000.000+0100.wav",
+                "bark_20240501T000500.000+0200.wav",
+                "bark_20240501T070000.000+0200.wav",
+                "bark_20240501T120500.000+0200.wav",
+                "bark_20240501T183007.000+0200.wav",
+                "bark_20240501T183007.000+0200_part2_short.flac",
+            ],
+        );
+    }
+
+    #[test]
+    fn a_repeated_dst_hour_is_read_as_the_first() {
+        // Clocks went back from 03:00 to 02:00 CEST on 27 October 2024
+        let (_dir, names) = migrate_files(&["bark_20241027_02_30_00_am.wav"]);
+        assert_eq!(names, ["bark_20241027T023000.000+0200.wav"]);
+    }
+
+    #[test]
+    fn clips_in_the_same_second_get_collision_suffixes() {
+        let (_dir, names) = migrate_files(&[
+            // Already recorded under the current format in the same second as a legacy clip
+            "bark_20240501T183007.000+0200_short.wav",
+            "bark_20240501_06_30_07_pm.wav",
+            "bark_20240501_06_30_07_pm_part2.wav",
+        ]);
+        assert_eq!(
+            names,
+            [
+                "bark_20240501T183007.000+0200-1.wav",
+                "bark_20240501T183007.000+0200-1_part2.wav",
+                "bark_20240501T183007.000+0200_short.wav",
+            ],
+        );
+    }
+
+    #[test]
+    fn the_sidecar_start_takes_precedence() {
+        let dir = tempfile::tempdir().unwrap();
+        // Part 2's sidecar has the episode's exact start, part 1 has none
+        let part2 = dir.path().join("bark_20240501_06_30_07_pm_part2.wav");
+        fs::write(dir.path().join("bark_20240501_06_30_07_pm.wav"), b"").unwrap();
+        fs::write(&part2, b"").unwrap();
+        sidecar("2024-05-01T18:30:06.250+02:00").write(&part2).unwrap();
+
+        migrate(dir.path(), &MigrateArgs { dry_run: false }, &Berlin).unwrap();
+        assert_eq!(
+            file_names(dir.path()),
+            [
+                "bark_20240501T183006.250+0200.wav",
+                "bark_20240501T183006.250+0200_part2.json",
+                "bark_20240501T183006.250+0200_part2.wav",
+            ],
+        );
+    }
+
+    #[test]
+    fn unreadable_names_are_left_in_place() {
+        let (_dir, names) = migrate_files(&["bark_20240501_18_30_07_pm.wav", "bark_20240501_06_30_07_pm.wav"]);
+        assert_eq!(names, ["bark_20240501T183007.000+0200.wav", "bark_20240501_18_30_07_pm.wav"]);
+    }
+
+    #[test]
+    fn dry_run_renames_nothing() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("bark_20240501_06_30_07_pm.wav"), b"").unwrap();
+        migrate(dir.path(), &MigrateArgs { dry_run: true }, &Berlin).unwrap();
+        assert_eq!(file_names(dir.path()), ["bark_20240501_06_30_07_pm.wav"]);
+    }
+}
@@ -0,0 +1,290 @@
+use chrono::{DateTime, FixedOffset, Local, LocalResult, NaiveDateTime, TimeZone};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::ffi::OsString;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+/// Current clip time stamp: 24-hour local time with milliseconds and the UTC offset, e.g.
+/// `20240501T183007.984+0200`. Unambiguous across DST changes and sortable within one offset.
+const STAMP_FORMAT: &str = "%Y%m%dT%H%M%S%.3f%z";
+const STAMP_LEN: usize = "20240501T183007.984+0200".len();
+/// Stamp used before the current one: 12-hour local time to the second, no offset.
+const LEGACY_STAMP_FORMAT: &str = "%Y%m%d_%I_%M_%S_%P";
+/// Added to a clip's file name while it's being written, and dropped once it's finalized.
+pub const PARTIAL_SUFFIX: &str = ".partial";
+
+/// How clips are stored, which gives them their file extension.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ClipFormat {
+    #[default]
+    Wav,
+    /// Lossless, typically around half the size of the WAV
+    Flac,
+    /// Lossy Ogg/Opus, a tenth of the size or less
+    Opus,
+}
+
+impl ClipFormat {
+    pub const ALL: [ClipFormat; 3] = [ClipFormat::Wav, ClipFormat::Flac, ClipFormat::Opus];
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            ClipFormat::Wav => "wav",
+            ClipFormat::Flac => "flac",
+            ClipFormat::Opus => "opus",
+        }
+    }
+}
+
+/// The parts of a clip's file name:
+/// `bark_<stamp>[-<collision>][_part<n>][_short].<wav|flac|opus>`.
+///
+/// Every part of an episode shares the episode's stamp and collision number.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ClipName {
+    /// Start of the episode
+    pub start: DateTime<FixedOffset>,
+    /// Disambiguates episodes that would otherwise get the same stamp. 0 for the first one.
+    pub collision: u32,
+    pub part: u32,
+    pub short: bool,
+    pub format: ClipFormat,
+    /// Parsed from the old 12-hour name format
+    pub legacy: bool,
+}
+
+impl ClipName {
+    pub fn new<Tz: TimeZone>(start: DateTime<Tz>) -> Self
+    where
+        Tz::Offset: fmt::Display,
+    {
+        Self {
+            // Round to the stamp's resolution so parsing the name gives back the same time
+            start: DateTime::parse_from_str(&start.format(STAMP_FORMAT).to_string(), STAMP_FORMAT)
+                .expect("formatted stamps parse"),
+            collision: 0,
+            part: 1,
+            short: false,
+            format: ClipFormat::Wav,
+            legacy: false,
+        }
+    }
+
+    /// Parse a clip file name in either the current or the legacy format.
+    pub fn parse(file_name: &str) -> Option<Self> {
+        Self::parse_in(file_name, &Local)
+    }
+
+    /// Like `parse`, reading legacy stamps as local time in `tz`.
+    pub fn parse_in<Tz: TimeZone>(file_name: &str, tz: &Tz) -> Option<Self> {
+        let rest = file_name.strip_prefix("bark_")?;
+        let (mut rest, format) = ClipFormat::ALL.into_iter().find_map(|format| {
+            let rest = rest.strip_suffix(format.extension())?.strip_suffix('.')?;
+            Some((rest, format))
+        })?;
+        let short = match rest.strip_suffix("_short") {
+            Some(r) => {
+                rest = r;
+                true
+            }
+            None => false,
+        };
+        let mut part = 1;
+        if let Some((r, n)) = rest.rsplit_once("_part")
+            && let Ok(n) = n.parse()
+        {
+            rest = r;
+            part = n;
+        }
+
+        if let Ok(naive) = NaiveDateTime::parse_from_str(rest, LEGACY_STAMP_FORMAT) {
+            // Legacy stamps are local time without an offset
+            let start = earliest(tz, &naive)?.fixed_offset();
+            return Some(Self { start, collision: 0, part, short, format, legacy: true });
+        }
+
+        let stamp = rest.get(..STAMP_LEN)?;
+        let start = DateTime::parse_from_str(stamp, STAMP_FORMAT).ok()?;
+        let collision = match &rest[STAMP_LEN..] {
+            "" => 0,
+            suffix => suffix.strip_prefix('-')?.parse().ok()?,
+        };
+        Some(Self { start, collision, part, short, format, legacy: false })
+    }
+
+    /// Name of the episode, shared by all its parts: `<stamp>[-<collision>]`.
+    pub fn episode(&self) -> String {
+        let mut name = self.start.format(STAMP_FORMAT).to_string();
+        if self.collision > 0 {
+            name.push_str(&format!("-{}", self.collision));
+        }
+        name
+    }
+
+    /// File name in the current format.
+    pub fn file_name(&self) -> String {
+        let mut name = format!("bark_{}", self.episode());
+        if self.part > 1 {
+            name.push_str(&format!("_part{}", self.part));
+        }
+        if self.short {
+            name.push_str("_short");
+        }
+        name.push('.');
+        name.push_str(self.format.extension());
+        name
+    }
+
+    /// Names that can't be given to another episode with the same stamp and collision number,
+    /// since they'd read as the same episode: flagged short or not, in any format.
+    pub fn clashes(&self) -> impl Iterator<Item = ClipName> + '_ {
+        [false, true].into_iter().flat_map(move |short| {
+            ClipFormat::ALL.into_iter().map(move |format| ClipName { short, format, ..self.clone() })
+        })
+    }
+}
+
+/// `bark_x.wav` -> `bark_x.wav.partial`, where the clip is written until it's complete.
+pub fn partial_path(clip: &Path) -> PathBuf {
+    let mut name = OsString::from(clip.as_os_str());
+    name.push(PARTIAL_SUFFIX);
+    PathBuf::from(name)
+}
+
+/// The first instant a local wall-clock time refers to. During the DST fall-back hour a time
+/// happens twice. In the spring-forward gap it never happens at all, and is read with the
+/// offset from before the gap, as a clock that missed the change would have shown it.
+pub fn earliest_local(naive: &NaiveDateTime) -> Option<DateTime<Local>> {
+    earliest(&Local, naive)
+}
+
+fn earliest<Tz: TimeZone>(tz: &Tz, naive: &NaiveDateTime) -> Option<DateTime<Tz>> {
+    // Don't rely on the order of `Ambiguous`, it isn't the same on every platform
+    let resolve = |naive: &NaiveDateTime| match tz.from_local_datetime(naive) {
+        LocalResult::Single(time) => Some(time),
+        LocalResult::Ambiguous(a, b) => Some(a.min(b)),
+        LocalResult::None => None,
+    };
+    resolve(naive).or_else(|| {
+        // An hour earlier is before the gap, and no gap is longer than that
+        let hour = chrono::Duration::hours(1);
+        resolve(&(*naive - hour)).map(|time| time + hour)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{NaiveDate, Timelike, Utc};
+    use chrono_tz::Europe::Berlin;
+
+    fn stamp(s: &str) -> DateTime<FixedOffset> {
+        DateTime::parse_from_str(s, STAMP_FORMAT).unwrap()
+    }
+
+    #[test]
+    fn file_names_parse_back() {
+        let start = stamp("20240501T183007.984+0200");
+        for (collision, part, short, format) in [
+            (0, 1, false, ClipFormat::Wav),
+            (0, 3, false, ClipFormat::Flac),
+            (2, 1, true, ClipFormat::Wav),
+            (1, 12, true, ClipFormat::Flac),
+            (0, 2, false, ClipFormat::Opus),
+        ] {
+            let name = ClipName { start, collision, part, short, format, legacy: false };
+            assert_eq!(ClipName::parse(&name.file_name()), Some(name));
+        }
+    }
+
+    #[test]
+    fn file_name_format() {
+        let name = ClipName {
+            start: stamp("20240501T183007.984-0530"),
+            collision: 1,
+            part: 2,
+            short: true,
+            format: ClipFormat::Flac,
+            legacy: false,
+        };
+        assert_eq!(name.file_name(), "bark_20240501T183007.984-0530-1_part2_short.flac");
+        assert_eq!(name.episode(), "20240501T183007.984-0530-1");
+    }
+
+    #[test]
+    fn new_rounds_to_the_stamp() {
+        let start = Local::now();
+        let name = ClipName::new(start);
+        assert_eq!(name.start.timestamp_millis(), start.timestamp_millis());
+        assert_eq!(ClipName::parse(&name.file_name()), Some(name));
+    }
+
+    #[test]
+    fn legacy_names() {
+        let name = ClipName::parse("bark_20240501_06_30_07_pm.wav").unwrap();
+        assert!(name.legacy);
+        assert_eq!((name.part, name.short, name.collision), (1, false, 0));
+        let naive = name.start.naive_local();
+        assert_eq!(naive.date(), NaiveDate::from_ymd_opt(2024, 5, 1).unwrap());
+        assert_eq!((naive.hour(), naive.minute(), naive.second()), (18, 30, 7));
+
+        let name = ClipName::parse("bark_20240501_12_05_00_am_part3_short.wav").unwrap();
+        assert!(name.legacy);
+        assert_eq!((name.part, name.short), (3, true));
+        assert_eq!(name.start.naive_local().hour(), 0);
+
+        // Renamed to the current format, they're no longer legacy
+        let current = ClipName { legacy: false, ..name.clone() };
+        assert_eq!(ClipName::parse(&name.file_name()), Some(current));
+    }
+
+    #[test]
+    fn rejects_other_names() {
+        for file_name in [
+            "bark_.wav",
+            "bark_20240501T183007.984+0200.mp3",
+            "bark_20240501T183007.984+0200-x.wav",
+            "bark_20240501T183007+0200.wav",
+            "bark_20240501_18_30_07_pm.wav",
+            "bark_20240501T183007.984+0200.json",
+            "bark_20240501T183007.984+0200.wav.partial",
+            "levels_2024-05-01.csv",
+        ] {
+            assert_eq!(ClipName::parse(file_name), None, "{}", file_name);
+        }
+    }
+
+    #[test]
+    fn partial_path_appends_the_suffix() {
+        assert_eq!(
+            partial_path(Path::new("barks/bark_x.flac")),
+            PathBuf::from("barks/bark_x.flac.partial"),
+        );
+    }
+
+    #[test]
+    fn earliest_takes_the_first_of_a_repeated_hour() {
+        // Clocks went back from 03:00 to 02:00 CEST on 27 October 2024
+        let naive = NaiveDate::from_ymd_opt(2024, 10, 27).unwrap().and_hms_opt(2, 30, 0).unwrap();
+        let time = earliest(&Berlin, &naive).unwrap();
+        assert_eq!(time.naive_local(), naive);
+        assert_eq!(time.with_timezone(&Utc).hour(), 0);
+    }
+
+    #[test]
+    fn earliest_moves_skipped_times_past_the_gap() {
+        // Clocks went forward from 02:00 to 03:00 CET on 31 March 2024
+        let date = NaiveDate::from_ymd_opt(2024, 3, 31).unwrap();
+        let time = earliest(&Berlin, &date.and_hms_opt(2, 30, 0).unwrap()).unwrap();
+        assert_eq!(time.naive_local(), date.and_hms_opt(3, 30, 0).unwrap());
+        assert_eq!(time.with_timezone(&Utc).naive_utc(), date.and_hms_opt(1, 30, 0).unwrap());
+
+        let before = earliest(&Berlin, &date.and_hms_opt(1, 59, 59).unwrap()).unwrap();
+        let after = earliest(&Berlin, &date.and_hms_opt(3, 0, 0).unwrap()).unwrap();
+        let start_of_gap = earliest(&Berlin, &date.and_hms_opt(2, 0, 0).unwrap()).unwrap();
+        assert_eq!(start_of_gap, after);
+        assert_eq!(after - before, chrono::Duration::seconds(1));
+    }
+}
@@ -0,0 +1,130 @@
+use std::collections::VecDeque;
+
+/// RMS frame length used for noise floor estimation, in seconds.
+const FRAME_SECS: f64 = 0.1;
+/// Lowest floor reported, so digital silence doesn't drive the threshold to zero (-80 dBFS).
+const MIN_FLOOR: f32 = 1e-4;
+
+/// Tracks the ambient noise floor as a low percentile of recent frame RMS levels.
+///
+/// Barks are short and loud compared to the background, so a low percentile over a long window
+/// follows the furnace or open windows while mostly ignoring the barking itself.
+pub struct NoiseFloor {
+    frame_len: usize,
+    frame_sum: f64,
+    frame_count: usize,
+    history: VecDeque<f32>,
+    history_len: usize,
+    // Copy of the history for picking the percentile, which reorders it
+    scratch: Vec<f32>,
+    percentile: f64,
+    floor: Option<f32>,
+}
+
+impl NoiseFloor {
+    pub fn new(channels: usize, sample_rate: u32, window_secs: f64, percentile: f64) -> Self {
+        let history_len = ((window_secs / FRAME_SECS) as usize).max(1);
+        Self {
+            frame_len: ((sample_rate as f64 * FRAME_SECS) as usize * channels).max(1),
+            frame_sum: 0.0,
+            frame_count: 0,
+            history: VecDeque::with_capacity(history_len),
+            history_len,
+            scratch: Vec::with_capacity(history_len),
+            percentile: percentile.clamp(0.0, 100.0),
+            floor: None,
+        }
+    }
+
+    /// Feed interleaved samples. Returns true when the floor estimate changed.
+    pub fn push(&mut self, data: &[f32]) -> bool {
+        let mut updated = false;
+        for &sample in data {
+            self.frame_sum += (sample * sample) as f64;
+            self.frame_count += 1;
+            if self.frame_count == self.frame_len {
+                let rms = (self.frame_sum / self.frame_len as f64).sqrt() as f32;
+                self.frame_sum = 0.0;
+                self.frame_count = 0;
+                if self.history.len() == self.history_len {
+                    self.history.pop_front();
+                }
+                self.history.push_back(rms);
+                self.update_floor();
+                updated = true;
+            }
+        }
+        updated
+    }
+
+    fn update_floor(&mut self) {
+        self.scratch.clear();
+        self.scratch.extend(self.history.iter().copied());
+        let index = ((self.scratch.len() - 1) as f64 * self.percentile / 100.0).round() as usize;
+        let (_, &mut value, _) = self.scratch.select_nth_unstable_by(index, |a, b| a.total_cmp(b));
+        self.floor = Some(value.max(MIN_FLOOR));
+    }
+
+    /// Current estimate in linear amplitude, `None` until the first frame is complete.
+    pub fn floor(&self) -> Option<f32> {
+        self.floor
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_signal::sine;
+
+    const RATE: u32 = 1000;
+
+    /// Feed `secs` of a sine at RMS `level` in 0.1 s buffers.
+    fn feed(floor: &mut NoiseFloor, level: f32, secs: usize) {
+        // The sine is at half scale, an RMS of 0.5 / √2
+        let gain = level * 2.0 * std::f32::consts::SQRT_2;
+        let tone: Vec<f32> = sine(50.0, RATE, secs * RATE as usize).iter().map(|s| s * gain).collect();
+        for buffer in tone.chunks(RATE as usize / 10) {
+            floor.push(buffer);
+        }
+    }
+
+    fn assert_near(floor: &NoiseFloor, level: f32) {
+        let floor = floor.floor().unwrap();
+        assert!((floor / level - 1.0).abs() < 0.01, "floor {} rather than {}", floor, level);
+    }
+
+    #[test]
+    fn floor_follows_a_step_in_the_ambient_level() {
+        let mut floor = NoiseFloor::new(1, RATE, 10.0, 20.0);
+        assert_eq!(floor.floor(), None);
+        feed(&mut floor, 0.01, 10);
+        assert_near(&floor, 0.01);
+
+        // Louder: it holds until the new level fills all but the bottom fifth of the window
+        feed(&mut floor, 0.1, 7);
+        assert_near(&floor, 0.01);
+        feed(&mut floor, 0.1, 2);
+        assert_near(&floor, 0.1);
+
+        // Quieter: it drops as soon as a fifth of the window is quiet
+        feed(&mut floor, 0.02, 3);
+        assert_near(&floor, 0.02);
+    }
+
+    #[test]
+    fn floor_ignores_short_loud_bursts() {
+        let mut floor = NoiseFloor::new(1, RATE, 10.0, 20.0);
+        for _ in 0..5 {
+            feed(&mut floor, 0.01, 1);
+            feed(&mut floor, 0.5, 1);
+        }
+        assert_near(&floor, 0.01);
+    }
+
+    #[test]
+    fn digital_silence_has_a_floor() {
+        let mut floor = NoiseFloor::new(2, RATE, 1.0, 20.0);
+        assert!(floor.push(&vec![0.0; 2 * RATE as usize / 10]));
+        assert_eq!(floor.floor(), Some(MIN_FLOOR));
+    }
+}
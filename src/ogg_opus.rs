@@ -0,0 +1,152 @@
+//! Reading Ogg/Opus clips. Decoding needs the `opus` feature, without it every clip is
+//! reported as unsupported.
+
+#[cfg(feature = "opus")]
+use audiopus::coder::Decoder;
+#[cfg(feature = "opus")]
+use audiopus::packet::Packet;
+#[cfg(feature = "opus")]
+use audiopus::{Channels, MutSignals, SampleRate};
+#[cfg(feature = "opus")]
+use ogg::reading::PacketReader;
+#[cfg(feature = "opus")]
+use std::fs::File;
+use std::io;
+#[cfg(feature = "opus")]
+use std::io::BufReader;
+use std::path::Path;
+
+/// Granule positions and pre-skip count samples at 48 kHz, whatever rate the audio was encoded at.
+pub const GRANULE_RATE: u32 = 48_000;
+#[cfg(feature = "opus")]
+/// Longest Opus packet, 120 ms, in samples per channel at 48 kHz.
+const MAX_PACKET_FRAMES: usize = 5760;
+
+/// An Ogg/Opus clip, decoded.
+pub struct OpusAudio {
+    /// Interleaved, in -1.0..=1.0
+    pub samples: Vec<f32>,
+    pub channels: u16,
+    pub sample_rate: u32,
+}
+
+/// The rates Opus encodes at natively. Any other rate is resampled to 48 kHz.
+#[cfg(feature = "opus")]
+pub fn opus_rate(sample_rate: u32) -> Option<SampleRate> {
+    match sample_rate {
+        8000 => Some(SampleRate::Hz8000),
+        12000 => Some(SampleRate::Hz12000),
+        16000 => Some(SampleRate::Hz16000),
+        24000 => Some(SampleRate::Hz24000),
+        48000 => Some(SampleRate::Hz48000),
+        _ => None,
+    }
+}
+
+/// Decode a whole Ogg/Opus file, at its original rate if Opus supports that and at 48 kHz if
+/// not. The encoder's delay is skipped and the padding of the last packet trimmed off. A file
+/// cut off by a crash decodes up to its last complete page.
+#[cfg(feature = "opus")]
+pub fn read(path: &Path) -> io::Result<OpusAudio> {
+    let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message.to_string());
+    let mut packets = PacketReader::new(BufReader::new(File::open(path)?));
+    let head = packets.read_packet_expected().map_err(|e| invalid(&e.to_string()))?.data;
+    if head.len() < 19 || &head[..8] != b"OpusHead" {
+        return Err(invalid("no OpusHead"));
+    }
+    let channels = match head[9] {
+        1 => Channels::Mono,
+        2 => Channels::Stereo,
+        _ => return Err(invalid("only mono and stereo Opus streams are supported")),
+    };
+    let pre_skip = u16::from_le_bytes([head[10], head[11]]) as u64;
+    let input_rate = u32::from_le_bytes(head[12..16].try_into().unwrap());
+    let rate = opus_rate(input_rate).unwrap_or(SampleRate::Hz48000);
+    let sample_rate = rate as i32 as u32;
+    // OpusTags, nothing in it is needed
+    packets.read_packet_expected().map_err(|e| invalid(&e.to_string()))?;
+
+    let mut decoder = Decoder::new(rate, channels).map_err(|e| invalid(&e.to_string()))?;
+    let per_frame = channels as usize;
+    let mut buffer = vec![0.0; MAX_PACKET_FRAMES * per_frame];
+    let mut samples = Vec::new();
+    let mut end_granule = None;
+    while let Ok(Some(packet)) = packets.read_packet() {
+        let Ok(input) = Packet::try_from(packet.data.as_slice()) else {
+            continue;
+        };
+        let output = MutSignals::try_from(buffer.as_mut_slice()).map_err(|e| invalid(&e.to_string()))?;
+        match decoder.decode_float(Some(input), output, false) {
+            Ok(frames) => samples.extend_from_slice(&buffer[..frames * per_frame]),
+            Err(_) => break,
+        }
+        if packet.last_in_stream() {
+            end_granule = Some(packet.absgp_page());
+            break;
+        }
+    }
+
+    let to_frames = |granule: u64| (granule * sample_rate as u64 / GRANULE_RATE as u64) as usize * per_frame;
+    if let Some(end) = end_granule {
+        samples.truncate(to_frames(end));
+    }
+    samples.drain(..to_frames(pre_skip).min(samples.len()));
+    Ok(OpusAudio { samples, channels: per_frame as u16, sample_rate })
+}
+
+/// Built without the `opus` feature there's no decoder.
+#[cfg(not(feature = "opus"))]
+pub fn read(_path: &Path) -> io::Result<OpusAudio> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "built without Opus support (the opus feature)"))
+}
+
+#[cfg(all(test, feature = "opus"))]
+mod tests {
+    use super::*;
+    use ogg::writing::{PacketWriteEndInfo, PacketWriter};
+
+    /// An Ogg file of the given packets, one page each.
+    fn ogg_file(packets: &[Vec<u8>]) -> tempfile::NamedTempFile {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        let mut writer = PacketWriter::new(file.as_file_mut());
+        for (i, packet) in packets.iter().enumerate() {
+            let info = if i + 1 == packets.len() { PacketWriteEndInfo::EndStream } else { PacketWriteEndInfo::EndPage };
+            writer.write_packet(packet.clone().into_boxed_slice(), 1, info, 0).unwrap();
+        }
+        file
+    }
+
+    fn head(channels: u8, sample_rate: u32) -> Vec<u8> {
+        let mut head = b"OpusHead".to_vec();
+        head.extend_from_slice(&[1, channels, 0, 0]);
+        head.extend_from_slice(&sample_rate.to_le_bytes());
+        head.extend_from_slice(&[0, 0, 0]);
+        head
+    }
+
+    #[test]
+    fn opus_rates_are_kept_and_others_are_not() {
+        assert_eq!(opus_rate(16_000), Some(SampleRate::Hz16000));
+        assert_eq!(opus_rate(48_000), Some(SampleRate::Hz48000));
+        assert_eq!(opus_rate(44_100), None);
+        assert_eq!(opus_rate(0), None);
+    }
+
+    #[test]
+    fn refuses_streams_that_are_not_opus() {
+        let vorbis = ogg_file(&[b"\x01vorbis and then some".to_vec(), Vec::new()]);
+        assert_eq!(read(vorbis.path()).err().unwrap().kind(), io::ErrorKind::InvalidData);
+        // Channel mapping family 0 only goes up to stereo
+        let surround = ogg_file(&[head(6, 48_000), b"OpusTags".to_vec()]);
+        assert_eq!(read(surround.path()).err().unwrap().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn stream_without_audio_decodes_to_nothing() {
+        let empty = ogg_file(&[head(2, 44_100), b"OpusTags".to_vec()]);
+        let audio = read(empty.path()).unwrap();
+        // Not an Opus rate, so it decodes at 48 kHz
+        assert_eq!((audio.channels, audio.sample_rate), (2, 48_000));
+        assert!(audio.samples.is_empty());
+    }
+}
@@ -0,0 +1,291 @@
+use audiopus::coder::Encoder;
+use audiopus::{Application, Bitrate, Channels, SampleRate};
+use ogg::writing::{PacketWriteEndInfo, PacketWriter};
+use std::io::{self, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use ranger_recorder_rs::ogg_opus::{opus_rate, GRANULE_RATE};
+
+use crate::preprocess::Resampler;
+
+/// Opus frames per second, 20 ms each. The encoder's usual frame length for general audio.
+const FRAMES_PER_SEC: u32 = 50;
+/// Packets on each Ogg page. A page only reaches the file once it's complete, so a crash loses
+/// at most about a second.
+const PACKETS_PER_PAGE: u64 = 50;
+/// Room for the largest packet, 1275 bytes per frame.
+const MAX_PACKET_BYTES: usize = 1500;
+
+/// Writes Ogg/Opus, encoding with libopus. Rates Opus doesn't support are resampled to 48 kHz,
+/// and as Opus' plain channel mapping stops at stereo, more channels are mixed down to mono.
+///
+/// OpusHead and OpusTags are written up front and each page as it fills, so like FLAC, a file
+/// cut off by a crash decodes up to its last complete page.
+pub struct OpusWriter<W: Write> {
+    packets: PacketWriter<W>,
+    serial: u32,
+    encoder: Encoder,
+    /// Channels of the samples written, and of the encoded audio
+    in_channels: usize,
+    channels: usize,
+    input_rate: u32,
+    /// Rate the encoder runs at
+    rate: u32,
+    resampler: Option<Resampler>,
+    /// The input frame being filled
+    frame: Vec<f32>,
+    /// Input, mixed down if needed, not yet passed on to be encoded
+    input: Vec<f32>,
+    /// Audio at the encoder's rate not yet encoded
+    pending: Vec<f32>,
+    input_frames: u64,
+    /// Frames encoded so far, at the encoder's rate
+    encoded_frames: u64,
+    /// Encoder delay at the start of the stream, at 48 kHz
+    pre_skip: u64,
+    packets_written: u64,
+    packet: Vec<u8>,
+}
+
+impl<W: Write> OpusWriter<W> {
+    pub fn new(writer: W, channels: u16, sample_rate: u32, bitrate_kbps: u32) -> io::Result<Self> {
+        let in_channels = channels as usize;
+        if in_channels == 0 || sample_rate == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Opus clips can't have {} channels at {} Hz", channels, sample_rate),
+            ));
+        }
+        let (opus_channels, channels) = match in_channels {
+            2 => (Channels::Stereo, 2),
+            _ => (Channels::Mono, 1),
+        };
+        let (rate, resampler) = match opus_rate(sample_rate) {
+            Some(rate) => (rate, None),
+            None => (SampleRate::Hz48000, Some(Resampler::new(channels, sample_rate, GRANULE_RATE))),
+        };
+        let mut encoder = Encoder::new(rate, opus_channels, Application::Audio).map_err(io::Error::other)?;
+        encoder
+            .set_bitrate(Bitrate::BitsPerSecond(bitrate_kbps as i32 * 1000))
+            .map_err(io::Error::other)?;
+        let rate = rate as i32 as u32;
+        let lookahead = encoder.lookahead().map_err(io::Error::other)? as u64;
+        let pre_skip = lookahead * (GRANULE_RATE / rate) as u64;
+        // Only needs to tell this stream apart from others in the same file, and there are none
+        let serial = SystemTime::now().duration_since(UNIX_EPOCH).map_or(1, |d| d.subsec_nanos());
+
+        let mut packets = PacketWriter::new(writer);
+        let mut head = b"OpusHead".to_vec();
+        head.push(1); // version
+        head.push(channels as u8);
+        head.extend_from_slice(&(pre_skip as u16).to_le_bytes());
+        head.extend_from_slice(&sample_rate.to_le_bytes());
+        head.extend_from_slice(&0i16.to_le_bytes()); // output gain
+        head.push(0); // channel mapping family: mono or stereo
+        packets.write_packet(head.into_boxed_slice(), serial, PacketWriteEndInfo::EndPage, 0)?;
+        let vendor = concat!("ranger-recorder-rs ", env!("CARGO_PKG_VERSION"));
+        let mut tags = b"OpusTags".to_vec();
+        tags.extend_from_slice(&(vendor.len() as u32).to_le_bytes());
+        tags.extend_from_slice(vendor.as_bytes());
+        tags.extend_from_slice(&0u32.to_le_bytes()); // no comments
+        packets.write_packet(tags.into_boxed_slice(), serial, PacketWriteEndInfo::EndPage, 0)?;
+
+        Ok(Self {
+            packets,
+            serial,
+            encoder,
+            in_channels,
+            channels,
+            input_rate: sample_rate,
+            rate,
+            resampler,
+            frame: Vec::with_capacity(in_channels),
+            input: Vec::new(),
+            pending: Vec::new(),
+            input_frames: 0,
+            encoded_frames: 0,
+            pre_skip,
+            packets_written: 0,
+            packet: vec![0; MAX_PACKET_BYTES],
+        })
+    }
+
+    /// Channels of the samples it takes, which can be more than it encodes.
+    pub fn channels(&self) -> u16 {
+        self.in_channels as u16
+    }
+
+    /// Append one sample. Channels are interleaved.
+    pub fn write_sample(&mut self, sample: f32) -> io::Result<()> {
+        self.frame.push(sample);
+        if self.frame.len() < self.in_channels {
+            return Ok(());
+        }
+        if self.channels == self.in_channels {
+            self.input.extend_from_slice(&self.frame);
+        } else {
+            self.input.push(self.frame.iter().sum::<f32>() / self.in_channels as f32);
+        }
+        self.frame.clear();
+        self.input_frames += 1;
+        if self.input.len() >= (self.input_rate / FRAMES_PER_SEC).max(1) as usize * self.channels {
+            self.encode_input()?;
+        }
+        Ok(())
+    }
+
+    /// Encode what's left, padding the last packet with silence that the final granule
+    /// position tells decoders to trim off.
+    pub fn finalize(mut self) -> io::Result<()> {
+        self.encode_input()?;
+        if let Some(resampler) = &mut self.resampler {
+            resampler.flush(&mut self.pending);
+        }
+        let total_frames = (self.input_frames * self.rate as u64).div_ceil(self.input_rate as u64);
+        let keep = total_frames.saturating_sub(self.encoded_frames) as usize * self.channels;
+        self.pending.truncate(keep);
+        let packet_len = self.frame_len() * self.channels;
+        while self.pending.len() > packet_len {
+            self.encode_packet(None)?;
+        }
+        self.pending.resize(packet_len, 0.0);
+        let end = self.pre_skip + total_frames * (GRANULE_RATE / self.rate) as u64;
+        self.encode_packet(Some(end))?;
+        self.packets.into_inner().flush()
+    }
+
+    fn frame_len(&self) -> usize {
+        (self.rate / FRAMES_PER_SEC) as usize
+    }
+
+    /// Resample the input if needed, and encode every whole packet of it.
+    fn encode_input(&mut self) -> io::Result<()> {
+        match &mut self.resampler {
+            Some(resampler) => resampler.process(&self.input, &mut self.pending),
+            None => self.pending.extend_from_slice(&self.input),
+        }
+        self.input.clear();
+        while self.pending.len() >= self.frame_len() * self.channels {
+            self.encode_packet(None)?;
+        }
+        Ok(())
+    }
+
+    /// Encode the first packet's worth of pending audio. `end` is the granule position the
+    /// stream ends at, for its last packet.
+    fn encode_packet(&mut self, end: Option<u64>) -> io::Result<()> {
+        let samples = self.frame_len() * self.channels;
+        let len = self
+            .encoder
+            .encode_float(&self.pending[..samples], &mut self.packet)
+            .map_err(io::Error::other)?;
+        self.pending.drain(..samples);
+        self.encoded_frames += self.frame_len() as u64;
+        self.packets_written += 1;
+        let granule = self.pre_skip + self.encoded_frames * (GRANULE_RATE / self.rate) as u64;
+        let (info, granule) = match end {
+            Some(end) => (PacketWriteEndInfo::EndStream, end),
+            None if self.packets_written.is_multiple_of(PACKETS_PER_PAGE) => (PacketWriteEndInfo::EndPage, granule),
+            None => (PacketWriteEndInfo::NormalPacket, granule),
+        };
+        self.packets.write_packet(Box::from(&self.packet[..len]), self.serial, info, granule)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ranger_recorder_rs::ogg_opus::{self, OpusAudio};
+    use crate::test_signal::{sine, sines};
+    use std::fs::{self, File};
+    use std::path::Path;
+
+    fn write(path: &Path, samples: &[f32], channels: u16, sample_rate: u32) {
+        let mut writer = OpusWriter::new(File::create(path).unwrap(), channels, sample_rate, 64).unwrap();
+        for &sample in samples {
+            writer.write_sample(sample).unwrap();
+        }
+        writer.finalize().unwrap();
+    }
+
+    /// Frequency of a channel from its zero crossings, away from the ends.
+    fn frequency(audio: &OpusAudio, channel: usize) -> f32 {
+        let samples: Vec<f32> = audio.samples.iter().skip(channel).step_by(audio.channels as usize).copied().collect();
+        let middle = &samples[samples.len() / 4..samples.len() * 3 / 4];
+        let crossings = middle.windows(2).filter(|w| (w[0] >= 0.0) != (w[1] >= 0.0)).count();
+        crossings as f32 / 2.0 / (middle.len() as f32 / audio.sample_rate as f32)
+    }
+
+    #[test]
+    fn sine_round_trips_at_a_native_rate() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("clip.opus");
+        // Not a whole number of 20 ms packets
+        let input = sine(440.0, 16_000, 16_000 + 123);
+        write(&path, &input, 1, 16_000);
+
+        let audio = ogg_opus::read(&path).unwrap();
+        assert_eq!((audio.channels, audio.sample_rate), (1, 16_000));
+        assert_eq!(audio.samples.len(), input.len());
+        // Lossy, but lined up with the input once the encoder delay is skipped
+        let signal: f32 = input.iter().map(|s| s * s).sum();
+        let error: f32 = input.iter().zip(&audio.samples).map(|(a, b)| (a - b) * (a - b)).sum();
+        assert!(signal / error > 100.0, "SNR {:.1} dB", 10.0 * (signal / error).log10());
+    }
+
+    #[test]
+    fn other_rates_are_resampled_to_48k() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("clip.opus");
+        let frames = 22_050 + 7;
+        write(&path, &sines(&[440.0, 1000.0], 44_100, frames), 2, 44_100);
+
+        let audio = ogg_opus::read(&path).unwrap();
+        assert_eq!((audio.channels, audio.sample_rate), (2, 48_000));
+        assert_eq!(audio.samples.len() as u64, (frames as u64 * 48_000).div_ceil(44_100) * 2);
+        assert!((frequency(&audio, 0) - 440.0).abs() < 10.0, "{}", frequency(&audio, 0));
+        assert!((frequency(&audio, 1) - 1000.0).abs() < 20.0, "{}", frequency(&audio, 1));
+    }
+
+    #[test]
+    fn more_than_two_channels_are_mixed_to_mono() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("clip.opus");
+        write(&path, &sines(&[500.0; 4], 48_000, 4800), 4, 48_000);
+
+        let audio = ogg_opus::read(&path).unwrap();
+        assert_eq!((audio.channels, audio.sample_rate), (1, 48_000));
+        assert_eq!(audio.samples.len(), 4800);
+        assert!((frequency(&audio, 0) - 500.0).abs() < 20.0);
+    }
+
+    #[test]
+    fn read_decodes_a_resampled_mixdown() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("clip.opus");
+        // Six channels at 22.05 kHz, written as mono at 48 kHz
+        let frames = 11_025 + 31;
+        write(&path, &sines(&[700.0; 6], 22_050, frames), 6, 22_050);
+
+        let audio = ogg_opus::read(&path).unwrap();
+        assert_eq!((audio.channels, audio.sample_rate), (1, 48_000));
+        assert_eq!(audio.samples.len() as u64, (frames as u64 * 48_000).div_ceil(22_050));
+        assert!((frequency(&audio, 0) - 700.0).abs() < 20.0, "{}", frequency(&audio, 0));
+        let middle = &audio.samples[audio.samples.len() / 4..audio.samples.len() * 3 / 4];
+        let rms = (middle.iter().map(|s| s * s).sum::<f32>() / middle.len() as f32).sqrt();
+        assert!((rms - 0.5 / 2f32.sqrt()).abs() < 0.03, "{}", rms);
+    }
+
+    #[test]
+    fn cut_off_file_decodes_up_to_its_last_page() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("clip.opus");
+        write(&path, &sine(440.0, 48_000, 3 * 48_000), 1, 48_000);
+        let len = fs::metadata(&path).unwrap().len();
+        File::options().write(true).open(&path).unwrap().set_len(len * 2 / 3).unwrap();
+
+        let audio = ogg_opus::read(&path).unwrap();
+        let secs = audio.samples.len() as f32 / 48_000.0;
+        assert!((1.0..2.0).contains(&secs), "{}", secs);
+    }
+}
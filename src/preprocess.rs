@@ -0,0 +1,290 @@
+use std::f64::consts::PI;
+
+use crate::config::Config;
+
+/// Fraction of the output's Nyquist frequency the resampler passes, the rest is the filter's
+/// transition band.
+const ROLLOFF: f64 = 0.9;
+/// Zero crossings of the sinc kernel on each side. More gives a steeper filter for more work.
+const ZERO_CROSSINGS: f64 = 16.0;
+/// Precomputed kernel phases, the ones in between are interpolated.
+const PHASES: usize = 256;
+
+/// Optional downmix and resample stage between the input and the recorder, so clips, detection
+/// and the level log all see the same reduced signal.
+pub struct Preprocessor {
+    channels: usize,
+    sample_rate: u32,
+    /// Weight of each input channel in the mono mix, summing to 1. `None` keeps every channel.
+    downmix: Option<Vec<f32>>,
+    resampler: Option<Resampler>,
+    mixed: Vec<f32>,
+    resampled: Vec<f32>,
+}
+
+impl Preprocessor {
+    pub fn new(config: &Config, channels: usize, sample_rate: u32) -> Result<Self, String> {
+        // Checked on mono input too, where there's nothing to mix, so a wrong count isn't silently
+        // ignored
+        let weights = match config.downmix_weights.len() {
+            _ if !config.downmix => None,
+            0 => Some(vec![1.0; channels]),
+            n if n == channels => Some(config.downmix_weights.clone()),
+            n => {
+                return Err(format!(
+                    "downmix_weights has {} weights but the input has {} channels",
+                    n, channels
+                ));
+            }
+        };
+        let downmix = weights.filter(|_| channels > 1).map(|weights| {
+            let total: f64 = weights.iter().sum();
+            weights.iter().map(|w| (w / total) as f32).collect()
+        });
+        let out_channels = if downmix.is_some() { 1 } else { channels };
+        let resampler = (config.resample_hz > 0 && config.resample_hz != sample_rate)
+            .then(|| Resampler::new(out_channels, sample_rate, config.resample_hz));
+        // Without a fixed rate in the config, this is the first time it's known
+        config.check_cutoffs(resampler.as_ref().map_or(sample_rate, |_| config.resample_hz))?;
+        Ok(Self {
+            channels,
+            sample_rate,
+            downmix,
+            resampler,
+            mixed: Vec::new(),
+            resampled: Vec::new(),
+        })
+    }
+
+    /// Channels of the processed signal.
+    pub fn channels(&self) -> usize {
+        if self.downmix.is_some() { 1 } else { self.channels }
+    }
+
+    /// Sample rate of the processed signal.
+    pub fn sample_rate(&self) -> u32 {
+        self.resampler.as_ref().map_or(self.sample_rate, |r| r.out_rate as u32)
+    }
+
+    /// e.g. `48000 Hz, 2 channels -> 16000 Hz mono`, or `None` if the input passes through.
+    pub fn describe(&self) -> Option<String> {
+        if self.downmix.is_none() && self.resampler.is_none() {
+            return None;
+        }
+        let channels = |n: usize| if n == 1 { "mono".to_string() } else { format!("{} channels", n) };
+        Some(format!(
+            "{} Hz, {} -> {} Hz, {}",
+            self.sample_rate,
+            channels(self.channels),
+            self.sample_rate(),
+            channels(self.channels()),
+        ))
+    }
+
+    /// Process a buffer of interleaved input frames. The resampler holds back a few
+    /// milliseconds of audio it needs to look ahead at, so the output isn't always the same
+    /// length and lags the input by that much.
+    pub fn process<'a>(&'a mut self, data: &'a [f32]) -> &'a [f32] {
+        let mixed = match &self.downmix {
+            Some(weights) => {
+                self.mixed.clear();
+                self.mixed.extend(
+                    data.chunks_exact(self.channels)
+                        .map(|frame| frame.iter().zip(weights).map(|(s, w)| s * w).sum::<f32>()),
+                );
+                &self.mixed
+            }
+            None => data,
+        };
+        match &mut self.resampler {
+            Some(resampler) => {
+                self.resampled.clear();
+                resampler.process(mixed, &mut self.resampled);
+                &self.resampled
+            }
+            None => mixed,
+        }
+    }
+}
+
+/// Windowed-sinc sample rate converter for any pair of rates. The kernel's cutoff sits below
+/// the lower of the two Nyquist frequencies, so nothing above it folds back when downsampling.
+pub struct Resampler {
+    channels: usize,
+    in_rate: u64,
+    out_rate: u64,
+    /// Kernel half-width in input samples
+    half_width: usize,
+    /// Taps for each of `PHASES + 1` fractional positions, `2 * half_width` per phase
+    kernel: Vec<f32>,
+    /// Input not yet used up, per channel, starting `half_width` samples of silence early
+    history: Vec<Vec<f32>>,
+    /// Input position of the next output sample within `history`, in steps of `1 / out_rate`
+    /// input samples, so the ratio is exact and never drifts
+    next: u64,
+}
+
+impl Resampler {
+    pub fn new(channels: usize, in_rate: u32, out_rate: u32) -> Self {
+        // Cutoff as a fraction of the input rate
+        let cutoff = 0.5 * ROLLOFF * (out_rate as f64 / in_rate as f64).min(1.0);
+        let half_width = (ZERO_CROSSINGS / (2.0 * cutoff)).ceil() as usize;
+        let taps = 2 * half_width;
+        let mut kernel = Vec::with_capacity((PHASES + 1) * taps);
+        for phase in 0..=PHASES {
+            let fraction = phase as f64 / PHASES as f64;
+            let row: Vec<f64> = (0..taps)
+                .map(|j| {
+                    // Distance from the output position to input sample j of the window
+                    let x = fraction + half_width as f64 - 1.0 - j as f64;
+                    sinc(2.0 * cutoff * x) * blackman(x / half_width as f64)
+                })
+                .collect();
+            // Unity gain at DC for every phase
+            let sum: f64 = row.iter().sum();
+            kernel.extend(row.iter().map(|&h| (h / sum) as f32));
+        }
+        Self {
+            channels,
+            in_rate: in_rate as u64,
+            out_rate: out_rate as u64,
+            half_width,
+            kernel,
+            history: vec![vec![0.0; half_width]; channels],
+            next: half_width as u64 * out_rate as u64,
+        }
+    }
+
+    pub fn process(&mut self, data: &[f32], out: &mut Vec<f32>) {
+        for frame in data.chunks_exact(self.channels) {
+            for (history, &sample) in self.history.iter_mut().zip(frame) {
+                history.push(sample);
+            }
+        }
+        let taps = 2 * self.half_width;
+        let available = self.history[0].len();
+        loop {
+            let index = (self.next / self.out_rate) as usize;
+            // The window reaches `half_width` samples past the output position
+            if index + self.half_width >= available {
+                break;
+            }
+            let position = (self.next % self.out_rate) as f64 / self.out_rate as f64 * PHASES as f64;
+            let phase = position as usize;
+            let weight = (position - phase as f64) as f32;
+            let (a, b) = (&self.kernel[phase * taps..][..taps], &self.kernel[(phase + 1) * taps..][..taps]);
+            let start = index + 1 - self.half_width;
+            for history in &self.history {
+                let window = &history[start..start + taps];
+                let y_a: f32 = window.iter().zip(a).map(|(x, h)| x * h).sum();
+                let y_b: f32 = window.iter().zip(b).map(|(x, h)| x * h).sum();
+                out.push(y_a + (y_b - y_a) * weight);
+            }
+            self.next += self.in_rate;
+        }
+
+        // Drop what no output sample will need again
+        let used = ((self.next / self.out_rate) as usize + 1).saturating_sub(self.half_width);
+        for history in &mut self.history {
+            history.drain(..used);
+        }
+        self.next -= used as u64 * self.out_rate;
+    }
+
+    /// Feed in enough silence to push out the audio held back for the lookahead, at the end of
+    /// the input. The output runs on a little past the input's length.
+    #[cfg(any(feature = "opus", test))]
+    pub fn flush(&mut self, out: &mut Vec<f32>) {
+        self.process(&vec![0.0; (self.half_width + 1) * self.channels], out);
+    }
+}
+
+fn sinc(x: f64) -> f64 {
+    if x == 0.0 { 1.0 } else { (PI * x).sin() / (PI * x) }
+}
+
+/// Blackman window over -1..=1.
+fn blackman(x: f64) -> f64 {
+    if x.abs() >= 1.0 {
+        return 0.0;
+    }
+    let t = (x + 1.0) / 2.0;
+    0.42 - 0.5 * (2.0 * PI * t).cos() + 0.08 * (4.0 * PI * t).cos()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_signal::sine;
+
+    /// Resample all of `input`, pushing it through in uneven buffers.
+    fn resample(input: &[f32], in_rate: u32, out_rate: u32) -> Vec<f32> {
+        let mut resampler = Resampler::new(1, in_rate, out_rate);
+        let mut out = Vec::new();
+        for chunk in input.chunks(333) {
+            resampler.process(chunk, &mut out);
+        }
+        resampler.flush(&mut out);
+        out
+    }
+
+    /// RMS away from the filter's ramp in and out at the ends.
+    fn rms(samples: &[f32]) -> f32 {
+        let middle = &samples[samples.len() / 4..samples.len() * 3 / 4];
+        (middle.iter().map(|s| s * s).sum::<f32>() / middle.len() as f32).sqrt()
+    }
+
+    #[test]
+    fn resampling_keeps_the_level_of_the_passband() {
+        for (in_rate, out_rate) in [(48_000, 16_000), (44_100, 48_000), (16_000, 44_100)] {
+            let input = sine(1000.0, in_rate, in_rate as usize);
+            let out = resample(&input, in_rate, out_rate);
+            // One second in, one second out, plus the flushed lookahead
+            assert!(out.len() >= out_rate as usize && out.len() < out_rate as usize * 11 / 10);
+            let gain = rms(&out) / rms(&input);
+            assert!((gain - 1.0).abs() < 0.01, "{} -> {} Hz: gain {}", in_rate, out_rate, gain);
+            let dc = resample(&vec![0.25; in_rate as usize], in_rate, out_rate);
+            assert!((dc[out_rate as usize / 2] - 0.25).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn resampling_down_rejects_what_would_alias() {
+        // 12 kHz is above the 8 kHz Nyquist frequency of 16 kHz, and would fold back to 4 kHz
+        let input = sine(12_000.0, 48_000, 48_000);
+        let out = resample(&input, 48_000, 16_000);
+        let rejection = 20.0 * (rms(&out) / rms(&input)).log10();
+        assert!(rejection < -60.0, "only {:.1} dB down", rejection);
+    }
+
+    #[test]
+    fn downmix_applies_the_weights() {
+        let config = |weights: Vec<f64>| Config { downmix: true, downmix_weights: weights, ..Config::default() };
+        let stereo = [0.4, -0.2, 0.8, 0.0];
+
+        let mut equal = Preprocessor::new(&config(Vec::new()), 2, 48_000).unwrap();
+        assert_eq!(equal.channels(), 1);
+        assert_eq!(equal.process(&stereo), [0.1, 0.4]);
+        let mut left = Preprocessor::new(&config(vec![1.0, 0.0]), 2, 48_000).unwrap();
+        assert_eq!(left.process(&stereo), [0.4, 0.8]);
+        // Weights are relative, they don't need to add up to 1
+        let mut mostly_right = Preprocessor::new(&config(vec![1.0, 3.0]), 2, 48_000).unwrap();
+        let mixed = mostly_right.process(&stereo);
+        assert!((mixed[0] - (0.25 * 0.4 - 0.75 * 0.2)).abs() < 1e-6 && (mixed[1] - 0.2).abs() < 1e-6);
+
+        assert!(Preprocessor::new(&config(vec![1.0, 0.0, 0.0]), 2, 48_000).is_err());
+    }
+
+    #[test]
+    fn mono_input_passes_through() {
+        let config = Config { downmix: true, ..Config::default() };
+        let mut preprocessor = Preprocessor::new(&config, 1, 48_000).unwrap();
+        assert_eq!(preprocessor.describe(), None);
+        assert_eq!(preprocessor.process(&[0.1, 0.2]), [0.1, 0.2]);
+
+        let weighted = |weights: Vec<f64>| Config { downmix: true, downmix_weights: weights, ..Config::default() };
+        assert!(Preprocessor::new(&weighted(vec![1.0]), 1, 48_000).is_ok());
+        let err = Preprocessor::new(&weighted(vec![1.0, 0.0]), 1, 48_000).err().unwrap();
+        assert!(err.contains("2 weights but the input has 1 channels"), "{}", err);
+    }
+}
@@ -0,0 +1,45 @@
+use std::collections::VecDeque;
+
+/// Fixed-size ring of the most recent interleaved samples, flushed into each new clip so it
+/// starts before the threshold crossing.
+pub struct PreRollBuffer {
+    samples: VecDeque<f32>,
+    capacity: usize,
+    channels: usize,
+}
+
+impl PreRollBuffer {
+    pub fn new(frames: usize, channels: usize) -> Self {
+        let capacity = frames * channels;
+        Self {
+            samples: VecDeque::with_capacity(capacity),
+            capacity,
+            channels,
+        }
+    }
+
+    /// Append interleaved samples, dropping the oldest once full.
+    pub fn push(&mut self, data: &[f32]) {
+        if self.capacity == 0 {
+            return;
+        }
+        if data.len() >= self.capacity {
+            self.samples.clear();
+            self.samples.extend(&data[data.len() - self.capacity..]);
+            return;
+        }
+        let overflow = (self.samples.len() + data.len()).saturating_sub(self.capacity);
+        self.samples.drain(..overflow);
+        self.samples.extend(data);
+    }
+
+    /// Number of whole frames currently buffered.
+    pub fn frames(&self) -> usize {
+        self.samples.len() / self.channels.max(1)
+    }
+
+    /// Take the buffered samples, oldest first, leaving the buffer empty.
+    pub fn drain(&mut self) -> impl Iterator<Item = f32> + '_ {
+        self.samples.drain(..)
+    }
+}
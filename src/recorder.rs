@@ -0,0 +1,980 @@
+use chrono::{DateTime, Local};
+use std::fs::{self, File};
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+
+use ranger_recorder_rs::levels::LevelLog;
+use ranger_recorder_rs::naming::{self, ClipFormat, ClipName};
+use ranger_recorder_rs::sidecar::{Classification, ClipMetadata, FilterSettings};
+use ranger_recorder_rs::spectral::{self, FeatureExtractor};
+use ranger_recorder_rs::to_dbfs;
+
+use crate::config::{self, Config, NonDogAction, ResolvedFormat, ShortEventAction};
+use crate::detector::{self, BarkDetector, DetectorKind};
+use crate::filter::FilterChain;
+use crate::flac::FlacWriter;
+#[cfg(feature = "opus")]
+use crate::opus::OpusWriter;
+use crate::noise_floor::NoiseFloor;
+use crate::preroll::PreRollBuffer;
+
+/// Where the samples fed to a `Recorder` come from.
+pub struct Input {
+    /// Label of the source, empty when only one is recorded
+    pub source: String,
+    /// Device name, or the source file for offline detection
+    pub device: String,
+    pub session: String,
+    pub channels: usize,
+    pub sample_rate: u32,
+    /// The input's own sample format, used when the config asks for `native`
+    pub native_format: ResolvedFormat,
+}
+
+/// The threshold / silence-timeout state machine that turns a stream of samples into bark clips.
+///
+/// It only knows about sample counts, so the live recorder and offline detection drive it the
+/// same way: feed it buffers of interleaved samples along with the time they were captured.
+pub struct Recorder {
+    source: String,
+    device: String,
+    session: String,
+    detector_kind: DetectorKind,
+    detector: Box<dyn BarkDetector>,
+    /// High-pass, hum notches and band-pass in front of the detector
+    filters: Option<FilterChain>,
+    filter_settings: Option<FilterSettings>,
+    /// Whether clips get the filtered audio too, rather than what was captured
+    filter_recording: bool,
+    filtered: Vec<f32>,
+    /// Clips aren't started until the filters have settled
+    settle_frames: u64,
+    /// Score that keeps an open clip going. The detector's own threshold only starts clips.
+    sustain_threshold: f32,
+    min_attack_frames: u64,
+    silence_timeout_frames: u64,
+    min_event_frames: u64,
+    short_event_action: ShortEventAction,
+    classify: bool,
+    dog_threshold: f32,
+    non_dog_action: NonDogAction,
+    /// 0 means clips are never split
+    max_clip_frames: u64,
+    output_dir: PathBuf,
+    /// Clips aren't started while the output disk has less than this free
+    min_free_bytes: u64,
+    /// Whether the last clip was refused for lack of space, so it's only logged once
+    low_space: bool,
+    wav_format: ResolvedFormat,
+    clip_format: ClipFormat,
+    #[cfg(feature = "opus")]
+    opus_bitrate_kbps: u32,
+    channels: usize,
+    sample_rate: u32,
+    pre_roll: PreRollBuffer,
+    auto_threshold: Option<AutoThreshold>,
+    level_log: Option<LevelLog>,
+    clip: Option<Clip>,
+    frames_processed: u64,
+    last_bark_frame: u64,
+    /// Consecutive frames the detector has triggered on while no clip is open
+    loud_frames: u64,
+}
+
+impl Recorder {
+    pub fn new(config: &Config, input: Input) -> Self {
+        let Input { source, device, session, channels, sample_rate, native_format } = input;
+        let pre_roll_frames = (sample_rate as f64 * config.pre_roll_secs) as usize;
+        let filter_settings = config.filter_settings();
+        let filters = filter_settings.as_ref().and_then(|s| FilterChain::new(s, channels, sample_rate));
+        Self {
+            source,
+            device,
+            session,
+            detector_kind: config.detector,
+            detector: detector::from_config(config, channels, sample_rate),
+            settle_frames: filters.as_ref().map_or(0, FilterChain::settle_frames),
+            filters,
+            filter_recording: config.filter_recording,
+            filter_settings,
+            filtered: Vec::new(),
+            sustain_threshold: config.sustain_threshold.unwrap_or(config.threshold) as f32,
+            min_attack_frames: (sample_rate as f64 * config.min_attack_ms / 1000.0) as u64,
+            silence_timeout_frames: (sample_rate as f64 * config.silence_timeout_secs) as u64,
+            min_event_frames: (sample_rate as f64 * config.min_event_secs) as u64,
+            short_event_action: config.short_event_action,
+            classify: config.classify,
+            dog_threshold: config.dog_threshold as f32,
+            non_dog_action: config.non_dog_action,
+            max_clip_frames: (sample_rate as f64 * config.max_clip_secs) as u64,
+            output_dir: config.output_dir.clone(),
+            min_free_bytes: config.min_free_mb * 1024 * 1024,
+            low_space: false,
+            wav_format: match config.clip_format {
+                ClipFormat::Wav => config.wav_format.resolve(native_format),
+                ClipFormat::Flac => config.wav_format.resolve(native_format).for_flac(),
+                // Opus encodes from floats, whatever the sample format
+                ClipFormat::Opus => ResolvedFormat::Float32,
+            },
+            clip_format: config.clip_format,
+            #[cfg(feature = "opus")]
+            opus_bitrate_kbps: config.opus_bitrate_kbps,
+            channels,
+            sample_rate,
+            pre_roll: PreRollBuffer::new(pre_roll_frames, channels),
+            auto_threshold: config.auto_threshold.then(|| AutoThreshold::new(config, channels, sample_rate)),
+            level_log: (config.level_log_secs > 0.0).then(|| {
+                LevelLog::new(&config.output_dir, channels, sample_rate, config.level_log_secs)
+            }),
+            clip: None,
+            frames_processed: 0,
+            last_bark_frame: 0,
+            loud_frames: 0,
+        }
+    }
+
+    /// Take up the detection settings of another schedule window's profile without reopening
+    /// the input. An open clip carries on under the new settings.
+    pub fn retune(&mut self, config: &Config) {
+        self.min_attack_frames = (self.sample_rate as f64 * config.min_attack_ms / 1000.0) as u64;
+        self.silence_timeout_frames = (self.sample_rate as f64 * config.silence_timeout_secs) as u64;
+        self.min_event_frames = (self.sample_rate as f64 * config.min_event_secs) as u64;
+        match (&mut self.auto_threshold, config.auto_threshold) {
+            // Keep the noise floor heard so far
+            (Some(auto), true) => auto.set_margins(config),
+            (None, true) => self.auto_threshold = Some(AutoThreshold::new(config, self.channels, self.sample_rate)),
+            (_, false) => self.auto_threshold = None,
+        }
+        let (start, sustain) = self.auto_threshold.as_ref().and_then(AutoThreshold::thresholds).unwrap_or((
+            config.threshold as f32,
+            config.sustain_threshold.unwrap_or(config.threshold) as f32,
+        ));
+        self.detector.set_threshold(start);
+        self.sustain_threshold = sustain;
+    }
+
+    /// Process one buffer of interleaved samples whose first frame was captured at `captured_at`.
+    pub fn process(&mut self, data: &[f32], captured_at: DateTime<Local>) {
+        let Some(filters) = &mut self.filters else {
+            self.detect_and_record(data, data, captured_at);
+            return;
+        };
+        let mut filtered = std::mem::take(&mut self.filtered);
+        filters.process(data, &mut filtered);
+        let recorded = if self.filter_recording { &filtered[..] } else { data };
+        self.detect_and_record(&filtered, recorded, captured_at);
+        self.filtered = filtered;
+    }
+
+    /// Run the detector, noise floor and level log on `heard`, and write `recorded` to clips.
+    /// They're the same audio unless filters are on and only apply to detection.
+    fn detect_and_record(&mut self, heard: &[f32], recorded: &[f32], captured_at: DateTime<Local>) {
+        if let Some(auto) = &mut self.auto_threshold
+            && let Some((start, sustain)) = auto.update(heard, self.frames_processed)
+        {
+            self.detector.set_threshold(start);
+            self.sustain_threshold = sustain;
+        }
+        if let Some(log) = &mut self.level_log {
+            log.push(heard, captured_at, self.detector.threshold());
+        }
+        let frames = (heard.len() / self.channels) as u64;
+        let detection = self.detector.process(heard);
+        let barking = detection.triggered || detection.score > self.sustain_threshold;
+        if self.clip.is_none() {
+            // Only open a clip once it's been loud for long enough, so single clicks don't count
+            let triggered = detection.triggered && self.frames_processed >= self.settle_frames;
+            self.loud_frames = if triggered { self.loud_frames + frames } else { 0 };
+            if triggered && self.loud_frames >= self.min_attack_frames {
+                self.start_clip(captured_at, detection.score);
+                self.last_bark_frame = self.frames_processed;
+                self.loud_frames = 0;
+            }
+        } else if let Some(clip) = &mut self.clip {
+            let loud = detection.score > self.sustain_threshold;
+            if loud {
+                // Reset the timer when we hear another bark
+                self.last_bark_frame = self.frames_processed;
+                // A bark spans several buffers, only count the first
+                if !clip.was_loud {
+                    clip.trigger_resets += 1;
+                }
+            }
+            clip.was_loud = loud;
+        }
+        self.frames_processed += frames;
+
+        if let Some(clip) = &mut self.clip {
+            // Only the barking is classified, not the quiet between barks
+            if barking && let Some(features) = &mut clip.features {
+                features.push(recorded);
+            }
+            if let Err(e) = clip.write(self.wav_format, recorded.iter().copied()) {
+                eprintln!("Failed to write {}, ending the clip early: {}", clip.path.display(), e);
+                self.finish();
+                return;
+            }
+            // Only stop recording if we haven't heard a bark for the silence timeout
+            if self.frames_processed - self.last_bark_frame > self.silence_timeout_frames {
+                self.finish();
+            } else if self.max_clip_frames > 0 && clip.part_frames >= self.max_clip_frames {
+                self.roll_over();
+            }
+        } else {
+            // Keep the most recent audio around for the start of the next clip
+            self.pre_roll.push(recorded);
+        }
+    }
+
+    /// Close the clip in progress, if any, ending its episode: the episode is classified as a
+    /// whole and the verdict applied to every part of it.
+    pub fn finish(&mut self) {
+        let Some(clip) = self.clip.take() else {
+            return;
+        };
+        let mut metadata = self.metadata(&clip);
+        let mut path = clip.path.clone();
+        let partial = naming::partial_path(&path);
+        if let Err(e) = clip.writer.finalize() {
+            eprintln!("Failed to finalize {}, removing it: {}", partial.display(), e);
+            discard(&partial);
+            // The earlier parts are complete, and still get the verdict on the whole episode
+            if !clip.earlier_parts.is_empty() {
+                let kept = self.judge(&mut metadata, &path);
+                self.judge_earlier_parts(&clip.earlier_parts, &metadata, kept);
+            }
+            return;
+        }
+
+        // Rolled-over episodes are long by definition, only single clips can be too short
+        let event_frames = self.last_bark_frame - clip.event_start_frame;
+        if clip.name.part == 1 && event_frames < self.min_event_frames {
+            let event_secs = event_frames as f64 / self.sample_rate as f64;
+            match self.short_event_action {
+                ShortEventAction::Discard => {
+                    if discard(&partial) {
+                        println!("Discarded short event ({:.1}s): {}", event_secs, path.display());
+                    }
+                    return;
+                }
+                ShortEventAction::Flag => {
+                    path = self.clip_path(&ClipName { short: true, ..clip.name.clone() });
+                    println!("Finished recording, flagged short event ({:.1}s): {}", event_secs, path.display());
+                    metadata.short = true;
+                }
+            }
+        } else {
+            println!("Finished recording");
+        }
+        let kept = self.judge(&mut metadata, &path);
+        if !kept {
+            discard(&partial);
+        } else if publish(&partial, &path) {
+            write_metadata(&metadata, &path);
+        }
+        self.judge_earlier_parts(&clip.earlier_parts, &metadata, kept);
+    }
+
+    fn start_clip(&mut self, captured_at: DateTime<Local>, score: f32) {
+        // Create output directory if it doesn't exist
+        if let Err(e) = fs::create_dir_all(&self.output_dir) {
+            eprintln!("Failed to create {}, skipping clip: {}", self.output_dir.display(), e);
+            return;
+        }
+        if !self.has_space() {
+            return;
+        }
+
+        // The clip starts with the pre-roll, so stamp it with when that audio was heard
+        let pre_roll_frames = self.pre_roll.frames() as u64;
+        let pre_roll_length = chrono::Duration::milliseconds(
+            pre_roll_frames as i64 * 1000 / self.sample_rate as i64
+        );
+        let start = captured_at - pre_roll_length;
+        let mut name = ClipName { format: self.clip_format, ..ClipName::new(start) };
+        // Two episodes can start within the same millisecond when clips are cut short, or
+        // when offline detection is rerun into the same directory
+        while name.clashes().any(|clash| {
+            let path = self.clip_path(&clash);
+            path.exists() || naming::partial_path(&path).exists()
+        }) {
+            name.collision += 1;
+        }
+        let path = self.clip_path(&name);
+        println!("Started recording: {} (score {:.3})", path.display(), score);
+        let Some(writer) = self.create_writer(&path) else {
+            return;
+        };
+        let mut clip = Clip {
+            event_start_frame: self.frames_processed,
+            features: self.feature_extractor(),
+            ..Clip::new(writer, path, name, start, start, self.detector.threshold(), self.sustain_threshold)
+        };
+        if let Err(e) = clip.write(self.wav_format, self.pre_roll.drain()) {
+            eprintln!("Failed to write {}, skipping clip: {}", clip.path.display(), e);
+            return;
+        }
+        self.clip = Some(clip);
+    }
+
+    /// Close the current part of a long episode and carry on in the next one. The episode is
+    /// classified as a whole once it ends, so the part is kept until then. If the next part
+    /// can't be opened, the episode ends with this one.
+    fn roll_over(&mut self) {
+        let Some(name) = self.clip.as_ref().map(|clip| ClipName { part: clip.name.part + 1, ..clip.name.clone() })
+        else {
+            return;
+        };
+        let path = self.clip_path(&name);
+        let Some(writer) = self.has_space().then(|| self.create_writer(&path)).flatten() else {
+            self.finish();
+            return;
+        };
+        println!("Continuing recording: {}", path.display());
+
+        let clip = self.clip.take().expect("checked above");
+        let mut metadata = self.metadata(&clip);
+        metadata.classification = None;
+        let Clip { writer: finished_writer, path: finished, episode_start, event_start_frame, was_loud, features, mut earlier_parts, .. } =
+            clip;
+        let partial = naming::partial_path(&finished);
+        if let Err(e) = finished_writer.finalize() {
+            eprintln!("Failed to finalize {}, removing it: {}", partial.display(), e);
+            discard(&partial);
+        } else if publish(&partial, &finished) {
+            write_metadata(&metadata, &finished);
+            earlier_parts.push(finished);
+        }
+        self.clip = Some(Clip {
+            event_start_frame,
+            was_loud,
+            features,
+            earlier_parts,
+            ..Clip::new(
+                writer,
+                path,
+                name,
+                episode_start,
+                metadata.end.with_timezone(&Local),
+                self.detector.threshold(),
+                self.sustain_threshold,
+            )
+        });
+    }
+
+    fn metadata(&self, clip: &Clip) -> ClipMetadata {
+        let end = clip.start + chrono::Duration::microseconds(
+            (clip.part_frames as f64 * 1_000_000.0 / self.sample_rate as f64) as i64
+        );
+        let samples = clip.part_frames * self.channels as u64;
+        ClipMetadata {
+            session: self.session.clone(),
+            start: clip.start.fixed_offset(),
+            end: end.fixed_offset(),
+            episode_start: clip.episode_start.fixed_offset(),
+            part: clip.name.part,
+            short: false,
+            source: self.source.clone(),
+            device: self.device.clone(),
+            sample_rate: self.sample_rate,
+            channels: self.channels as u16,
+            detector: self.detector_kind.name().to_string(),
+            threshold: clip.threshold,
+            sustain_threshold: clip.sustain_threshold,
+            peak: clip.peak,
+            rms: if samples > 0 { (clip.sum_squares / samples as f64).sqrt() as f32 } else { 0.0 },
+            trigger_resets: clip.trigger_resets,
+            filters: self.filter_settings.clone(),
+            classification: clip.features.as_ref().and_then(FeatureExtractor::features).map(|features| {
+                let dog_score = spectral::dog_score(&features);
+                Classification { dog_score, dog: dog_score >= self.dog_threshold, features }
+            }),
+            non_dog: false,
+            recovered: false,
+            keep: false,
+        }
+    }
+
+    fn feature_extractor(&self) -> Option<FeatureExtractor> {
+        self.classify.then(|| FeatureExtractor::new(self.channels, self.sample_rate))
+    }
+
+    /// Apply `non_dog_action` to the metadata of a clip the classifier says isn't a dog. Returns
+    /// whether the clip is kept, it's up to the caller to remove it if not.
+    fn judge(&self, metadata: &mut ClipMetadata, path: &Path) -> bool {
+        let Some(classification) = &metadata.classification else {
+            return true;
+        };
+        if classification.dog {
+            return true;
+        }
+        let score = classification.dog_score;
+        match self.non_dog_action {
+            NonDogAction::Keep => true,
+            NonDogAction::Tag => {
+                println!("Not a dog (score {:.2}), tagged: {}", score, path.display());
+                metadata.non_dog = true;
+                true
+            }
+            NonDogAction::Drop => {
+                println!("Dropped, not a dog (score {:.2}): {}", score, path.display());
+                false
+            }
+        }
+    }
+
+    /// Give the earlier parts of a finished episode the classification of the whole episode,
+    /// and remove them if its last part was dropped.
+    fn judge_earlier_parts(&self, parts: &[PathBuf], last: &ClipMetadata, kept: bool) {
+        for part in parts {
+            if !kept {
+                let sidecar = ClipMetadata::path_for(part);
+                match fs::remove_file(part).and_then(|()| fs::remove_file(&sidecar)) {
+                    Ok(()) => println!("Dropped with the rest of its episode: {}", part.display()),
+                    Err(e) => eprintln!("Failed to remove {}: {}", part.display(), e),
+                }
+                continue;
+            }
+            let Some(mut metadata) = ClipMetadata::read(part) else {
+                continue;
+            };
+            metadata.classification = last.classification.clone();
+            metadata.non_dog = last.non_dog;
+            write_metadata(&metadata, part);
+        }
+    }
+
+    /// Whether there's room on the output disk for another clip, logging when that changes.
+    fn has_space(&mut self) -> bool {
+        if self.min_free_bytes == 0 {
+            return true;
+        }
+        let available = match fs2::available_space(&self.output_dir) {
+            Ok(available) => available,
+            Err(e) => {
+                // Not knowing is no reason to stop recording
+                eprintln!("Failed to check free space in {}: {}", self.output_dir.display(), e);
+                return true;
+            }
+        };
+        let enough = available >= self.min_free_bytes;
+        if !enough && !self.low_space {
+            eprintln!(
+                "Not recording: only {} MB free in {} (min_free_mb = {}), waiting for space",
+                available / (1024 * 1024),
+                self.output_dir.display(),
+                self.min_free_bytes / (1024 * 1024),
+            );
+        } else if enough && self.low_space {
+            println!("Free space in {} recovered, recording again", self.output_dir.display());
+        }
+        self.low_space = !enough;
+        enough
+    }
+
+    /// Open the `.partial` file a clip is written to until it's complete.
+    fn create_writer(&self, path: &Path) -> Option<ClipWriter> {
+        let spec = self.wav_format.spec(self.channels as u16, self.sample_rate);
+        let partial = naming::partial_path(path);
+        let writer = match self.clip_format {
+            ClipFormat::Wav => hound::WavWriter::create(&partial, spec).map(ClipWriter::Wav),
+            ClipFormat::Flac => File::create(&partial)
+                .and_then(|file| {
+                    FlacWriter::new(BufWriter::new(file), spec.channels, spec.sample_rate, spec.bits_per_sample as u32)
+                })
+                .map(ClipWriter::Flac)
+                .map_err(hound::Error::from),
+            #[cfg(feature = "opus")]
+            ClipFormat::Opus => File::create(&partial)
+                .and_then(|file| {
+                    OpusWriter::new(BufWriter::new(file), spec.channels, spec.sample_rate, self.opus_bitrate_kbps)
+                })
+                .map(|writer| ClipWriter::Opus(Box::new(writer)))
+                .map_err(hound::Error::from),
+            // Refused by `Config::validate`
+            #[cfg(not(feature = "opus"))]
+            ClipFormat::Opus => Err(hound::Error::Unsupported),
+        };
+        writer.map_err(|e| eprintln!("Failed to create {}: {}", partial.display(), e)).ok()
+    }
+
+    fn clip_path(&self, name: &ClipName) -> PathBuf {
+        self.output_dir.join(name.file_name())
+    }
+}
+
+/// Give a finalized clip its real name, so a clip only ever appears complete. If that fails
+/// the `.partial` file is left for `repair`.
+fn publish(partial: &Path, clip: &Path) -> bool {
+    match fs::rename(partial, clip) {
+        Ok(()) => true,
+        Err(e) => {
+            eprintln!("Failed to rename {} to {}: {}", partial.display(), clip.display(), e);
+            false
+        }
+    }
+}
+
+/// Remove a `.partial` file that won't be published. Returns whether it's gone.
+fn discard(partial: &Path) -> bool {
+    match fs::remove_file(partial) {
+        Ok(()) => true,
+        Err(e) => {
+            eprintln!("Failed to remove {}: {}", partial.display(), e);
+            false
+        }
+    }
+}
+
+fn write_metadata(metadata: &ClipMetadata, clip: &Path) {
+    if let Err(e) = metadata.write(clip) {
+        eprintln!("Failed to write metadata for {}: {}", clip.display(), e);
+    }
+}
+
+/// Encodes a clip in the configured format.
+enum ClipWriter {
+    Wav(hound::WavWriter<BufWriter<File>>),
+    Flac(FlacWriter<BufWriter<File>>),
+    #[cfg(feature = "opus")]
+    Opus(Box<OpusWriter<BufWriter<File>>>),
+}
+
+impl ClipWriter {
+    fn write_sample(&mut self, format: ResolvedFormat, sample: f32) -> hound::Result<()> {
+        match self {
+            ClipWriter::Wav(writer) => format.write_sample(writer, sample),
+            ClipWriter::Flac(writer) => Ok(writer.write_sample(config::quantize(sample, writer.bits_per_sample()))?),
+            #[cfg(feature = "opus")]
+            ClipWriter::Opus(writer) => Ok(writer.write_sample(sample)?),
+        }
+    }
+
+    fn channels(&self) -> u16 {
+        match self {
+            ClipWriter::Wav(writer) => writer.spec().channels,
+            ClipWriter::Flac(writer) => writer.channels(),
+            #[cfg(feature = "opus")]
+            ClipWriter::Opus(writer) => writer.channels(),
+        }
+    }
+
+    fn finalize(self) -> hound::Result<()> {
+        match self {
+            ClipWriter::Wav(writer) => writer.finalize(),
+            ClipWriter::Flac(writer) => Ok(writer.finalize()?),
+            #[cfg(feature = "opus")]
+            ClipWriter::Opus(writer) => Ok(writer.finalize()?),
+        }
+    }
+}
+
+/// The file currently being written, and the episode it belongs to.
+struct Clip {
+    writer: ClipWriter,
+    /// Where the clip goes once it's finalized, it's written next to it as `.partial` until then
+    path: PathBuf,
+    /// Episode name shared by every part, with this part's number
+    name: ClipName,
+    episode_start: DateTime<Local>,
+    /// When the first frame of this part was heard
+    start: DateTime<Local>,
+    /// Frames written to this part so far
+    part_frames: u64,
+    /// Frame of the trigger that opened the episode
+    event_start_frame: u64,
+    threshold: f32,
+    sustain_threshold: f32,
+    peak: f32,
+    sum_squares: f64,
+    trigger_resets: u32,
+    /// Whether the last buffer was above the sustain threshold
+    was_loud: bool,
+    /// Features of the episode's barking so far, when clips are classified
+    features: Option<FeatureExtractor>,
+    /// Parts of the episode already published, waiting for it to be classified
+    earlier_parts: Vec<PathBuf>,
+}
+
+impl Clip {
+    fn new(
+        writer: ClipWriter,
+        path: PathBuf,
+        name: ClipName,
+        episode_start: DateTime<Local>,
+        start: DateTime<Local>,
+        threshold: f32,
+        sustain_threshold: f32,
+    ) -> Self {
+        Self {
+            writer,
+            path,
+            name,
+            episode_start,
+            start,
+            part_frames: 0,
+            event_start_frame: 0,
+            threshold,
+            sustain_threshold,
+            peak: 0.0,
+            sum_squares: 0.0,
+            trigger_resets: 0,
+            // Clips are opened by a trigger
+            was_loud: true,
+            features: None,
+            earlier_parts: Vec::new(),
+        }
+    }
+
+    /// Append interleaved samples, keeping track of the clip's length and levels.
+    fn write(&mut self, format: ResolvedFormat, samples: impl Iterator<Item = f32>) -> hound::Result<()> {
+        let mut written = 0;
+        for sample in samples {
+            self.writer.write_sample(format, sample)?;
+            self.peak = self.peak.max(sample.abs());
+            self.sum_squares += (sample * sample) as f64;
+            written += 1;
+        }
+        self.part_frames += written / self.writer.channels() as u64;
+        Ok(())
+    }
+}
+
+fn db_to_gain(db: f64) -> f32 {
+    10f32.powf(db as f32 / 20.0)
+}
+
+/// Keeps the start and sustain thresholds a fixed margin above the ambient noise floor.
+struct AutoThreshold {
+    noise_floor: NoiseFloor,
+    margin: f32,
+    sustain_margin: f32,
+    log_interval_frames: u64,
+    next_log_frame: u64,
+}
+
+impl AutoThreshold {
+    fn new(config: &Config, channels: usize, sample_rate: u32) -> Self {
+        let mut auto = Self {
+            noise_floor: NoiseFloor::new(
+                channels,
+                sample_rate,
+                config.noise_floor_window_secs,
+                config.noise_floor_percentile,
+            ),
+            margin: 1.0,
+            sustain_margin: 1.0,
+            log_interval_frames: (sample_rate as f64 * config.noise_floor_log_secs) as u64,
+            next_log_frame: 0,
+        };
+        auto.set_margins(config);
+        auto
+    }
+
+    fn set_margins(&mut self, config: &Config) {
+        self.margin = db_to_gain(config.auto_margin_db);
+        self.sustain_margin = db_to_gain(config.auto_sustain_margin_db.unwrap_or(config.auto_margin_db));
+    }
+
+    /// `(start, sustain)` thresholds for the current floor estimate, once there is one.
+    fn thresholds(&self) -> Option<(f32, f32)> {
+        let floor = self.noise_floor.floor()?;
+        Some(((floor * self.margin).min(1.0), (floor * self.sustain_margin).min(1.0)))
+    }
+
+    /// Returns the new `(start, sustain)` thresholds whenever the floor estimate moves.
+    fn update(&mut self, data: &[f32], frame: u64) -> Option<(f32, f32)> {
+        if !self.noise_floor.push(data) {
+            return None;
+        }
+        let floor = self.noise_floor.floor()?;
+        let (threshold, sustain) = self.thresholds()?;
+
+        if frame >= self.next_log_frame {
+            println!(
+                "Noise floor {:.1} dBFS, threshold {:.1} dBFS ({:.4}), sustain {:.1} dBFS",
+                to_dbfs(floor),
+                to_dbfs(threshold),
+                threshold,
+                to_dbfs(sustain),
+            );
+            self.next_log_frame = frame + self.log_interval_frames.max(1);
+        }
+        Some((threshold, sustain))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    const RATE: u32 = 1000;
+    /// Frames per buffer, 0.1 s
+    const BUFFER: usize = 100;
+    const QUIET: f32 = 0.01;
+    const LOUD: f32 = 0.9;
+
+    fn config(dir: &Path) -> Config {
+        Config {
+            threshold: 0.5,
+            silence_timeout_secs: 1.0,
+            pre_roll_secs: 0.5,
+            max_clip_secs: 0.0,
+            level_log_secs: 0.0,
+            min_free_mb: 0,
+            output_dir: dir.to_path_buf(),
+            ..Config::default()
+        }
+    }
+
+    fn recorder(config: &Config) -> Recorder {
+        Recorder::new(config, Input {
+            source: String::new(),
+            device: "test".to_string(),
+            session: "test".to_string(),
+            channels: 1,
+            sample_rate: RATE,
+            native_format: ResolvedFormat::Int16,
+        })
+    }
+
+    fn t0() -> DateTime<Local> {
+        Local.with_ymd_and_hms(2024, 5, 1, 18, 30, 0).unwrap()
+    }
+
+    /// Feed `(level, secs)` stretches of constant level in buffers of `BUFFER` frames, then close
+    /// any open clip.
+    fn feed(recorder: &mut Recorder, stretches: &[(f32, f64)]) {
+        feed_wave(recorder, stretches, |_| 1.0);
+    }
+
+    /// Like `feed`, with each stretch's level scaling `wave` of the frame number.
+    fn feed_wave(recorder: &mut Recorder, stretches: &[(f32, f64)], wave: impl Fn(u64) -> f32) {
+        feed_from(recorder, &mut 0, stretches, wave);
+        recorder.finish();
+    }
+
+    /// Like `feed_wave` from `frame` on, advancing it and leaving any clip open.
+    fn feed_from(recorder: &mut Recorder, frame: &mut u64, stretches: &[(f32, f64)], wave: impl Fn(u64) -> f32) {
+        for &(level, secs) in stretches {
+            for _ in 0..(secs * RATE as f64 / BUFFER as f64).round() as usize {
+                let captured_at = t0() + chrono::Duration::milliseconds((*frame * 1000 / RATE as u64) as i64);
+                let buffer: Vec<f32> = (*frame..*frame + BUFFER as u64).map(|i| level * wave(i)).collect();
+                recorder.process(&buffer, captured_at);
+                *frame += BUFFER as u64;
+            }
+        }
+    }
+
+    /// A 125 Hz square wave, a hum nothing like a bark.
+    fn hum(frame: u64) -> f32 {
+        if frame % 8 < 4 { 1.0 } else { -1.0 }
+    }
+
+    /// Finished clips in `dir`, oldest first, with their samples and sidecars.
+    fn clips(dir: &Path) -> Vec<(ClipName, Vec<f32>, ClipMetadata)> {
+        let mut clips: Vec<_> = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter_map(|path| {
+                let name = ClipName::parse(path.file_name()?.to_str()?)?;
+                let samples = hound::WavReader::open(&path)
+                    .unwrap()
+                    .into_samples::<i16>()
+                    .map(|s| s.unwrap() as f32 / i16::MAX as f32)
+                    .collect();
+                Some((name, samples, ClipMetadata::read(&path).unwrap()))
+            })
+            .collect();
+        clips.sort_by_key(|(name, _, _)| (name.start, name.collision, name.part));
+        clips
+    }
+
+    #[test]
+    fn clip_starts_with_the_pre_roll_and_ends_after_the_silence_timeout() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(dir.path());
+        feed(&mut recorder(&config), &[(QUIET, 2.0), (LOUD, 0.3), (QUIET, 3.0)]);
+
+        let clips = clips(dir.path());
+        assert_eq!(clips.len(), 1);
+        let (name, samples, metadata) = &clips[0];
+        // Triggered by the buffer at 2.0 s, with 0.5 s of pre-roll before it
+        assert_eq!(name.start, (t0() + chrono::Duration::milliseconds(1500)).fixed_offset());
+        assert_eq!(metadata.start, name.start);
+        assert!(samples[..500].iter().all(|&s| (s - QUIET).abs() < 1e-3));
+        assert!(samples[500..800].iter().all(|&s| (s - LOUD).abs() < 1e-3));
+        // The last loud buffer starts at 2.2 s, the first buffer more than 1 s after that one
+        // starts at 3.2 s and is the last one written
+        assert_eq!(samples.len(), 500 + 1300);
+        assert_eq!(metadata.duration_secs(), 1.8);
+        assert!(samples[800..].iter().all(|&s| (s - QUIET).abs() < 1e-3));
+        assert_eq!(metadata.peak, LOUD);
+    }
+
+    #[test]
+    fn pre_roll_is_cut_short_at_the_start_of_the_input() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(dir.path());
+        feed(&mut recorder(&config), &[(QUIET, 0.2), (LOUD, 0.1), (QUIET, 2.0)]);
+
+        let clips = clips(dir.path());
+        assert_eq!(clips.len(), 1);
+        let (name, samples, _) = &clips[0];
+        assert_eq!(name.start, t0().fixed_offset());
+        assert!(samples[..200].iter().all(|&s| (s - QUIET).abs() < 1e-3));
+        assert!((samples[200] - LOUD).abs() < 1e-3);
+    }
+
+    #[test]
+    fn min_attack_ignores_a_single_loud_buffer() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config { min_attack_ms: 250.0, ..config(dir.path()) };
+        feed(&mut recorder(&config), &[(QUIET, 1.0), (LOUD, 0.1), (QUIET, 2.0), (LOUD, 0.2), (QUIET, 2.0)]);
+        assert!(clips(dir.path()).is_empty());
+
+        // Loud for long enough, the clip opens on the third loud buffer
+        feed(&mut recorder(&config), &[(QUIET, 1.0), (LOUD, 0.3), (QUIET, 2.0)]);
+        let clips = clips(dir.path());
+        assert_eq!(clips.len(), 1);
+        assert_eq!(clips[0].0.start, (t0() + chrono::Duration::milliseconds(700)).fixed_offset());
+    }
+
+    #[test]
+    fn retune_takes_up_a_profile_without_a_new_recorder() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(dir.path());
+        let mut recorder = recorder(&config);
+        feed(&mut recorder, &[(QUIET, 1.0), (0.3, 0.2), (QUIET, 2.0)]);
+        assert!(clips(dir.path()).is_empty());
+
+        recorder.retune(&Config { threshold: 0.2, sustain_threshold: Some(0.1), ..config.clone() });
+        feed(&mut recorder, &[(QUIET, 1.0), (0.3, 0.2), (QUIET, 2.0)]);
+        let clips = clips(dir.path());
+        assert_eq!(clips.len(), 1);
+        assert_eq!((clips[0].2.threshold, clips[0].2.sustain_threshold), (0.2, 0.1));
+    }
+
+    #[test]
+    fn barks_within_the_silence_timeout_share_a_clip() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(dir.path());
+        feed(&mut recorder(&config), &[
+            (QUIET, 1.0),
+            (LOUD, 0.1),
+            (QUIET, 0.9),
+            (LOUD, 0.1),
+            (QUIET, 2.0),
+            (LOUD, 0.1),
+            (QUIET, 2.0),
+        ]);
+
+        let clips = clips(dir.path());
+        assert_eq!(clips.len(), 2);
+        let (first, _, first_metadata) = &clips[0];
+        let (second, _, _) = &clips[1];
+        assert_eq!(first.start, (t0() + chrono::Duration::milliseconds(500)).fixed_offset());
+        assert_eq!(first_metadata.trigger_resets, 1);
+        // The second bark is at 2.0 s, so the clip closes at 3.1 s, before the third one at 4.1 s
+        assert_eq!(first_metadata.end, (t0() + chrono::Duration::milliseconds(3100)).fixed_offset());
+        assert_eq!(second.start, (t0() + chrono::Duration::milliseconds(3600)).fixed_offset());
+    }
+
+    #[test]
+    fn trigger_resets_count_barks_not_buffers() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(dir.path());
+        feed(&mut recorder(&config), &[
+            (QUIET, 1.0),
+            (LOUD, 0.5),
+            (QUIET, 0.5),
+            (LOUD, 0.5),
+            (QUIET, 0.5),
+            (LOUD, 0.3),
+            (QUIET, 2.0),
+        ]);
+
+        let clips = clips(dir.path());
+        assert_eq!(clips.len(), 1);
+        assert_eq!(clips[0].2.trigger_resets, 2);
+    }
+
+    #[test]
+    fn long_episodes_roll_over_into_parts() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config { max_clip_secs: 1.0, ..config(dir.path()) };
+        feed(&mut recorder(&config), &[(QUIET, 1.0), (LOUD, 2.5), (QUIET, 2.0)]);
+
+        let clips = clips(dir.path());
+        let parts: Vec<u32> = clips.iter().map(|(name, _, _)| name.part).collect();
+        assert_eq!(parts, [1, 2, 3, 4]);
+        for (name, samples, metadata) in &clips {
+            assert_eq!(name.start, clips[0].0.start);
+            assert_eq!(metadata.episode_start, clips[0].2.start);
+            assert_eq!(metadata.part, name.part);
+            assert!(samples.len() <= 1000, "part {} is {} frames", name.part, samples.len());
+        }
+        // Each part picks up where the last one ended, without losing or repeating audio
+        for pair in clips.windows(2) {
+            assert_eq!(pair[1].2.start, pair[0].2.end);
+        }
+        let written: usize = clips.iter().map(|(_, samples, _)| samples.len()).sum();
+        // 0.5 s of pre-roll, 2.5 s of barking and 1 s of quiet until the silence timeout
+        assert_eq!(written, 500 + 2500 + 1000);
+        let loud = clips.iter().flat_map(|(_, samples, _)| samples).filter(|&&s| s > 0.5).count();
+        assert_eq!(loud, 2500);
+    }
+
+    #[test]
+    fn rolled_over_episodes_are_classified_as_a_whole() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config { max_clip_secs: 1.0, classify: true, non_dog_action: NonDogAction::Tag, ..config(dir.path()) };
+        feed_wave(&mut recorder(&config), &[(QUIET, 1.0), (LOUD, 2.5), (QUIET, 2.0)], hum);
+
+        let clips = clips(dir.path());
+        assert_eq!(clips.len(), 4);
+        let episode = clips[3].2.classification.clone().expect("the episode is classified");
+        assert!(!episode.dog);
+        for (name, _, metadata) in &clips {
+            assert!(metadata.non_dog, "part {} isn't tagged", name.part);
+            assert_eq!(metadata.classification.as_ref(), Some(&episode));
+        }
+    }
+
+    #[test]
+    fn dropping_an_episode_drops_every_part() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config { max_clip_secs: 1.0, classify: true, non_dog_action: NonDogAction::Drop, ..config(dir.path()) };
+        feed_wave(&mut recorder(&config), &[(QUIET, 1.0), (LOUD, 2.5), (QUIET, 2.0)], hum);
+
+        assert!(fs::read_dir(dir.path()).unwrap().next().is_none(), "left {:?}", fs::read_dir(dir.path()).unwrap().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn running_out_of_space_mid_episode_ends_it_with_the_part_so_far() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config { max_clip_secs: 1.0, classify: true, non_dog_action: NonDogAction::Tag, ..config(dir.path()) };
+        let mut recorder = recorder(&config);
+        let mut frame = 0;
+        // Part 1 is done and part 2 is open
+        feed_from(&mut recorder, &mut frame, &[(QUIET, 1.0), (LOUD, 1.0)], hum);
+        recorder.min_free_bytes = u64::MAX;
+        // Part 2 fills up with no room for part 3, and nothing more is recorded
+        feed_from(&mut recorder, &mut frame, &[(LOUD, 1.5), (QUIET, 2.0), (LOUD, 0.5), (QUIET, 2.0)], hum);
+        recorder.finish();
+
+        let files: Vec<_> = fs::read_dir(dir.path()).unwrap().map(|entry| entry.unwrap().file_name()).collect();
+        assert_eq!(files.len(), 4, "{:?}", files);
+        let clips = clips(dir.path());
+        let parts: Vec<u32> = clips.iter().map(|(name, _, _)| name.part).collect();
+        assert_eq!(parts, [1, 2]);
+        assert_eq!(clips[1].1.len(), 1000);
+        // Classified as the episode it turned out to be, both parts alike
+        let episode = clips[1].2.classification.clone().expect("the episode is classified");
+        for (name, _, metadata) in &clips {
+            assert!(metadata.non_dog, "part {} isn't tagged", name.part);
+            assert_eq!(metadata.classification.as_ref(), Some(&episode));
+        }
+    }
+}
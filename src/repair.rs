@@ -0,0 +1,507 @@
+use chrono::{DateTime, FixedOffset};
+use clap::Args;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+use ranger_recorder_rs::naming::{ClipFormat, ClipName, PARTIAL_SUFFIX};
+use ranger_recorder_rs::ogg_opus;
+use ranger_recorder_rs::sidecar::ClipMetadata;
+
+use crate::retention::{self, IN_USE};
+
+/// Fix clips left behind by a recorder that was killed or lost power mid-clip.
+#[derive(Args, Debug)]
+pub struct RepairArgs {
+    /// Print what would be repaired without touching anything
+    #[arg(long)]
+    pub dry_run: bool,
+}
+
+/// Repair everything under the output directory, except files written to in the last minute,
+/// which a running recorder may still have open.
+pub fn run(output_dir: &Path, args: &RepairArgs) -> Result<(), String> {
+    if repair_dir(output_dir, args.dry_run, true) == 0 {
+        println!("Nothing to repair under {}", output_dir.display());
+    }
+    Ok(())
+}
+
+/// Repair everything under the output directory before recording. None of this recorder's clips
+/// is open yet, so partials are recovered however recently they were written to, as they are
+/// after a crash and a quick restart.
+pub fn on_startup(output_dir: &Path) {
+    repair_dir(output_dir, false, false);
+}
+
+/// Fix the WAV headers of `.partial` clips and give them their real names and a sidecar, and fix
+/// clips whose headers don't match their length. With `skip_recent`, files written to in the last
+/// minute are skipped. Returns how many files needed it.
+fn repair_dir(dir: &Path, dry_run: bool, skip_recent: bool) -> usize {
+    let mut repaired = 0;
+    for entry in WalkDir::new(dir).into_iter().filter_map(|e| e.ok()) {
+        let Some(file_name) = entry.file_name().to_str() else {
+            continue;
+        };
+        let (partial, format) = match file_name.strip_suffix(PARTIAL_SUFFIX) {
+            Some(clip) => match ClipName::parse(clip) {
+                Some(name) => (Some(name.clone()), name.format),
+                None => continue,
+            },
+            None => match ClipName::parse(file_name) {
+                Some(name) => (None, name.format),
+                None => continue,
+            },
+        };
+        let path = entry.path();
+        if skip_recent && retention::modified_within(path, IN_USE) {
+            if partial.is_some() {
+                println!("Skipping {}, it may still be recording", path.display());
+            }
+            continue;
+        }
+        // Killed before the first buffer of it reached the disk
+        if partial.is_some() && fs::metadata(path).is_ok_and(|m| m.len() == 0) {
+            repaired += 1;
+            println!("Removing {}, no audio reached it", path.display());
+            if !dry_run && let Err(e) = fs::remove_file(path) {
+                eprintln!("Failed to remove {}: {}", path.display(), e);
+            }
+            continue;
+        }
+
+        // A FLAC or Ogg/Opus file's header is valid with the length unknown, and decoders stop
+        // at a frame or page that was cut off, so only the name needs fixing
+        let fix = match format {
+            ClipFormat::Wav => match check(path) {
+                Ok(fix) => fix,
+                Err(e) => {
+                    eprintln!("Can't repair {}: {}", path.display(), e);
+                    continue;
+                }
+            },
+            ClipFormat::Flac | ClipFormat::Opus => None,
+        };
+        let Some(name) = partial else {
+            // A finished clip only needs attention if its header is off
+            let Some(fix) = fix else {
+                continue;
+            };
+            repaired += 1;
+            println!("Repairing {} ({:.1}s of audio)", path.display(), fix.secs());
+            if !dry_run && let Err(e) = fix.apply(path) {
+                eprintln!("Failed to repair {}: {}", path.display(), e);
+            }
+            continue;
+        };
+
+        repaired += 1;
+        let clip_dir = path.parent().unwrap_or(dir);
+        let target = free_name(clip_dir, name.clone());
+        let secs = match (&fix, format) {
+            (Some(fix), _) => fix.secs(),
+            (None, _) => Audio::read(path, format).map_or(0.0, |audio| audio.secs()),
+        };
+        println!("Recovering {} -> {} ({:.1}s of audio)", path.display(), target.display(), secs);
+        if dry_run {
+            continue;
+        }
+        if let Some(fix) = fix
+            && let Err(e) = fix.apply(path)
+        {
+            eprintln!("Failed to repair {}: {}", path.display(), e);
+            continue;
+        }
+        if let Err(e) = fs::rename(path, &target) {
+            eprintln!("Failed to rename {} to {}: {}", path.display(), target.display(), e);
+            continue;
+        }
+        let Some(audio) = Audio::read(&target, format) else {
+            eprintln!("Can't read {}, recovered it without a sidecar", target.display());
+            continue;
+        };
+        let metadata = audio.metadata(&name, recovered_start(clip_dir, &name));
+        if let Err(e) = metadata.write(&target) {
+            eprintln!("Failed to write metadata for {}: {}", target.display(), e);
+        }
+    }
+    repaired
+}
+
+/// When a recovered part started: where the part before it ended, if that one has a sidecar.
+/// Otherwise, and for the first part, the episode start in its name.
+fn recovered_start(dir: &Path, name: &ClipName) -> DateTime<FixedOffset> {
+    if name.part > 1 {
+        let previous = ClipName { part: name.part - 1, short: false, ..name.clone() };
+        if let Some(metadata) = ClipMetadata::read(&dir.join(previous.file_name())) {
+            return metadata.end;
+        }
+    }
+    name.start
+}
+
+/// Where a recovered clip can go without replacing another, bumping the collision number like
+/// the recorder does.
+fn free_name(dir: &Path, mut name: ClipName) -> PathBuf {
+    while name.clashes().any(|clash| dir.join(clash.file_name()).exists()) {
+        name.collision += 1;
+    }
+    dir.join(name.file_name())
+}
+
+/// The sizes a WAV file's header should have, to match the audio actually in the file.
+struct HeaderFix {
+    /// Offset of the data chunk's size field, the audio follows it
+    data_size_offset: u64,
+    data_len: u32,
+    /// Bytes per second of audio, for logging
+    byte_rate: u32,
+}
+
+impl HeaderFix {
+    fn secs(&self) -> f64 {
+        self.data_len as f64 / self.byte_rate.max(1) as f64
+    }
+
+    /// Drop any incomplete frame at the end and write the sizes into the header.
+    fn apply(&self, path: &Path) -> io::Result<()> {
+        let mut file = OpenOptions::new().write(true).open(path)?;
+        let file_len = self.data_size_offset + 4 + self.data_len as u64;
+        file.set_len(file_len)?;
+        file.seek(SeekFrom::Start(4))?;
+        file.write_all(&((file_len - 8) as u32).to_le_bytes())?;
+        file.seek(SeekFrom::Start(self.data_size_offset))?;
+        file.write_all(&self.data_len.to_le_bytes())?;
+        file.sync_all()
+    }
+}
+
+/// Read the header of the WAV file at `path`. Returns the fix it needs, or `None` if its sizes
+/// already match what's in it.
+fn check(path: &Path) -> Result<Option<HeaderFix>, String> {
+    let mut file = File::open(path).map_err(|e| e.to_string())?;
+    let file_len = file.metadata().map_err(|e| e.to_string())?.len();
+    // hound writes the whole header up front, well within this
+    let mut header = Vec::new();
+    (&file).take(4096).read_to_end(&mut header).map_err(|e| e.to_string())?;
+    if header.len() < 12 || &header[..4] != b"RIFF" || &header[8..12] != b"WAVE" {
+        return Err("not a WAV file".to_string());
+    }
+    let u32_at = |offset: usize| header.get(offset..offset + 4).map(|b| u32::from_le_bytes(b.try_into().unwrap()));
+
+    // Walk the chunks up to the data chunk, picking up the frame size from the format chunk
+    let mut offset = 12;
+    let mut format = None;
+    let data_size_offset = loop {
+        let (Some(id), Some(size)) = (header.get(offset..offset + 4), u32_at(offset + 4)) else {
+            return Err("no data chunk".to_string());
+        };
+        if id == b"data" {
+            break offset + 4;
+        }
+        if id == b"fmt " {
+            let block_align = header.get(offset + 20..offset + 22).map(|b| u16::from_le_bytes([b[0], b[1]]));
+            format = u32_at(offset + 16).zip(block_align);
+        }
+        offset += 8 + size as usize + (size as usize & 1);
+    };
+    let Some((byte_rate, block_align)) = format.filter(|&(_, align)| align > 0) else {
+        return Err("no format chunk before the data".to_string());
+    };
+
+    let data_start = data_size_offset as u64 + 4;
+    // Audio running past the end of the data chunk is what a crash leaves. Whole chunks after
+    // it, like a LIST chunk of tags another program added, belong to a finished file.
+    let declared = u32_at(data_size_offset).unwrap_or(0) as u64;
+    let data_end = data_start + declared + (declared & 1);
+    if declared > 0 && data_end < file_len && whole_chunks(&mut file, data_end, file_len) {
+        return Ok(None);
+    }
+    let available = file_len.saturating_sub(data_start);
+    let data_len = available - available % block_align as u64;
+    let data_len = u32::try_from(data_len).map_err(|_| "too long for a WAV file".to_string())?;
+    let fix = HeaderFix { data_size_offset: data_size_offset as u64, data_len, byte_rate };
+    let riff_len = data_start + data_len as u64 - 8;
+    let up_to_date = u32_at(4) == Some(riff_len as u32)
+        && u32_at(data_size_offset) == Some(data_len)
+        && file_len == data_start + data_len as u64;
+    Ok((!up_to_date).then_some(fix))
+}
+
+/// Whether `file` from `offset` to its end is nothing but whole RIFF chunks.
+fn whole_chunks(file: &mut File, mut offset: u64, file_len: u64) -> bool {
+    let mut chunk = [0; 8];
+    while offset < file_len {
+        if file.seek(SeekFrom::Start(offset)).is_err() || file.read_exact(&mut chunk).is_err() {
+            return false;
+        }
+        // Chunk IDs are printable ASCII, audio rarely is
+        if !chunk[..4].iter().all(|&b| (b' '..=b'~').contains(&b)) {
+            return false;
+        }
+        let size = u32::from_le_bytes(chunk[4..].try_into().unwrap()) as u64;
+        offset += 8 + size;
+        // The pad byte after an odd-sized chunk is sometimes left off at the end of the file
+        if offset < file_len {
+            offset += size & 1;
+        }
+    }
+    offset == file_len
+}
+
+/// What can be told about a recovered clip from the audio that reached the disk.
+struct Audio {
+    sample_rate: u32,
+    channels: u16,
+    samples: u64,
+    peak: f32,
+    sum_squares: f64,
+}
+
+impl Audio {
+    /// Decode the whole clip. A cut-off FLAC file decodes up to its last complete frame, which
+    /// is less than STREAMINFO says, and an Ogg/Opus one up to its last complete page.
+    fn read(path: &Path, format: ClipFormat) -> Option<Self> {
+        let mut audio = match format {
+            ClipFormat::Wav => {
+                let mut reader = hound::WavReader::open(path).ok()?;
+                let spec = reader.spec();
+                let mut audio = Self::new(spec.sample_rate, spec.channels);
+                match spec.sample_format {
+                    hound::SampleFormat::Float => reader.samples::<f32>().map_while(|s| s.ok()).for_each(|s| audio.push(s)),
+                    hound::SampleFormat::Int => {
+                        let scale = (1i64 << (spec.bits_per_sample - 1)) as f32;
+                        reader.samples::<i32>().map_while(|s| s.ok()).for_each(|s| audio.push(s as f32 / scale));
+                    }
+                }
+                audio
+            }
+            ClipFormat::Flac => {
+                let mut reader = claxon::FlacReader::open(path).ok()?;
+                let info = reader.streaminfo();
+                let mut audio = Self::new(info.sample_rate, info.channels as u16);
+                let scale = (1i64 << (info.bits_per_sample - 1)) as f32;
+                reader.samples().map_while(|s| s.ok()).for_each(|s| audio.push(s as f32 / scale));
+                audio
+            }
+            ClipFormat::Opus => {
+                let decoded = ogg_opus::read(path).ok()?;
+                let mut audio = Self::new(decoded.sample_rate, decoded.channels);
+                decoded.samples.into_iter().for_each(|s| audio.push(s));
+                audio
+            }
+        };
+        // Only whole frames count
+        audio.samples -= audio.samples % audio.channels.max(1) as u64;
+        Some(audio)
+    }
+
+    fn new(sample_rate: u32, channels: u16) -> Self {
+        Self { sample_rate, channels, samples: 0, peak: 0.0, sum_squares: 0.0 }
+    }
+
+    fn push(&mut self, sample: f32) {
+        self.samples += 1;
+        self.peak = self.peak.max(sample.abs());
+        self.sum_squares += (sample * sample) as f64;
+    }
+
+    fn secs(&self) -> f64 {
+        self.samples as f64 / self.channels.max(1) as f64 / self.sample_rate.max(1) as f64
+    }
+
+    /// A sidecar with what's known, marked as recovered.
+    fn metadata(&self, name: &ClipName, start: DateTime<FixedOffset>) -> ClipMetadata {
+        let end = start + chrono::Duration::microseconds((self.secs() * 1_000_000.0) as i64);
+        ClipMetadata {
+            session: String::new(),
+            start,
+            end,
+            episode_start: name.start,
+            part: name.part,
+            short: name.short,
+            source: String::new(),
+            device: String::new(),
+            sample_rate: self.sample_rate,
+            channels: self.channels,
+            detector: String::new(),
+            threshold: 0.0,
+            sustain_threshold: 0.0,
+            peak: self.peak,
+            rms: if self.samples > 0 { (self.sum_squares / self.samples as f64).sqrt() as f32 } else { 0.0 },
+            trigger_resets: 0,
+            filters: None,
+            classification: None,
+            non_dog: false,
+            recovered: true,
+            keep: false,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{Local, TimeZone};
+    use ranger_recorder_rs::naming::partial_path;
+    use std::time::{Duration, SystemTime};
+
+    const RATE: u32 = 1000;
+
+    /// A finished 16-bit stereo WAV of `frames` frames of a ramp.
+    fn write_wav(path: &Path, frames: usize) {
+        let spec = hound::WavSpec { channels: 2, sample_rate: RATE, bits_per_sample: 16, sample_format: hound::SampleFormat::Int };
+        let mut writer = hound::WavWriter::create(path, spec).unwrap();
+        for i in 0..frames * 2 {
+            writer.write_sample((i % 20_000) as i16).unwrap();
+        }
+        writer.finalize().unwrap();
+    }
+
+    /// What a crash leaves: sizes in the header never written, and a frame cut off partway.
+    fn cut_off(path: &Path) {
+        let len = fs::metadata(path).unwrap().len();
+        let mut file = OpenOptions::new().write(true).open(path).unwrap();
+        file.set_len(len - 3).unwrap();
+        file.seek(SeekFrom::Start(4)).unwrap();
+        file.write_all(&0u32.to_le_bytes()).unwrap();
+        file.seek(SeekFrom::Start(40)).unwrap();
+        file.write_all(&0u32.to_le_bytes()).unwrap();
+    }
+
+    fn age(path: &Path) {
+        let file = OpenOptions::new().write(true).open(path).unwrap();
+        file.set_modified(SystemTime::now() - IN_USE - Duration::from_secs(10)).unwrap();
+    }
+
+    fn name(part: u32) -> ClipName {
+        ClipName { part, ..ClipName::new(Local.with_ymd_and_hms(2024, 5, 1, 18, 30, 0).unwrap()) }
+    }
+
+    #[test]
+    fn finished_wav_needs_no_fix() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("clip.wav");
+        write_wav(&path, 1000);
+        assert!(check(&path).unwrap().is_none());
+    }
+
+    #[test]
+    fn cut_off_wav_is_fixed_to_its_whole_frames() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("clip.wav");
+        write_wav(&path, 1000);
+        cut_off(&path);
+
+        let fix = check(&path).unwrap().expect("header needs fixing");
+        // The last frame lost 3 of its 4 bytes
+        assert_eq!(fix.data_len, 999 * 4);
+        assert!((fix.secs() - 0.999).abs() < 1e-9);
+        fix.apply(&path).unwrap();
+        assert!(check(&path).unwrap().is_none());
+
+        let mut reader = hound::WavReader::open(&path).unwrap();
+        assert_eq!(reader.duration(), 999);
+        let samples: Vec<i16> = reader.samples::<i16>().map(|s| s.unwrap()).collect();
+        assert!(samples.iter().enumerate().all(|(i, &s)| s == i as i16));
+    }
+
+    #[test]
+    fn trailing_chunks_after_the_audio_are_left_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("clip.wav");
+        write_wav(&path, 1000);
+        // A tagger's LIST chunk after the data, with the RIFF size grown to match
+        let list = b"LIST\x0d\0\0\0INFOISFT\x01\0\0\0x";
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(list).unwrap();
+        file.write_all(b"\0").unwrap();
+        let file_len = fs::metadata(&path).unwrap().len();
+        let mut file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.seek(SeekFrom::Start(4)).unwrap();
+        file.write_all(&((file_len - 8) as u32).to_le_bytes()).unwrap();
+        drop(file);
+
+        assert!(check(&path).unwrap().is_none());
+        age(&path);
+        assert_eq!(repair_dir(dir.path(), false, true), 0);
+        assert_eq!(fs::metadata(&path).unwrap().len(), file_len);
+
+        // Audio appended past the data chunk isn't a chunk, so that does get fixed
+        let garbage_path = dir.path().join("overrun.wav");
+        write_wav(&garbage_path, 1000);
+        OpenOptions::new().append(true).open(&garbage_path).unwrap().write_all(&[0x01, 0x02, 0x03, 0x04]).unwrap();
+        let fix = check(&garbage_path).unwrap().expect("audio past the data chunk is recovered");
+        assert_eq!(fix.data_len, 1001 * 4);
+    }
+
+    #[test]
+    fn check_rejects_what_it_cant_fix() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("clip.wav");
+        fs::write(&path, b"not audio at all").unwrap();
+        assert!(check(&path).is_err());
+        // Cut off inside the header, before the data chunk
+        write_wav(&path, 10);
+        OpenOptions::new().write(true).open(&path).unwrap().set_len(30).unwrap();
+        assert!(check(&path).is_err());
+    }
+
+    #[test]
+    fn partials_are_recovered_with_a_sidecar() {
+        let dir = tempfile::tempdir().unwrap();
+        let first = dir.path().join(name(1).file_name());
+        write_wav(&first, 2000);
+        let before = Audio::read(&first, ClipFormat::Wav).unwrap().metadata(&name(1), name(1).start);
+        before.write(&first).unwrap();
+
+        let second = dir.path().join(name(2).file_name());
+        write_wav(&partial_path(&second), 1000);
+        cut_off(&partial_path(&second));
+        age(&partial_path(&second));
+
+        assert_eq!(repair_dir(dir.path(), false, true), 1);
+        assert!(!partial_path(&second).exists());
+        let metadata = ClipMetadata::read(&second).expect("recovered clip has a sidecar");
+        assert!(metadata.recovered);
+        // Carries on from where the first part ended
+        assert_eq!(metadata.start, before.end);
+        assert_eq!(metadata.end - metadata.start, chrono::Duration::milliseconds(999));
+        assert_eq!(metadata.episode_start, name(1).start);
+        assert_eq!((metadata.part, metadata.sample_rate, metadata.channels), (2, RATE, 2));
+        // The ramp up to the last whole frame
+        assert!((metadata.peak - 1997.0 / 32768.0).abs() < 1e-6);
+        assert!(metadata.rms > 0.0 && metadata.rms < metadata.peak);
+    }
+
+    #[test]
+    fn empty_partials_are_removed_and_fresh_ones_skipped() {
+        let dir = tempfile::tempdir().unwrap();
+        let empty = partial_path(&dir.path().join(name(1).file_name()));
+        fs::write(&empty, b"").unwrap();
+        age(&empty);
+        let fresh = partial_path(&dir.path().join(name(2).file_name()));
+        write_wav(&fresh, 100);
+        cut_off(&fresh);
+
+        assert_eq!(repair_dir(dir.path(), false, true), 1);
+        assert!(!empty.exists());
+        assert!(fresh.exists());
+        assert!(!dir.path().join(name(2).file_name()).exists());
+    }
+
+    #[test]
+    fn startup_recovers_partials_written_just_now() {
+        let dir = tempfile::tempdir().unwrap();
+        let fresh = partial_path(&dir.path().join(name(1).file_name()));
+        write_wav(&fresh, 100);
+        cut_off(&fresh);
+
+        on_startup(dir.path());
+        assert!(!fresh.exists());
+        let clip = dir.path().join(name(1).file_name());
+        assert_eq!(hound::WavReader::open(&clip).unwrap().duration(), 99);
+    }
+}
@@ -0,0 +1,370 @@
+use chrono::{DateTime, FixedOffset, Local};
+use clap::Args;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime};
+use walkdir::WalkDir;
+
+use ranger_recorder_rs::levels;
+use ranger_recorder_rs::naming::ClipName;
+use ranger_recorder_rs::sidecar::ClipMetadata;
+
+use crate::config::Config;
+
+/// How often the recorder applies the retention policy while it runs.
+pub const SWEEP_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// Files modified more recently than this may still be open for writing, so they're left alone.
+pub const IN_USE: Duration = Duration::from_secs(60);
+
+/// Apply the retention policy to the output directory once.
+#[derive(Args, Debug)]
+pub struct PruneArgs {
+    /// Print what would be removed without touching anything
+    #[arg(long)]
+    pub dry_run: bool,
+}
+
+pub fn run(config: &Config, args: &PruneArgs) -> Result<(), String> {
+    if config.retention_max_age_days == 0.0 && config.retention_max_mb == 0 {
+        println!("No retention limits set, nothing to prune");
+        return Ok(());
+    }
+    sweep(config, args.dry_run);
+    Ok(())
+}
+
+/// A clip on disk, with its sidecar if it has one.
+struct StoredClip {
+    path: PathBuf,
+    start: DateTime<FixedOffset>,
+    /// Clip and sidecar together
+    bytes: u64,
+    keep: bool,
+    in_use: bool,
+}
+
+/// Runs the recorder's sweeps on a thread of their own, so a big output directory or a slow
+/// archive disk doesn't hold up input supervision.
+pub struct Sweeper {
+    config: Config,
+    running: Option<JoinHandle<()>>,
+}
+
+impl Sweeper {
+    pub fn new(config: &Config) -> Self {
+        Self { config: config.clone(), running: None }
+    }
+
+    /// Start a sweep, unless the last one is still going.
+    pub fn start(&mut self) {
+        if self.running.as_ref().is_some_and(|sweep| !sweep.is_finished()) {
+            return;
+        }
+        self.finish();
+        let config = self.config.clone();
+        match thread::Builder::new().name("retention".to_string()).spawn(move || sweep(&config, false)) {
+            Ok(handle) => self.running = Some(handle),
+            Err(e) => eprintln!("Failed to start retention sweep: {}", e),
+        }
+    }
+
+    /// Wait for a sweep in progress, so a clip isn't left half moved to the archive.
+    pub fn finish(&mut self) {
+        if let Some(sweep) = self.running.take()
+            && sweep.join().is_err()
+        {
+            eprintln!("Retention sweep panicked");
+        }
+    }
+}
+
+/// Remove clips, oldest first, until none is older than `retention_max_age_days` and they take
+/// no more than `retention_max_mb` in total, then remove level logs past the age limit. Errors
+/// are logged and the file skipped, so a sweep never stops the recorder.
+pub fn sweep(config: &Config, dry_run: bool) {
+    let max_age = (config.retention_max_age_days > 0.0)
+        .then(|| chrono::Duration::seconds((config.retention_max_age_days * 86400.0) as i64));
+    let max_bytes = (config.retention_max_mb > 0).then_some(config.retention_max_mb * 1024 * 1024);
+    if max_age.is_none() && max_bytes.is_none() {
+        return;
+    }
+    let cutoff = max_age.map(|age| Local::now().fixed_offset() - age);
+
+    let mut clips = Vec::new();
+    let mut level_logs = Vec::new();
+    for entry in WalkDir::new(&config.output_dir).into_iter().filter_map(|e| e.ok()) {
+        let Some(file_name) = entry.file_name().to_str() else {
+            continue;
+        };
+        if let Some(name) = ClipName::parse(file_name) {
+            let json = ClipMetadata::path_for(entry.path());
+            let bytes = file_size(entry.path()) + file_size(&json);
+            clips.push(StoredClip {
+                start: name.start,
+                bytes,
+                keep: ClipMetadata::read(entry.path()).is_some_and(|m| m.keep),
+                in_use: modified_within(entry.path(), IN_USE),
+                path: entry.into_path(),
+            });
+        } else if let Some(date) = levels::log_date(file_name) {
+            level_logs.push((entry.into_path(), date));
+        }
+    }
+    clips.sort_by_key(|clip| clip.start);
+
+    let mut total: u64 = clips.iter().map(|clip| clip.bytes).sum();
+    let mut removed = 0;
+    let mut freed = 0;
+    for clip in &clips {
+        let too_old = cutoff.is_some_and(|cutoff| clip.start < cutoff);
+        let over_quota = max_bytes.is_some_and(|max| total > max);
+        if !(too_old || over_quota) || clip.in_use || (clip.keep && config.retention_keep_flagged) {
+            continue;
+        }
+        let reason = if too_old { "older than retention_max_age_days" } else { "over retention_max_mb" };
+        let json = ClipMetadata::path_for(&clip.path);
+        if !remove(config, &clip.path, reason, dry_run) {
+            continue;
+        }
+        if json.exists() {
+            remove(config, &json, reason, dry_run);
+        }
+        total -= clip.bytes;
+        removed += 1;
+        freed += clip.bytes;
+    }
+    if let Some(max) = max_bytes
+        && total > max
+    {
+        eprintln!(
+            "Clips still take {} MB, over retention_max_mb = {}: the rest are kept or in use",
+            total / (1024 * 1024),
+            config.retention_max_mb,
+        );
+    }
+
+    // A day's level log goes once the whole day is past the age limit
+    if let Some(cutoff) = cutoff {
+        for (path, date) in level_logs {
+            if date < cutoff.date_naive() && !modified_within(&path, IN_USE) {
+                remove(config, &path, "older than retention_max_age_days", dry_run);
+            }
+        }
+    }
+
+    if removed > 0 {
+        let action = match (&config.retention_archive_dir, dry_run) {
+            (_, true) => "Would remove",
+            (Some(_), false) => "Archived",
+            (None, false) => "Deleted",
+        };
+        println!("{} {} clips, {:.1} MB", action, removed, freed as f64 / (1024.0 * 1024.0));
+    }
+}
+
+/// Delete `path`, or move it into the archive directory under the same relative path. Returns
+/// whether it's gone.
+fn remove(config: &Config, path: &Path, reason: &str, dry_run: bool) -> bool {
+    let Some(archive) = &config.retention_archive_dir else {
+        if dry_run {
+            println!("Would delete {} ({})", path.display(), reason);
+            return true;
+        }
+        return match fs::remove_file(path) {
+            Ok(()) => {
+                println!("Deleted {} ({})", path.display(), reason);
+                true
+            }
+            Err(e) => {
+                eprintln!("Failed to delete {}: {}", path.display(), e);
+                false
+            }
+        };
+    };
+
+    let relative = path.strip_prefix(&config.output_dir).unwrap_or(path);
+    let target = archive.join(relative);
+    if dry_run {
+        println!("Would archive {} -> {} ({})", path.display(), target.display(), reason);
+        return true;
+    }
+    let moved = target
+        .parent()
+        .map_or(Ok(()), fs::create_dir_all)
+        .and_then(|()| move_file(path, &target, |from, to| fs::rename(from, to)));
+    match moved {
+        Ok(()) => {
+            println!("Archived {} -> {} ({})", path.display(), target.display(), reason);
+            true
+        }
+        Err(e) => {
+            eprintln!("Failed to archive {} to {}: {}", path.display(), target.display(), e);
+            false
+        }
+    }
+}
+
+/// Move `from` to `to` with `rename`, or by copying if that fails: the archive is often on
+/// another disk, where renaming doesn't work.
+fn move_file(from: &Path, to: &Path, rename: impl Fn(&Path, &Path) -> io::Result<()>) -> io::Result<()> {
+    rename(from, to).or_else(|_| {
+        fs::copy(from, to)?;
+        fs::remove_file(from)
+    })
+}
+
+fn file_size(path: &Path) -> u64 {
+    fs::metadata(path).map_or(0, |m| m.len())
+}
+
+pub fn modified_within(path: &Path, age: Duration) -> bool {
+    fs::metadata(path)
+        .and_then(|m| m.modified())
+        .is_ok_and(|modified| SystemTime::now().duration_since(modified).map_or(true, |elapsed| elapsed < age))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+
+    const KB: usize = 1024;
+
+    fn config(dir: &Path) -> Config {
+        Config { output_dir: dir.join("barks"), ..Config::default() }
+    }
+
+    /// A clip of `bytes` that started `age` ago, last written long enough ago not to be in use,
+    /// with a sidecar if `keep` is given.
+    fn clip(config: &Config, age: chrono::Duration, bytes: usize, keep: Option<bool>) -> PathBuf {
+        fs::create_dir_all(&config.output_dir).unwrap();
+        let path = config.output_dir.join(ClipName::new(Local::now() - age).file_name());
+        fs::write(&path, vec![0; bytes]).unwrap();
+        if let Some(keep) = keep {
+            ClipMetadata { keep, ..ClipMetadata::default() }.write(&path).unwrap();
+        }
+        age_file(&path);
+        path
+    }
+
+    fn age_file(path: &Path) {
+        let file = File::options().write(true).open(path).unwrap();
+        file.set_modified(SystemTime::now() - 2 * IN_USE).unwrap();
+    }
+
+    fn days(days: i64) -> chrono::Duration {
+        chrono::Duration::days(days)
+    }
+
+    #[test]
+    fn clips_past_the_age_limit_are_deleted_with_their_sidecars() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config { retention_max_age_days: 2.0, ..config(dir.path()) };
+        let old = clip(&config, days(3), KB, Some(false));
+        let recent = clip(&config, days(1), KB, Some(false));
+        let old_levels = config.output_dir.join(format!("levels_{}.csv", (Local::now() - days(3)).format("%Y-%m-%d")));
+        let todays_levels = config.output_dir.join(format!("levels_{}.csv", Local::now().format("%Y-%m-%d")));
+        for levels in [&old_levels, &todays_levels] {
+            fs::write(levels, "").unwrap();
+            age_file(levels);
+        }
+
+        sweep(&config, false);
+        assert!(!old.exists() && !ClipMetadata::path_for(&old).exists());
+        assert!(recent.exists() && ClipMetadata::path_for(&recent).exists());
+        assert!(!old_levels.exists() && todays_levels.exists());
+    }
+
+    #[test]
+    fn the_oldest_clips_go_first_to_get_under_the_quota() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config { retention_max_mb: 1, ..config(dir.path()) };
+        let clips: Vec<_> = [4, 1, 3, 2].map(|age| clip(&config, days(age), 400 * KB, None)).into();
+
+        sweep(&config, false);
+        // 1600 KB down to 800 KB, removing the 4 and 3 day old clips
+        let kept: Vec<_> = clips.iter().map(|path| path.exists()).collect();
+        assert_eq!(kept, [false, true, false, true]);
+    }
+
+    #[test]
+    fn clips_marked_to_keep_survive() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config { retention_max_age_days: 1.0, ..config(dir.path()) };
+        let kept = clip(&config, days(3), KB, Some(true));
+        let unmarked = clip(&config, days(4), KB, Some(false));
+        sweep(&config, false);
+        assert!(kept.exists() && !unmarked.exists());
+
+        sweep(&Config { retention_keep_flagged: false, ..config }, false);
+        assert!(!kept.exists());
+    }
+
+    #[test]
+    fn files_still_being_written_are_skipped() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config { retention_max_age_days: 1.0, ..config(dir.path()) };
+        let clip = clip(&config, days(3), KB, None);
+        fs::write(&clip, vec![0; 2 * KB]).unwrap();
+        sweep(&config, false);
+        assert!(clip.exists());
+    }
+
+    #[test]
+    fn dry_run_removes_nothing() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config { retention_max_age_days: 1.0, ..config(dir.path()) };
+        let clip = clip(&config, days(3), KB, Some(false));
+        sweep(&config, true);
+        assert!(clip.exists() && ClipMetadata::path_for(&clip).exists());
+    }
+
+    #[test]
+    fn archiving_keeps_the_layout_under_the_output_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = dir.path().join("archive");
+        let config = Config {
+            retention_max_age_days: 1.0,
+            retention_archive_dir: Some(archive.clone()),
+            ..config(dir.path())
+        };
+        let source = Config { output_dir: config.output_dir.join("yard"), ..config.clone() };
+        let clip = clip(&source, days(3), KB, Some(false));
+        let file_name = clip.file_name().unwrap();
+
+        sweep(&config, false);
+        assert!(!clip.exists() && !ClipMetadata::path_for(&clip).exists());
+        let archived = archive.join("yard").join(file_name);
+        assert_eq!(fs::read(&archived).unwrap().len(), KB);
+        assert!(ClipMetadata::path_for(&archived).exists());
+    }
+
+    #[test]
+    fn the_sweeper_sweeps_in_the_background() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config { retention_max_age_days: 1.0, ..config(dir.path()) };
+        let mut sweeper = Sweeper::new(&config);
+        let first = clip(&config, days(3), KB, None);
+        sweeper.start();
+        sweeper.finish();
+        assert!(!first.exists());
+        // The finished sweep doesn't stop the next one from starting
+        let second = clip(&config, days(4), KB, None);
+        sweeper.start();
+        sweeper.finish();
+        assert!(!second.exists());
+    }
+
+    #[test]
+    fn moving_falls_back_to_copying_when_renaming_fails() {
+        let dir = tempfile::tempdir().unwrap();
+        let (from, to) = (dir.path().join("a.wav"), dir.path().join("b.wav"));
+        fs::write(&from, b"clip").unwrap();
+        move_file(&from, &to, |_, _| Err(io::Error::from(io::ErrorKind::CrossesDevices))).unwrap();
+        assert!(!from.exists());
+        assert_eq!(fs::read(&to).unwrap(), b"clip");
+    }
+}
@@ -0,0 +1,190 @@
+use chrono::{Datelike, NaiveDateTime, NaiveTime, Weekday};
+use serde::{Deserialize, Serialize};
+
+use crate::config::Config;
+
+/// Whether the recorder should be listening, and with which settings.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Arming {
+    Disarmed,
+    /// There's no schedule, record all the time
+    Always,
+    /// Armed by this window of the schedule
+    Window(usize),
+}
+
+/// A weekly time range during which the recorder is armed.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Window {
+    /// Days the window starts on, e.g. `["mon", "tue"]`. Empty means every day.
+    #[serde(default)]
+    pub days: Vec<Weekday>,
+    /// Local time of day, `"HH:MM"`. An `end` at or before `start` runs past midnight.
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+    /// Detection profile to record with, from `[profiles]`. None keeps the top-level settings.
+    pub profile: Option<String>,
+}
+
+impl Window {
+    fn starts_on(&self, day: Weekday) -> bool {
+        self.days.is_empty() || self.days.contains(&day)
+    }
+
+    /// Whether the window is open at local time `now`.
+    pub fn contains(&self, now: NaiveDateTime) -> bool {
+        let (day, time) = (now.weekday(), now.time());
+        if self.start < self.end {
+            self.starts_on(day) && self.start <= time && time < self.end
+        } else {
+            // Runs past midnight: either today's evening or the tail of yesterday's window
+            (self.starts_on(day) && time >= self.start) || (self.starts_on(day.pred()) && time < self.end)
+        }
+    }
+
+    /// e.g. `mon,tue 22:00-06:00 (night)`, for logs.
+    pub fn describe(&self) -> String {
+        let days = if self.days.is_empty() {
+            "daily".to_string()
+        } else {
+            self.days.iter().map(|d| d.to_string().to_lowercase()).collect::<Vec<_>>().join(",")
+        };
+        let mut description = format!("{} {}-{}", days, self.start.format("%H:%M"), self.end.format("%H:%M"));
+        if let Some(profile) = &self.profile {
+            description.push_str(&format!(" ({})", profile));
+        }
+        description
+    }
+}
+
+/// Detection settings that replace the top-level ones while a window using the profile is open.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    pub threshold: Option<f64>,
+    pub sustain_threshold: Option<f64>,
+    pub auto_threshold: Option<bool>,
+    pub auto_margin_db: Option<f64>,
+    pub min_attack_ms: Option<f64>,
+    pub silence_timeout_secs: Option<f64>,
+    pub min_event_secs: Option<f64>,
+}
+
+impl Profile {
+    /// Every setting a profile can change, as `config` has them.
+    pub fn of(config: &Config) -> Self {
+        Self {
+            threshold: Some(config.threshold),
+            sustain_threshold: config.sustain_threshold,
+            auto_threshold: Some(config.auto_threshold),
+            auto_margin_db: Some(config.auto_margin_db),
+            min_attack_ms: Some(config.min_attack_ms),
+            silence_timeout_secs: Some(config.silence_timeout_secs),
+            min_event_secs: Some(config.min_event_secs),
+        }
+    }
+
+    /// `config` with this profile's settings in place.
+    pub fn apply(&self, config: &Config) -> Config {
+        let mut config = config.clone();
+        if let Some(threshold) = self.threshold {
+            config.threshold = threshold;
+            config.auto_threshold = false;
+        }
+        if let Some(sustain) = self.sustain_threshold {
+            config.sustain_threshold = Some(sustain);
+        }
+        if let Some(auto) = self.auto_threshold {
+            config.auto_threshold = auto;
+        }
+        if let Some(db) = self.auto_margin_db {
+            config.auto_margin_db = db;
+        }
+        if let Some(ms) = self.min_attack_ms {
+            config.min_attack_ms = ms;
+        }
+        if let Some(secs) = self.silence_timeout_secs {
+            config.silence_timeout_secs = secs;
+        }
+        if let Some(secs) = self.min_event_secs {
+            config.min_event_secs = secs;
+        }
+        config
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+
+    fn window(days: &[Weekday], start: &str, end: &str) -> Window {
+        Window {
+            days: days.to_vec(),
+            start: NaiveTime::parse_from_str(start, "%H:%M").unwrap(),
+            end: NaiveTime::parse_from_str(end, "%H:%M").unwrap(),
+            profile: None,
+        }
+    }
+
+    /// `time` on the day of the week, in the week starting Monday 6 May 2024.
+    fn at(day: Weekday, time: &str) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(2024, 5, 6 + day.num_days_from_monday())
+            .unwrap()
+            .and_time(NaiveTime::parse_from_str(time, "%H:%M:%S").unwrap())
+    }
+
+    #[test]
+    fn daytime_window() {
+        let window = window(&[], "09:00", "17:00");
+        assert!(!window.contains(at(Weekday::Wed, "08:59:59")));
+        assert!(window.contains(at(Weekday::Wed, "09:00:00")));
+        assert!(window.contains(at(Weekday::Wed, "16:59:59")));
+        assert!(!window.contains(at(Weekday::Wed, "17:00:00")));
+    }
+
+    #[test]
+    fn window_across_midnight() {
+        let window = window(&[], "22:00", "06:00");
+        assert!(!window.contains(at(Weekday::Wed, "21:59:59")));
+        assert!(window.contains(at(Weekday::Wed, "22:00:00")));
+        assert!(window.contains(at(Weekday::Wed, "23:59:59")));
+        assert!(window.contains(at(Weekday::Thu, "00:00:00")));
+        assert!(window.contains(at(Weekday::Thu, "05:59:59")));
+        assert!(!window.contains(at(Weekday::Thu, "06:00:00")));
+        assert!(!window.contains(at(Weekday::Thu, "12:00:00")));
+    }
+
+    #[test]
+    fn days_are_the_ones_a_window_starts_on() {
+        let window = window(&[Weekday::Fri, Weekday::Sat], "22:00", "06:00");
+        // Friday night runs into Saturday morning, Thursday night isn't armed at all
+        assert!(!window.contains(at(Weekday::Thu, "23:00:00")));
+        assert!(!window.contains(at(Weekday::Fri, "05:00:00")));
+        assert!(window.contains(at(Weekday::Fri, "23:00:00")));
+        assert!(window.contains(at(Weekday::Sat, "05:00:00")));
+        assert!(window.contains(at(Weekday::Sat, "23:00:00")));
+        assert!(window.contains(at(Weekday::Sun, "05:00:00")));
+        assert!(!window.contains(at(Weekday::Sun, "23:00:00")));
+        assert!(!window.contains(at(Weekday::Mon, "05:00:00")));
+    }
+
+    #[test]
+    fn sunday_night_runs_into_monday() {
+        let window = window(&[Weekday::Sun], "23:30", "00:30");
+        assert!(window.contains(at(Weekday::Sun, "23:45:00")));
+        assert!(window.contains(at(Weekday::Mon, "00:15:00")));
+        assert!(!window.contains(at(Weekday::Mon, "23:45:00")));
+        assert!(!window.contains(at(Weekday::Sun, "00:15:00")));
+    }
+
+    #[test]
+    fn same_start_and_end_is_a_whole_day() {
+        let window = window(&[Weekday::Tue], "12:00", "12:00");
+        assert!(!window.contains(at(Weekday::Tue, "11:59:59")));
+        assert!(window.contains(at(Weekday::Tue, "12:00:00")));
+        assert!(window.contains(at(Weekday::Wed, "11:59:59")));
+        assert!(!window.contains(at(Weekday::Wed, "12:00:00")));
+    }
+}
@@ -0,0 +1,83 @@
+use chrono::{DateTime, Local};
+use serde::Serialize;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use ranger_recorder_rs::coverage::SESSION_LOG_FILE;
+
+use crate::config::Config;
+
+/// How often a running session says it's still alive. If the recorder dies without writing an
+/// end record, its coverage is known to within this much.
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Something that happened to a recording session.
+#[derive(Serialize, Debug)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum SessionEvent {
+    /// The input stream is running, with the effective configuration
+    Start {
+        /// Label of the source, empty when only one is recorded
+        source: String,
+        host: String,
+        device: String,
+        sample_rate: u32,
+        channels: u16,
+        sample_format: String,
+        config: Box<Config>,
+    },
+    Heartbeat,
+    /// A schedule window opened, the inputs are recording
+    Armed { window: String },
+    /// Outside the schedule, nothing is recording
+    Disarmed,
+    /// Reported by the audio backend, recording carries on if it can
+    StreamError { source: String, message: String },
+    /// An input was lost, its open clip was closed and it's being reopened
+    Outage { source: String, reason: String },
+    /// A lost input is recording again
+    Restored { source: String, device: String, outage_secs: f64 },
+    End { reason: String },
+}
+
+#[derive(Serialize)]
+struct Record<'a> {
+    session: &'a str,
+    time: DateTime<Local>,
+    #[serde(flatten)]
+    event: &'a SessionEvent,
+}
+
+/// Append-only log of when the recorder was running, kept next to the clips.
+pub struct SessionLog {
+    path: PathBuf,
+    file: File,
+    id: String,
+}
+
+impl SessionLog {
+    /// Open the log in `output_dir` for a new session, named after its start time.
+    pub fn open(output_dir: &Path) -> io::Result<Self> {
+        fs::create_dir_all(output_dir)?;
+        let path = output_dir.join(SESSION_LOG_FILE);
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let id = Local::now().format("%Y%m%dT%H%M%S%.3f").to_string();
+        Ok(Self { path, file, id })
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Append `event`, stamped with the current time. Failures are logged rather than returned,
+    /// a missing session record shouldn't stop the recording.
+    pub fn record(&mut self, event: SessionEvent) {
+        let record = Record { session: &self.id, time: Local::now(), event: &event };
+        let line = serde_json::to_string(&record).expect("session records are always serializable");
+        if let Err(e) = writeln!(self.file, "{}", line).and_then(|_| self.file.sync_data()) {
+            eprintln!("Failed to write session record to {}: {}", self.path.display(), e);
+        }
+    }
+}
@@ -0,0 +1,119 @@
+use chrono::{DateTime, FixedOffset};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::spectral::SpectralFeatures;
+
+/// Metadata the recorder writes next to each clip, as `<clip name>.json`.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ClipMetadata {
+    /// Session the clip was recorded in, see the session log
+    pub session: String,
+    /// When the first and last frame of the clip were heard
+    pub start: DateTime<FixedOffset>,
+    pub end: DateTime<FixedOffset>,
+    /// Start of the episode this clip belongs to, and its part number within it
+    pub episode_start: DateTime<FixedOffset>,
+    pub part: u32,
+    /// Event was shorter than the recorder's `min_event_secs`
+    pub short: bool,
+    /// Label of the microphone when several are recorded at once, empty otherwise
+    #[serde(default)]
+    pub source: String,
+    /// Input device, or the source file for offline detection
+    pub device: String,
+    pub sample_rate: u32,
+    pub channels: u16,
+    pub detector: String,
+    /// Start and sustain thresholds in effect when the clip was opened
+    pub threshold: f32,
+    pub sustain_threshold: f32,
+    /// Levels over the whole clip, in linear amplitude
+    pub peak: f32,
+    pub rms: f32,
+    /// How many times a bark extended the clip after it started
+    pub trigger_resets: u32,
+    /// Filters the detector heard the input through, `None` if it heard it as is
+    #[serde(default)]
+    pub filters: Option<FilterSettings>,
+    /// How much the clip's barking sounded like a dog, when the recorder classifies clips
+    #[serde(default)]
+    pub classification: Option<Classification>,
+    /// Classified as not a dog, and kept with a tag rather than dropped
+    #[serde(default)]
+    pub non_dog: bool,
+    /// Recovered by `repair` from a clip the recorder never finished. Only what the audio itself
+    /// says is known, the session, device and detection settings are left empty.
+    #[serde(default)]
+    pub recovered: bool,
+    /// Marked in the viewer to be exempt from retention
+    #[serde(default)]
+    pub keep: bool,
+}
+
+/// The recorder's filter chain in front of the detector. A frequency of 0 is a stage that's off.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct FilterSettings {
+    pub highpass_hz: f64,
+    /// Mains frequency, notched out along with its harmonics
+    pub notch_hz: f64,
+    /// Notches at the mains frequency and its multiples, this many in all
+    pub notch_harmonics: u32,
+    pub notch_q: f64,
+    pub bandpass_low_hz: f64,
+    pub bandpass_high_hz: f64,
+    /// Whether the clip's audio went through the filters too, not just detection
+    pub recorded: bool,
+}
+
+impl FilterSettings {
+    /// e.g. `high-pass 80 Hz, notch 60 Hz x3, band-pass 400-4000 Hz`
+    pub fn describe(&self) -> String {
+        let mut stages = Vec::new();
+        if self.highpass_hz > 0.0 {
+            stages.push(format!("high-pass {} Hz", self.highpass_hz));
+        }
+        if self.notch_hz > 0.0 {
+            stages.push(format!("notch {} Hz x{}", self.notch_hz, self.notch_harmonics));
+        }
+        if self.bandpass_low_hz > 0.0 {
+            stages.push(format!("band-pass {}-{} Hz", self.bandpass_low_hz, self.bandpass_high_hz));
+        }
+        stages.join(", ")
+    }
+}
+
+/// The classifier's verdict on a clip, and what it was based on.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Classification {
+    /// 0 for certainly not a dog, 1 for certainly one
+    pub dog_score: f32,
+    /// Whether the score reached the recorder's `dog_threshold`
+    pub dog: bool,
+    /// Features of the parts of the clip the detector triggered on
+    pub features: SpectralFeatures,
+}
+
+impl ClipMetadata {
+    /// `bark_x.wav` -> `bark_x.json`
+    pub fn path_for(clip: &Path) -> PathBuf {
+        clip.with_extension("json")
+    }
+
+    pub fn write(&self, clip: &Path) -> io::Result<()> {
+        let json = serde_json::to_string_pretty(self).map_err(io::Error::other)?;
+        fs::write(Self::path_for(clip), json)
+    }
+
+    /// The sidecar for `clip`, if there is a readable one.
+    pub fn read(clip: &Path) -> Option<Self> {
+        let json = fs::read_to_string(Self::path_for(clip)).ok()?;
+        serde_json::from_str(&json).ok()
+    }
+
+    pub fn duration_secs(&self) -> f64 {
+        (self.end - self.start).num_milliseconds() as f64 / 1000.0
+    }
+}
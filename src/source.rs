@@ -0,0 +1,357 @@
+use cpal::traits::{DeviceTrait, StreamTrait};
+use cpal::{FromSample, SampleFormat, SizedSample};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+use crate::config::{Config, ResolvedFormat};
+use crate::devices;
+use crate::preprocess::Preprocessor;
+use crate::recorder::{Input, Recorder};
+use crate::session::{SessionEvent, SessionLog};
+use crate::writer::{self, WriterThread};
+use crate::Wake;
+
+/// First wait before reopening a lost input, doubled after every failed attempt.
+const RETRY_INITIAL: Duration = Duration::from_secs(1);
+const RETRY_MAX: Duration = Duration::from_secs(60);
+
+/// Counts every stream opened, so errors from one that's since been closed or replaced can be
+/// told apart from the current one's.
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// One input device with its own recorder and writer thread.
+pub struct Source {
+    label: String,
+    device: String,
+    stream: cpal::Stream,
+    /// Tags the errors this stream reports
+    generation: u64,
+    writer: WriterThread,
+    /// Audio callbacks so far, and when that count last moved
+    callbacks: Arc<AtomicU64>,
+    seen_callbacks: u64,
+    last_activity: Instant,
+}
+
+impl Source {
+    /// Open the device `config` names and start its writer thread. The stream isn't running
+    /// until `play`. Also returns the session record describing the source.
+    fn open(
+        label: &str,
+        config: &Config,
+        session: &str,
+        wake: &mpsc::Sender<Wake>,
+    ) -> Result<(Self, SessionEvent), String> {
+        let devices::Selection { host, device, stream_config } = devices::select(config)?;
+        let sample_format = stream_config.sample_format();
+        let device_name = device.name().unwrap_or_default();
+        println!(
+            "Using input device: {} on {} ({} Hz, {} channels, {}){}",
+            device_name,
+            host.name(),
+            stream_config.sample_rate().0,
+            stream_config.channels(),
+            sample_format,
+            describe(label),
+        );
+
+        let sample_rate = stream_config.sample_rate().0;
+        let channels = stream_config.channels() as usize;
+        let preprocessor = Preprocessor::new(config, channels, sample_rate)?;
+        if let Some(processing) = preprocessor.describe() {
+            println!("Processing input{}: {}", describe(label), processing);
+        }
+        let recorder = Recorder::new(config, Input {
+            source: label.to_string(),
+            device: device_name.clone(),
+            session: session.to_string(),
+            channels: preprocessor.channels(),
+            sample_rate: preprocessor.sample_rate(),
+            native_format: ResolvedFormat::for_sample_format(sample_format),
+        });
+        let (capture, writer) = writer::spawn(recorder, preprocessor, channels, sample_rate);
+        let callbacks = capture.callbacks();
+
+        let stream_config: cpal::StreamConfig = stream_config.into();
+        let generation = GENERATION.fetch_add(1, Ordering::Relaxed);
+        let errors = StreamErrors { source: label.to_string(), generation, wake: wake.clone() };
+        let stream = match sample_format {
+            SampleFormat::I8 => build_stream::<i8>(&device, &stream_config, capture, errors),
+            SampleFormat::I16 => build_stream::<i16>(&device, &stream_config, capture, errors),
+            SampleFormat::I32 => build_stream::<i32>(&device, &stream_config, capture, errors),
+            SampleFormat::I64 => build_stream::<i64>(&device, &stream_config, capture, errors),
+            SampleFormat::U8 => build_stream::<u8>(&device, &stream_config, capture, errors),
+            SampleFormat::U16 => build_stream::<u16>(&device, &stream_config, capture, errors),
+            SampleFormat::U32 => build_stream::<u32>(&device, &stream_config, capture, errors),
+            SampleFormat::U64 => build_stream::<u64>(&device, &stream_config, capture, errors),
+            SampleFormat::F32 => build_stream::<f32>(&device, &stream_config, capture, errors),
+            SampleFormat::F64 => build_stream::<f64>(&device, &stream_config, capture, errors),
+            other => Err(format!("Unsupported sample format {}", other)),
+        };
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                writer.stop();
+                return Err(e);
+            }
+        };
+
+        let start = SessionEvent::Start {
+            source: label.to_string(),
+            host: host.name().to_string(),
+            device: device_name.clone(),
+            sample_rate,
+            channels: channels as u16,
+            sample_format: sample_format.to_string(),
+            config: Box::new(config.clone()),
+        };
+        let source = Self {
+            label: label.to_string(),
+            device: device_name,
+            stream,
+            generation,
+            writer,
+            callbacks,
+            seen_callbacks: 0,
+            last_activity: Instant::now(),
+        };
+        Ok((source, start))
+    }
+
+    /// `open` the source and start its stream.
+    pub fn start(
+        label: &str,
+        config: &Config,
+        session: &str,
+        wake: &mpsc::Sender<Wake>,
+    ) -> Result<(Self, SessionEvent), String> {
+        let (mut source, start) = Self::open(label, config, session, wake)?;
+        match source.play() {
+            Ok(()) => Ok((source, start)),
+            Err(e) => {
+                source.stop();
+                Err(e)
+            }
+        }
+    }
+
+    fn play(&mut self) -> Result<(), String> {
+        self.last_activity = Instant::now();
+        self.stream.play().map_err(|e| format!("Failed to start stream{}: {}", describe(&self.label), e))
+    }
+
+    /// Whether the stream has gone `timeout` without delivering any audio.
+    fn stalled(&mut self, timeout: Duration) -> bool {
+        let callbacks = self.callbacks.load(Ordering::Relaxed);
+        if callbacks != self.seen_callbacks {
+            self.seen_callbacks = callbacks;
+            self.last_activity = Instant::now();
+        }
+        self.last_activity.elapsed() > timeout
+    }
+
+    fn retune(&self, config: Config) {
+        self.writer.retune(config);
+    }
+
+    /// Stop the callbacks first so the writer sees the end of the audio, then close the clip.
+    pub fn stop(self) {
+        drop(self.stream);
+        self.writer.stop();
+    }
+}
+
+enum State {
+    Up(Source),
+    /// Lost, with when it went down and when to try reopening it
+    Down { since: Instant, retry_at: Instant },
+}
+
+/// Waits between attempts to reopen a lost input, from `RETRY_INITIAL` doubling up to `RETRY_MAX`.
+struct Backoff {
+    next: Duration,
+}
+
+impl Backoff {
+    fn new() -> Self {
+        Self { next: RETRY_INITIAL }
+    }
+
+    /// The wait before the next attempt, doubling the one after it.
+    fn next(&mut self) -> Duration {
+        let wait = self.next;
+        self.next = (self.next * 2).min(RETRY_MAX);
+        wait
+    }
+
+    /// Back to the shortest wait, once the input is recording again.
+    fn reset(&mut self) {
+        self.next = RETRY_INITIAL;
+    }
+}
+
+/// A source that's reopened with backoff whenever its stream errors out or stops delivering audio.
+pub struct Supervised {
+    label: String,
+    config: Config,
+    state: State,
+    backoff: Backoff,
+}
+
+impl Supervised {
+    pub fn new(label: String, config: Config, source: Source) -> Self {
+        Self { label, config, state: State::Up(source), backoff: Backoff::new() }
+    }
+
+    /// A source that couldn't be opened, to be retried like any other outage.
+    pub fn lost(label: String, config: Config, reason: &str, session_log: &mut SessionLog) -> Self {
+        eprintln!("Failed to open input{}: {}, retrying", describe(&label), reason);
+        session_log.record(SessionEvent::Outage { source: label.clone(), reason: reason.to_string() });
+        let mut backoff = Backoff::new();
+        let now = Instant::now();
+        Self { label, config, state: State::Down { since: now, retry_at: now + backoff.next() }, backoff }
+    }
+
+    pub fn label(&self) -> &str {
+        &self.label
+    }
+
+    /// Whether the source is up on the stream of `generation`, rather than down or on a newer one.
+    pub fn is_current(&self, generation: u64) -> bool {
+        matches!(&self.state, State::Up(source) if source.generation == generation)
+    }
+
+    /// Switch to `config`, which differs only in a schedule profile's detection settings. A running
+    /// source takes them up without reopening, a lost one is reopened with them.
+    pub fn retune(&mut self, config: Config) {
+        if let State::Up(source) = &self.state {
+            source.retune(config.clone());
+        }
+        self.config = config;
+    }
+
+    /// Close the open clip and stop the stream, then keep trying to reopen it. Does nothing if
+    /// the source is already down.
+    pub fn fail(&mut self, reason: &str, session_log: &mut SessionLog) {
+        if !matches!(self.state, State::Up(_)) {
+            return;
+        }
+        let now = Instant::now();
+        let down = State::Down { since: now, retry_at: now + self.backoff.next() };
+        if let State::Up(source) = std::mem::replace(&mut self.state, down) {
+            eprintln!("Lost input{}: {}, reopening", describe(&self.label), reason);
+            source.stop();
+            session_log.record(SessionEvent::Outage { source: self.label.clone(), reason: reason.to_string() });
+        }
+    }
+
+    /// Fail the source if it has stalled, or try reopening it if it's down and due a retry.
+    pub fn check(&mut self, session_log: &mut SessionLog, wake: &mpsc::Sender<Wake>) {
+        let timeout = Duration::from_secs_f64(self.config.stall_timeout_secs);
+        if let State::Up(source) = &mut self.state
+            && source.stalled(timeout)
+        {
+            let reason = format!("no audio for {:.0}s", timeout.as_secs_f64());
+            self.fail(&reason, session_log);
+            return;
+        }
+        if let State::Down { since, retry_at } = &mut self.state {
+            if Instant::now() < *retry_at {
+                return;
+            }
+            match Source::start(&self.label, &self.config, session_log.id(), wake) {
+                Ok((source, start)) => {
+                    let outage_secs = since.elapsed().as_secs_f64();
+                    println!("Input{} restored after {:.0}s", describe(&self.label), outage_secs);
+                    session_log.record(SessionEvent::Restored {
+                        source: self.label.clone(),
+                        device: source.device.clone(),
+                        outage_secs,
+                    });
+                    // The reopened stream may not match the lost one, or the source may never have
+                    // been up this session
+                    session_log.record(start);
+                    self.state = State::Up(source);
+                    self.backoff.reset();
+                }
+                Err(e) => {
+                    let wait = self.backoff.next();
+                    *retry_at = Instant::now() + wait;
+                    eprintln!("{}, retrying in {}s", e, wait.as_secs());
+                }
+            }
+        }
+    }
+
+    pub fn stop(self) {
+        if let State::Up(source) = self.state {
+            source.stop();
+        }
+    }
+}
+
+/// ` for <label>`, or nothing for the only source, to tack onto messages.
+pub fn describe(label: &str) -> String {
+    if label.is_empty() { String::new() } else { format!(" for {}", label) }
+}
+
+/// Where a stream's error callback reports to.
+struct StreamErrors {
+    source: String,
+    generation: u64,
+    wake: mpsc::Sender<Wake>,
+}
+
+/// Open an input stream delivering `T` samples, converted to f32 on their way into the ring.
+fn build_stream<T>(
+    device: &cpal::Device,
+    stream_config: &cpal::StreamConfig,
+    mut capture: writer::CaptureProducer,
+    errors: StreamErrors,
+) -> Result<cpal::Stream, String>
+where
+    T: SizedSample,
+    f32: FromSample<T>,
+{
+    device.build_input_stream(
+        stream_config,
+        move |data: &[T], _: &cpal::InputCallbackInfo| {
+            capture.push(data);
+        },
+        move |err| {
+            let _ = errors.wake.send(Wake::StreamError {
+                source: errors.source.clone(),
+                generation: errors.generation,
+                // Anything else may be transient, the stall check catches streams that die from it
+                fatal: matches!(err, cpal::StreamError::DeviceNotAvailable),
+                message: err.to_string(),
+            });
+        },
+        None,
+    ).map_err(|e| format!("Failed to create stream: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_up_to_a_minute() {
+        let mut backoff = Backoff::new();
+        let waits: Vec<u64> = (0..9).map(|_| backoff.next().as_secs()).collect();
+        assert_eq!(waits, [1, 2, 4, 8, 16, 32, 60, 60, 60]);
+    }
+
+    #[test]
+    fn backoff_starts_over_after_recovery() {
+        let mut backoff = Backoff::new();
+        for _ in 0..4 {
+            backoff.next();
+        }
+        backoff.reset();
+        assert_eq!(backoff.next(), RETRY_INITIAL);
+        assert_eq!(backoff.next(), 2 * RETRY_INITIAL);
+    }
+}
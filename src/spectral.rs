@@ -0,0 +1,263 @@
+use serde::{Deserialize, Serialize};
+use std::f32::consts::PI;
+
+/// Length of each analysis frame, rounded up to a power of two for the FFT.
+const FRAME_SECS: f32 = 0.032;
+/// Upper edges of the bands `band_energy` splits the spectrum into, in Hz. The last band runs
+/// up to Nyquist.
+pub const BAND_EDGES_HZ: [f32; 3] = [300.0, 1000.0, 3000.0];
+/// Range spectral flatness is measured over, so it means the same at any sample rate.
+const FLATNESS_RANGE_HZ: (f32, f32) = (100.0, 8000.0);
+
+/// Spectral features of a stretch of audio, each an average over its FFT frames weighted by
+/// the frame's energy, so the loud parts count and the gaps between them don't.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct SpectralFeatures {
+    /// Share of the energy below 300 Hz, 300 - 1000 Hz, 1 - 3 kHz and above 3 kHz
+    pub band_energy: [f32; 4],
+    /// Centre of mass of the spectrum, in Hz
+    pub centroid_hz: f32,
+    /// Geometric over arithmetic mean of the power spectrum: near 0 for tones, near 1 for noise
+    pub flatness: f32,
+    /// Sign changes per second
+    pub zero_crossing_rate: f32,
+}
+
+/// In-place radix-2 FFT of the complex signal `re + i * im`. The length must be a power of two.
+pub fn fft(re: &mut [f32], im: &mut [f32]) {
+    let n = re.len();
+    assert!(n.is_power_of_two() && im.len() == n, "FFT length must be a power of two");
+    // Bit-reversal permutation
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            re.swap(i, j);
+            im.swap(i, j);
+        }
+    }
+    let mut len = 2;
+    while len <= n {
+        let angle = -2.0 * PI / len as f32;
+        for k in 0..len / 2 {
+            let (sin, cos) = (angle * k as f32).sin_cos();
+            for start in (0..n).step_by(len) {
+                let (a, b) = (start + k, start + k + len / 2);
+                let t_re = re[b] * cos - im[b] * sin;
+                let t_im = re[b] * sin + im[b] * cos;
+                re[b] = re[a] - t_re;
+                im[b] = im[a] - t_im;
+                re[a] += t_re;
+                im[a] += t_im;
+            }
+        }
+        len <<= 1;
+    }
+}
+
+/// Collects `SpectralFeatures` from interleaved audio fed to it in pieces of any size.
+pub struct FeatureExtractor {
+    channels: usize,
+    sample_rate: u32,
+    window: Vec<f32>,
+    /// Mono samples not yet filling a frame
+    pending: Vec<f32>,
+    re: Vec<f32>,
+    im: Vec<f32>,
+    /// Energy-weighted sums over the frames so far, and the weights' total
+    energy: f64,
+    band_energy: [f64; 4],
+    centroid: f64,
+    flatness: f64,
+    last_sample: f32,
+    zero_crossings: u64,
+    samples: u64,
+}
+
+impl FeatureExtractor {
+    pub fn new(channels: usize, sample_rate: u32) -> Self {
+        let frame_len = ((sample_rate as f32 * FRAME_SECS) as usize).next_power_of_two();
+        Self {
+            channels,
+            sample_rate,
+            // Hann
+            window: (0..frame_len)
+                .map(|i| 0.5 - 0.5 * (2.0 * PI * i as f32 / frame_len as f32).cos())
+                .collect(),
+            pending: Vec::with_capacity(frame_len),
+            re: vec![0.0; frame_len],
+            im: vec![0.0; frame_len],
+            energy: 0.0,
+            band_energy: [0.0; 4],
+            centroid: 0.0,
+            flatness: 0.0,
+            last_sample: 0.0,
+            zero_crossings: 0,
+            samples: 0,
+        }
+    }
+
+    pub fn push(&mut self, data: &[f32]) {
+        for frame in data.chunks_exact(self.channels) {
+            let sample = frame.iter().sum::<f32>() / self.channels as f32;
+            if (sample >= 0.0) != (self.last_sample >= 0.0) {
+                self.zero_crossings += 1;
+            }
+            self.last_sample = sample;
+            self.samples += 1;
+            self.pending.push(sample);
+            if self.pending.len() == self.window.len() {
+                self.analyze_frame();
+                self.pending.clear();
+            }
+        }
+    }
+
+    fn analyze_frame(&mut self) {
+        let mean = self.pending.iter().sum::<f32>() / self.pending.len() as f32;
+        for (i, (&x, w)) in self.pending.iter().zip(&self.window).enumerate() {
+            self.re[i] = (x - mean) * w;
+            self.im[i] = 0.0;
+        }
+        fft(&mut self.re, &mut self.im);
+
+        let bin_hz = self.sample_rate as f32 / self.window.len() as f32;
+        let (flat_low, flat_high) = FLATNESS_RANGE_HZ;
+        let mut energy = 0.0;
+        let mut bands = [0.0; 4];
+        let mut weighted_freq = 0.0;
+        let (mut log_sum, mut flat_sum, mut flat_bins) = (0.0, 0.0, 0);
+        for bin in 1..=self.window.len() / 2 {
+            let power = (self.re[bin] * self.re[bin] + self.im[bin] * self.im[bin]) as f64;
+            let freq = bin as f32 * bin_hz;
+            energy += power;
+            weighted_freq += power * freq as f64;
+            bands[BAND_EDGES_HZ.iter().take_while(|&&edge| freq >= edge).count()] += power;
+            if (flat_low..=flat_high).contains(&freq) {
+                log_sum += (power + 1e-12).ln();
+                flat_sum += power + 1e-12;
+                flat_bins += 1;
+            }
+        }
+        if energy <= 0.0 {
+            return;
+        }
+        self.energy += energy;
+        for (total, band) in self.band_energy.iter_mut().zip(bands) {
+            *total += band;
+        }
+        self.centroid += weighted_freq;
+        if flat_bins > 0 {
+            let flatness = (log_sum / flat_bins as f64).exp() / (flat_sum / flat_bins as f64);
+            self.flatness += flatness * energy;
+        }
+    }
+
+    /// Features of everything pushed so far, or `None` if it didn't fill a single frame.
+    pub fn features(&self) -> Option<SpectralFeatures> {
+        if self.energy <= 0.0 {
+            return None;
+        }
+        Some(SpectralFeatures {
+            band_energy: self.band_energy.map(|band| (band / self.energy) as f32),
+            centroid_hz: (self.centroid / self.energy) as f32,
+            flatness: (self.flatness / self.energy) as f32,
+            zero_crossing_rate: self.zero_crossings as f32 * self.sample_rate as f32 / self.samples as f32,
+        })
+    }
+}
+
+/// How much `features` sound like a dog rather than a voice, a TV or a door, from 0 to 1.
+///
+/// A hand-weighted logistic model rather than a trained one. Barks put most of their energy
+/// between 300 Hz and 3 kHz with a centroid around 1 - 2 kHz. Speech has its fundamental and
+/// much of its energy below 1 kHz, so a lower centroid, slams and knocks are thumps below 300 Hz,
+/// and hiss, crashes and clatter sit high or spread flat across the spectrum.
+pub fn dog_score(features: &SpectralFeatures) -> f32 {
+    let [low, mid, high, top] = features.band_energy;
+    // How many octaves the centroid and the zero-crossing frequency are from a bark's
+    let centroid_octaves = (features.centroid_hz.max(1.0) / 1400.0).log2();
+    let crossing_octaves = (features.zero_crossing_rate.max(1.0) / 2.0 / 1200.0).log2();
+    // Barks are harsh, so only a spectrum as flat as hiss or a crash counts against them
+    let noisiness = (features.flatness - 0.3).max(0.0);
+    let z = 1.0
+        - 7.0 * low
+        + 1.5 * mid
+        + 3.0 * high
+        - 2.0 * top
+        - 1.5 * centroid_octaves * centroid_octaves
+        - 0.5 * crossing_octaves * crossing_octaves
+        - 8.0 * noisiness;
+    1.0 / (1.0 + (-z).exp())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_signal::{noise, sine};
+
+    const RATE: u32 = 16_000;
+
+    fn features(samples: &[f32]) -> SpectralFeatures {
+        let mut extractor = FeatureExtractor::new(1, RATE);
+        // In uneven pieces, as the recorder's buffers come
+        for piece in samples.chunks(700) {
+            extractor.push(piece);
+        }
+        extractor.features().unwrap()
+    }
+
+    #[test]
+    fn fft_matches_a_naive_dft() {
+        let n = 64;
+        let input_re = noise(n);
+        let input_im = noise(2 * n)[n..].to_vec();
+        let (mut re, mut im) = (input_re.clone(), input_im.clone());
+        fft(&mut re, &mut im);
+        for k in 0..n {
+            let (mut want_re, mut want_im) = (0.0f64, 0.0f64);
+            for t in 0..n {
+                let angle = -2.0 * std::f64::consts::PI * (k * t) as f64 / n as f64;
+                let (sin, cos) = angle.sin_cos();
+                want_re += input_re[t] as f64 * cos - input_im[t] as f64 * sin;
+                want_im += input_re[t] as f64 * sin + input_im[t] as f64 * cos;
+            }
+            assert!((re[k] as f64 - want_re).abs() < 1e-4 && (im[k] as f64 - want_im).abs() < 1e-4, "bin {}", k);
+        }
+    }
+
+    #[test]
+    fn tone_features() {
+        let features = features(&sine(1200.0, RATE, RATE as usize));
+        assert!(features.band_energy[2] > 0.95, "{:?}", features.band_energy);
+        assert!((features.centroid_hz - 1200.0).abs() < 50.0, "{}", features.centroid_hz);
+        assert!(features.flatness < 0.05, "{}", features.flatness);
+        // Two crossings per period
+        assert!((features.zero_crossing_rate - 2400.0).abs() < 10.0, "{}", features.zero_crossing_rate);
+        assert!((features.band_energy.iter().sum::<f32>() - 1.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn dog_score_prefers_a_bark_pitched_tone_to_noise_or_hum() {
+        let hum = dog_score(&features(&sine(100.0, RATE, RATE as usize)));
+        let tone = dog_score(&features(&sine(1200.0, RATE, RATE as usize)));
+        let noise_features = features(&noise(RATE as usize));
+        let noise = dog_score(&noise_features);
+        assert!(noise_features.flatness > 0.5, "{}", noise_features.flatness);
+        assert!(tone > 0.5, "tone {}", tone);
+        assert!(noise < 0.5, "noise {}", noise);
+        assert!(hum < 0.1, "hum {}", hum);
+    }
+
+    #[test]
+    fn too_little_audio_has_no_features() {
+        let mut extractor = FeatureExtractor::new(2, RATE);
+        extractor.push(&[0.1; 100]);
+        assert_eq!(extractor.features(), None);
+    }
+}
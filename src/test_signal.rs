@@ -0,0 +1,30 @@
+//! Test signals, shared by the library's tests and the recorder's. Both crates include this
+//! file and each uses only some of it.
+#![allow(dead_code)]
+
+use std::f32::consts::TAU;
+
+/// Deterministic noise in -1.0..1.0, from a xorshift generator.
+pub fn noise(len: usize) -> Vec<f32> {
+    let mut state = 0x2545_f491_4f6c_dd1du64;
+    (0..len)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            (state >> 40) as f32 / (1u64 << 23) as f32 - 1.0
+        })
+        .collect()
+}
+
+/// A sine at half of full scale.
+pub fn sine(freq: f32, sample_rate: u32, frames: usize) -> Vec<f32> {
+    sines(&[freq], sample_rate, frames)
+}
+
+/// Interleaved sines at half of full scale, one frequency per channel.
+pub fn sines(freqs: &[f32], sample_rate: u32, frames: usize) -> Vec<f32> {
+    (0..frames)
+        .flat_map(|i| freqs.iter().map(move |f| 0.5 * (i as f32 * f / sample_rate as f32 * TAU).sin()))
+        .collect()
+}
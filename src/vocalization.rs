@@ -0,0 +1,310 @@
+use std::fmt;
+
+use crate::spectral::fft;
+
+/// Step between analysis frames.
+const HOP_SECS: f32 = 0.01;
+/// Length of each analysis frame, rounded up to a power of two. Long enough for two periods of
+/// the lowest pitch tracked.
+const FRAME_SECS: f32 = 0.04;
+/// Pitch range of a dog's voice that's tracked, in Hz.
+const PITCH_RANGE_HZ: (f32, f32) = (150.0, 2000.0);
+/// Normalized autocorrelation at the pitch period above which a frame counts as voiced.
+const VOICED_CLARITY: f32 = 0.6;
+/// Frames within this far below the loudest one, and clearly above the quiet ones, are sound.
+const ACTIVE_BELOW_PEAK_DB: f32 = 30.0;
+const ACTIVE_ABOVE_FLOOR_DB: f32 = 10.0;
+/// Gaps shorter than this don't split a sound in two.
+const BRIDGE_SECS: f32 = 0.03;
+
+/// A kind of sound a dog makes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Vocalization {
+    /// Short, with a sharp onset
+    Bark,
+    /// Long, voiced and low
+    Howl,
+    /// Voiced and high
+    Whine,
+    /// Long and unvoiced, probably not the dog
+    Other,
+}
+
+impl Vocalization {
+    pub const ALL: [Vocalization; 4] = [Vocalization::Bark, Vocalization::Howl, Vocalization::Whine, Vocalization::Other];
+}
+
+impl fmt::Display for Vocalization {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Vocalization::Bark => "bark",
+            Vocalization::Howl => "howl",
+            Vocalization::Whine => "whine",
+            Vocalization::Other => "other",
+        })
+    }
+}
+
+/// One continuous sound within a clip.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Segment {
+    /// Seconds from the start of the clip
+    pub start_secs: f32,
+    pub end_secs: f32,
+    pub kind: Vocalization,
+    /// Median pitch of its voiced frames, `None` if too few were voiced
+    pub pitch_hz: Option<f32>,
+    /// Time from its start to close to its loudest
+    pub rise_secs: f32,
+}
+
+impl Segment {
+    pub fn secs(&self) -> f32 {
+        self.end_secs - self.start_secs
+    }
+}
+
+/// A clip split into the sounds in it, each classified.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Breakdown {
+    pub segments: Vec<Segment>,
+}
+
+impl Breakdown {
+    /// Total seconds of `kind` in the clip.
+    pub fn secs(&self, kind: Vocalization) -> f32 {
+        self.segments.iter().filter(|s| s.kind == kind).map(Segment::secs).sum()
+    }
+}
+
+/// Split interleaved audio into separate sounds and classify each by its length, how sharply it
+/// starts, and its pitch track.
+///
+/// These are rules of thumb rather than a trained model. A bark is short with a sharp onset,
+/// or noisy and not much longer. A howl is a long, steady, voiced sound below about 700 Hz and
+/// a whine a voiced one above that, often thin and wavering. Long sounds with no clear pitch
+/// are left as `Other`.
+pub fn analyze(samples: &[f32], channels: usize, sample_rate: u32) -> Breakdown {
+    let mono: Vec<f32> = samples
+        .chunks_exact(channels.max(1))
+        .map(|frame| frame.iter().sum::<f32>() / frame.len() as f32)
+        .collect();
+    let hop = ((sample_rate as f32 * HOP_SECS) as usize).max(1);
+    let frame_len = ((sample_rate as f32 * FRAME_SECS) as usize).next_power_of_two();
+    if mono.len() < frame_len {
+        return Breakdown::default();
+    }
+    let frame = |i: usize| &mono[i * hop..i * hop + frame_len];
+    let levels: Vec<f32> = (0..=(mono.len() - frame_len) / hop).map(|i| rms(frame(i))).collect();
+
+    // What counts as sound: near the loudest frame, and well above the quiet ones. A clip that
+    // is sound nearly all the way through has no quiet frames to go by.
+    let mut sorted = levels.clone();
+    sorted.sort_by(f32::total_cmp);
+    let peak = sorted[sorted.len() - 1];
+    if peak <= 0.0 {
+        return Breakdown::default();
+    }
+    let floor = sorted[sorted.len() / 10];
+    let active_level = (floor * db_to_gain(ACTIVE_ABOVE_FLOOR_DB))
+        .min(peak * db_to_gain(-ACTIVE_ABOVE_FLOOR_DB))
+        .max(peak * db_to_gain(-ACTIVE_BELOW_PEAK_DB));
+
+    // Pitch is only tracked through the sounds, most of a clip is the quiet around them
+    let mut tracker = PitchTracker::new(frame_len, sample_rate);
+    let bridge = (BRIDGE_SECS / HOP_SECS).round() as usize;
+    let mut segments = Vec::new();
+    let mut i = 0;
+    while i < levels.len() {
+        if levels[i] < active_level {
+            i += 1;
+            continue;
+        }
+        let start = i;
+        let mut end = i + 1;
+        let mut quiet = 0;
+        while end < levels.len() && quiet <= bridge {
+            if levels[end] < active_level {
+                quiet += 1;
+            } else {
+                quiet = 0;
+            }
+            end += 1;
+        }
+        let end = end - quiet;
+        // Frames are placed at their centres
+        let hop_secs = hop as f32 / sample_rate as f32;
+        let start_secs = start as f32 * hop_secs + frame_len as f32 / 2.0 / sample_rate as f32;
+        let pitches: Vec<Option<(f32, f32)>> = (start..end).map(|i| tracker.pitch(frame(i))).collect();
+        segments.push(classify(&levels[start..end], &pitches, start_secs, hop_secs));
+        i = end;
+    }
+    Breakdown { segments }
+}
+
+/// Classify one sound from the level and pitch of each of its frames.
+fn classify(levels: &[f32], pitches: &[Option<(f32, f32)>], start_secs: f32, hop_secs: f32) -> Segment {
+    let secs = levels.len() as f32 * hop_secs;
+    let peak = levels.iter().copied().fold(0.0, f32::max);
+    let rise_secs = levels.iter().position(|&level| level >= peak * 0.7).unwrap_or(0) as f32 * hop_secs;
+
+    let mut pitches: Vec<f32> = pitches
+        .iter()
+        .flatten()
+        .filter(|&&(_, clarity)| clarity >= VOICED_CLARITY)
+        .map(|&(pitch, _)| pitch)
+        .collect();
+    let voiced = pitches.len() as f32 / levels.len() as f32;
+    pitches.sort_by(f32::total_cmp);
+    let pitch_hz = (voiced >= 0.3).then(|| pitches[pitches.len() / 2]);
+
+    let kind = match pitch_hz {
+        _ if secs < 0.3 || (secs < 0.6 && rise_secs <= 0.05) => Vocalization::Bark,
+        Some(pitch) if voiced >= 0.5 && pitch >= 700.0 => Vocalization::Whine,
+        Some(_) if voiced >= 0.5 && secs >= 0.8 => Vocalization::Howl,
+        _ if secs < 0.8 => Vocalization::Bark,
+        _ => Vocalization::Other,
+    };
+    Segment {
+        start_secs,
+        end_secs: start_secs + secs,
+        kind,
+        pitch_hz,
+        rise_secs,
+    }
+}
+
+/// Pitch from the autocorrelation of a frame, computed through the FFT.
+struct PitchTracker {
+    sample_rate: u32,
+    re: Vec<f32>,
+    im: Vec<f32>,
+}
+
+impl PitchTracker {
+    fn new(frame_len: usize, sample_rate: u32) -> Self {
+        // Zero-padded to twice the length, so the correlation doesn't wrap around
+        Self { sample_rate, re: vec![0.0; frame_len * 2], im: vec![0.0; frame_len * 2] }
+    }
+
+    /// The frame's pitch and how clearly periodic it is at that pitch, 0 - 1.
+    fn pitch(&mut self, samples: &[f32]) -> Option<(f32, f32)> {
+        let n = samples.len();
+        let mean = samples.iter().sum::<f32>() / n as f32;
+        self.re.fill(0.0);
+        self.im.fill(0.0);
+        for (re, &s) in self.re.iter_mut().zip(samples) {
+            *re = s - mean;
+        }
+        fft(&mut self.re, &mut self.im);
+        // The autocorrelation is the inverse transform of the power spectrum. That's real and
+        // symmetric, so the forward transform gives the same result.
+        for (re, im) in self.re.iter_mut().zip(self.im.iter_mut()) {
+            *re = *re * *re + *im * *im;
+            *im = 0.0;
+        }
+        fft(&mut self.re, &mut self.im);
+        let energy = self.re[0];
+        if energy <= 0.0 {
+            return None;
+        }
+        // Unbiased, so longer lags aren't penalized for overlapping less of the frame
+        let clarity = |lag: usize| self.re[lag] / energy * n as f32 / (n - lag) as f32;
+
+        let (low_hz, high_hz) = PITCH_RANGE_HZ;
+        let min_lag = (self.sample_rate as f32 / high_hz).floor().max(1.0) as usize;
+        let max_lag = ((self.sample_rate as f32 / low_hz).ceil() as usize).min(n / 2);
+        let peaks: Vec<usize> = (min_lag..max_lag)
+            .filter(|&lag| clarity(lag) > clarity(lag - 1) && clarity(lag) >= clarity(lag + 1))
+            .collect();
+        let best = peaks.iter().map(|&lag| clarity(lag)).max_by(f32::total_cmp)?;
+        // Every multiple of the period correlates too, take the shortest one nearly as good as
+        // the best so the pitch doesn't drop an octave
+        let lag = peaks.into_iter().find(|&lag| clarity(lag) >= best * 0.9).unwrap_or(min_lag);
+        // Parabolic interpolation between the neighbouring lags
+        let (a, b, c) = (clarity(lag - 1), clarity(lag), clarity(lag + 1));
+        let offset = if a - 2.0 * b + c != 0.0 { 0.5 * (a - c) / (a - 2.0 * b + c) } else { 0.0 };
+        let pitch = self.sample_rate as f32 / (lag as f32 + offset.clamp(-0.5, 0.5));
+        Some((pitch, b.min(1.0)))
+    }
+}
+
+/// Level of a frame with any DC offset taken out.
+fn rms(samples: &[f32]) -> f32 {
+    let mean = samples.iter().sum::<f32>() / samples.len() as f32;
+    (samples.iter().map(|s| (s - mean) * (s - mean)).sum::<f32>() / samples.len() as f32).sqrt()
+}
+
+fn db_to_gain(db: f32) -> f32 {
+    10f32.powf(db / 20.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_signal::{noise, sine};
+
+    const RATE: u32 = 16_000;
+
+    /// `secs` of `sound` in the middle of a second of quiet noise on either side.
+    fn clip(sound: impl Fn(usize) -> f32, secs: f32) -> Vec<f32> {
+        let len = (secs * RATE as f32) as usize;
+        let quiet = RATE as usize;
+        noise(2 * quiet + len)
+            .into_iter()
+            .enumerate()
+            .map(|(i, n)| 0.001 * n + if (quiet..quiet + len).contains(&i) { sound(i - quiet) } else { 0.0 })
+            .collect()
+    }
+
+    fn tone(freq: f32, secs: f32) -> impl Fn(usize) -> f32 {
+        let tone = sine(freq, RATE, (secs * RATE as f32) as usize);
+        move |i| tone[i]
+    }
+
+    #[test]
+    fn short_burst_is_a_bark() {
+        let noise = noise(RATE as usize);
+        let breakdown = analyze(&clip(|i| 0.5 * noise[i], 0.15), 1, RATE);
+        assert_eq!(breakdown.segments.len(), 1, "{:?}", breakdown);
+        let bark = &breakdown.segments[0];
+        assert_eq!(bark.kind, Vocalization::Bark);
+        assert!((bark.start_secs - 1.0).abs() < 0.05, "{:?}", bark);
+        // Widened by about a frame, as frames overlapping its ends count too
+        assert!(bark.secs() >= 0.15 && bark.secs() < 0.25, "{:?}", bark);
+        assert_eq!(breakdown.secs(Vocalization::Howl), 0.0);
+    }
+
+    #[test]
+    fn long_steady_low_tone_is_a_howl() {
+        let breakdown = analyze(&clip(tone(450.0, 2.0), 2.0), 1, RATE);
+        assert_eq!(breakdown.segments.len(), 1, "{:?}", breakdown);
+        let howl = &breakdown.segments[0];
+        assert_eq!(howl.kind, Vocalization::Howl);
+        assert!((howl.pitch_hz.unwrap() - 450.0).abs() < 10.0, "{:?}", howl);
+        assert!((howl.secs() - 2.0).abs() < 0.1, "{:?}", howl);
+    }
+
+    #[test]
+    fn long_high_tone_is_a_whine() {
+        let breakdown = analyze(&clip(tone(1100.0, 1.0), 1.0), 1, RATE);
+        assert_eq!(breakdown.segments.iter().map(|s| s.kind).collect::<Vec<_>>(), [Vocalization::Whine]);
+    }
+
+    #[test]
+    fn separate_sounds_are_separate_segments() {
+        let noise = noise(RATE as usize);
+        let bark = |i: usize| 0.5 * noise[i];
+        // 0.1 s each, 0.4 s apart
+        let two_barks = |i: usize| if (1600..8000).contains(&i) { 0.0 } else { bark(i % 8000) };
+        let breakdown = analyze(&clip(two_barks, 0.6), 1, RATE);
+        assert_eq!(breakdown.segments.len(), 2, "{:?}", breakdown);
+        assert!(breakdown.segments.iter().all(|s| s.kind == Vocalization::Bark));
+    }
+
+    #[test]
+    fn silence_and_scraps_have_no_segments() {
+        assert_eq!(analyze(&vec![0.0; RATE as usize], 1, RATE), Breakdown::default());
+        assert_eq!(analyze(&[0.5; 10], 1, RATE), Breakdown::default());
+    }
+}
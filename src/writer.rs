@@ -0,0 +1,148 @@
+use chrono::Local;
+use cpal::{FromSample, Sample};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::config::Config;
+use crate::preprocess::Preprocessor;
+use crate::recorder::Recorder;
+
+/// Seconds of audio the ring between the audio callback and the writer thread can hold.
+const RING_SECS: f64 = 4.0;
+/// How long the writer thread sleeps when the ring is empty.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+/// Most samples handed to the recorder at once.
+const MAX_CHUNK_FRAMES: usize = 4096;
+
+/// Real-time side of the ring: lives in the audio callback and never blocks or allocates.
+pub struct CaptureProducer {
+    producer: rtrb::Producer<f32>,
+    channels: usize,
+    dropped_frames: Arc<AtomicU64>,
+    callbacks: Arc<AtomicU64>,
+}
+
+impl CaptureProducer {
+    /// Counts every `push`, so a stalled stream can be noticed from outside the callback.
+    pub fn callbacks(&self) -> Arc<AtomicU64> {
+        Arc::clone(&self.callbacks)
+    }
+
+    /// Queue interleaved samples for the writer thread, normalised to f32. Whole frames that
+    /// don't fit are dropped and counted.
+    pub fn push<T>(&mut self, data: &[T])
+    where
+        T: Sample,
+        f32: FromSample<T>,
+    {
+        self.callbacks.fetch_add(1, Ordering::Relaxed);
+        let fits = self.producer.slots().min(data.len());
+        let fits = fits - fits % self.channels;
+        if let Ok(chunk) = self.producer.write_chunk_uninit(fits) {
+            chunk.fill_from_iter(data.iter().map(|&s| f32::from_sample(s)));
+        }
+        let dropped = (data.len() - fits) / self.channels;
+        if dropped > 0 {
+            self.dropped_frames.fetch_add(dropped as u64, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Handle to the writer thread that runs the recorder off the audio callback.
+pub struct WriterThread {
+    stop: Arc<AtomicBool>,
+    retune: mpsc::Sender<Config>,
+    handle: JoinHandle<()>,
+}
+
+impl WriterThread {
+    /// Have the recorder take up `config`'s detection settings, see `Recorder::retune`.
+    pub fn retune(&self, config: Config) {
+        // Only fails if the thread is gone, which `stop` reports
+        let _ = self.retune.send(config);
+    }
+
+    /// Drain whatever is still queued, close the open clip and wait for the thread to exit.
+    pub fn stop(self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if self.handle.join().is_err() {
+            eprintln!("Writer thread panicked");
+        }
+    }
+}
+
+/// Create the ring and start the writer thread that owns `recorder`: preprocessing, detection,
+/// file creation, WAV writing and rotation all happen there instead of on the audio thread.
+/// `channels` and `sample_rate` are the input's, before `preprocessor`.
+pub fn spawn(
+    mut recorder: Recorder,
+    mut preprocessor: Preprocessor,
+    channels: usize,
+    sample_rate: u32,
+) -> (CaptureProducer, WriterThread) {
+    let capacity = (sample_rate as f64 * RING_SECS) as usize * channels;
+    let (producer, mut consumer) = rtrb::RingBuffer::<f32>::new(capacity);
+    let dropped_frames = Arc::new(AtomicU64::new(0));
+    let stop = Arc::new(AtomicBool::new(false));
+    let (retune, retuned) = mpsc::channel::<Config>();
+
+    let thread_dropped = Arc::clone(&dropped_frames);
+    let thread_stop = Arc::clone(&stop);
+    let handle = thread::Builder::new()
+        .name("clip-writer".to_string())
+        .spawn(move || {
+            let mut buffer = Vec::with_capacity(MAX_CHUNK_FRAMES * channels);
+            let mut reported_dropped = 0;
+            loop {
+                for config in retuned.try_iter() {
+                    recorder.retune(&config);
+                }
+                let dropped = thread_dropped.load(Ordering::Relaxed);
+                if dropped != reported_dropped {
+                    eprintln!(
+                        "Dropped {} frames, the writer fell behind the audio input ({} total)",
+                        dropped - reported_dropped,
+                        dropped
+                    );
+                    reported_dropped = dropped;
+                }
+
+                let available = consumer.slots().min(MAX_CHUNK_FRAMES * channels);
+                let available = available - available % channels;
+                if available == 0 {
+                    if thread_stop.load(Ordering::Relaxed) {
+                        break;
+                    }
+                    thread::sleep(POLL_INTERVAL);
+                    continue;
+                }
+
+                // Anything still queued behind this chunk was captured after it, so back-date
+                // the chunk by the queue's length
+                let queued_frames = (consumer.slots() / channels) as i64;
+                let captured_at = Local::now()
+                    - chrono::Duration::milliseconds(queued_frames * 1000 / sample_rate as i64);
+
+                let chunk = consumer.read_chunk(available).expect("available samples are readable");
+                let (first, second) = chunk.as_slices();
+                buffer.clear();
+                buffer.extend_from_slice(first);
+                buffer.extend_from_slice(second);
+                chunk.commit_all();
+                let processed = preprocessor.process(&buffer);
+                if !processed.is_empty() {
+                    recorder.process(processed, captured_at);
+                }
+            }
+            recorder.finish();
+        })
+        .expect("Failed to start writer thread");
+
+    (
+        CaptureProducer { producer, channels, dropped_frames, callbacks: Arc::new(AtomicU64::new(0)) },
+        WriterThread { stop, retune, handle },
+    )
+}